@@ -1,7 +1,18 @@
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// USB identity, each can be overridden with an environment variable of
+/// the same name at build time
+const USB_VID: &str = "0xFFFF";
+const USB_PID: &str = "0xFFFF";
+const USB_MANUFACTURER: &str = "Rusty Manufacturer";
+const USB_PRODUCT: &str = "Rusty Product";
+
+/// Key matrix scanning, overridden the same way as the USB identity
+const SCAN_RATE_HZ: &str = "1000";
+const DEBOUNCE_MS: &str = "5";
 
 fn main() {
     // Put the linker script somewhere the linker can find it
@@ -17,7 +28,115 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    write_usb_identity(out);
+    write_scan_settings(out);
+
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=memory-release.x");
     println!("cargo:rerun-if-changed=memory-debug.x");
 }
+
+/// Generate `usb_identity.rs` for `usb::descriptors`
+fn write_usb_identity(out: &Path) {
+    let vid = parse_id("USB_VID", &setting("USB_VID", USB_VID));
+    let pid = parse_id("USB_PID", &setting("USB_PID", USB_PID));
+    let release = device_release();
+
+    let mut file = File::create(out.join("usb_identity.rs")).unwrap();
+    writeln!(file, "pub const VENDOR_ID: u16 = {:#06x};", vid).unwrap();
+    writeln!(file, "pub const PRODUCT_ID: u16 = {:#06x};", pid).unwrap();
+    writeln!(file, "/// The crate version, 0xJJMN in BCD").unwrap();
+    writeln!(file, "pub const DEVICE_RELEASE: u16 = {:#06x};", release).unwrap();
+    let strings = [
+        (
+            "MANUFACTURER_STR",
+            setting("USB_MANUFACTURER", USB_MANUFACTURER),
+        ),
+        ("PRODUCT_STR", setting("USB_PRODUCT", USB_PRODUCT)),
+        ("CONF_STR", "Rusty Configuration".to_string()),
+        ("INTERFACE_STR", "Rusty Interface".to_string()),
+    ];
+    for (name, value) in strings.iter() {
+        let descriptor = string_descriptor(value);
+        writeln!(file, "/// {:?}", value).unwrap();
+        writeln!(
+            file,
+            "pub const {}: [u8; {}] = {:?};",
+            name,
+            descriptor.len(),
+            descriptor
+        )
+        .unwrap();
+    }
+}
+
+/// Generate `scan_settings.rs` for `keymatrix`
+fn write_scan_settings(out: &Path) {
+    let rate: u32 = parse_number("SCAN_RATE_HZ", &setting("SCAN_RATE_HZ", SCAN_RATE_HZ));
+    assert!(
+        (1_000..=8_000).contains(&rate) && 1_000_000 % rate == 0,
+        "SCAN_RATE_HZ must be between 1000 and 8000 and divide 1 MHz, not {}",
+        rate
+    );
+    let debounce: u32 = parse_number("DEBOUNCE_MS", &setting("DEBOUNCE_MS", DEBOUNCE_MS));
+    assert!(
+        debounce <= 30,
+        "DEBOUNCE_MS must be at most 30, not {}",
+        debounce
+    );
+
+    let mut file = File::create(out.join("scan_settings.rs")).unwrap();
+    writeln!(file, "pub const SCAN_RATE_HZ: u32 = {};", rate).unwrap();
+    writeln!(file, "pub const DEBOUNCE_MS: u32 = {};", debounce).unwrap();
+}
+
+fn parse_number(name: &str, value: &str) -> u32 {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("{} must be a number, not {:?}", name, value))
+}
+
+fn setting(name: &str, default: &str) -> String {
+    println!("cargo:rerun-if-env-changed={}", name);
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// A hexadecimal USB vendor or product ID, with or without 0x
+fn parse_id(name: &str, value: &str) -> u16 {
+    let digits = value.trim_start_matches("0x");
+    u16::from_str_radix(digits, 16)
+        .unwrap_or_else(|_| panic!("{} must be a 16 bit hex number, not {:?}", name, value))
+}
+
+/// bcdDevice from the crate version, major.minor.patch as 0xJJMN
+fn device_release() -> u16 {
+    let part = |name: &str, max: u16| {
+        let value: u16 = env::var(name).unwrap().parse().unwrap();
+        assert!(
+            value <= max,
+            "{} {} doesn't fit into bcdDevice",
+            name,
+            value
+        );
+        value
+    };
+    let major = part("CARGO_PKG_VERSION_MAJOR", 99);
+    let minor = part("CARGO_PKG_VERSION_MINOR", 9);
+    let patch = part("CARGO_PKG_VERSION_PATCH", 9);
+    (major / 10) << 12 | (major % 10) << 8 | minor << 4 | patch
+}
+
+/// A USB string descriptor: length, type and the UTF-16LE text
+fn string_descriptor(value: &str) -> Vec<u8> {
+    let mut descriptor = vec![0, 0x03];
+    for unit in value.encode_utf16() {
+        descriptor.extend_from_slice(&unit.to_le_bytes());
+    }
+    assert!(
+        descriptor.len() <= 255,
+        "USB string {:?} is too long",
+        value
+    );
+    descriptor[0] = descriptor.len() as u8;
+    descriptor
+}
@@ -1,4 +1,10 @@
-use crate::keycodes::KeyCode;
+use crate::keycodes::{KeyCode, SystemControl};
+use crate::layout::LAYERS;
+use crate::macros::MACRO_COUNT;
+use crate::mousekeys::MouseAction;
+
+/// Size of an action in `Action::to_bytes` format
+pub const ACTION_SIZE: usize = 3;
 
 #[allow(dead_code)]
 #[derive(Copy, Clone, PartialEq)]
@@ -11,8 +17,25 @@ pub enum Action {
     Transparent,
     /// Toggle sending HID report over USB
     UsbToggle,
+    /// Toggle between N-key and 6-key rollover over USB. Only makes a
+    /// difference while the host uses the report protocol.
+    NkroToggle,
+    /// Toggle the hardware test mode, see `matrixtest::MatrixTest`.
+    /// No HID reports are sent while it's running.
+    MatrixTest,
+    /// Type out one of the macros edited with VIA, see
+    /// `macros::MacroPlayer`
+    Macro(u8),
 
     Key(KeyCode), // = 0x10
+    /// Usage from the HID consumer page, see `keycodes::consumer`.
+    /// Only sent over USB.
+    Consumer(u16),
+    /// Power management keys, only sent over USB
+    System(SystemControl),
+    /// Move the pointer, scroll or click, see `mousekeys::MouseKeys`.
+    /// Only sent over USB.
+    Mouse(MouseAction),
 
     LayerMomentary(u8), // = 0x20,
     LayerToggle(u8),
@@ -61,4 +84,173 @@ impl Action {
     pub const fn to_action(self) -> Action {
         self
     }
+
+    /// Wire format for the raw HID commands: a kind byte, following the
+    /// numbering hinted at in the enum, and up to two bytes argument
+    pub fn to_bytes(self) -> [u8; ACTION_SIZE] {
+        let (kind, arg): (u8, u16) = match self {
+            Action::Nop => (0x00, 0),
+            Action::Reset => (0x01, 0),
+            Action::Transparent => (0x02, 0),
+            Action::UsbToggle => (0x03, 0),
+            Action::NkroToggle => (0x04, 0),
+            Action::MatrixTest => (0x05, 0),
+            Action::Macro(index) => (0x06, u16::from(index)),
+            Action::Key(code) => (0x10, code as u16),
+            Action::Consumer(usage) => (0x11, usage),
+            Action::System(usage) => (0x12, usage as u16),
+            Action::Mouse(action) => (0x13, action as u16),
+            Action::LayerMomentary(layer) => (0x20, u16::from(layer)),
+            Action::LayerToggle(layer) => (0x21, u16::from(layer)),
+            Action::LayerOn(layer) => (0x22, u16::from(layer)),
+            Action::LayerOff(layer) => (0x23, u16::from(layer)),
+            Action::LedOn => (0x30, 0),
+            Action::LedOff => (0x31, 0),
+            Action::LedToggle => (0x32, 0),
+            Action::LedNextTheme => (0x33, 0),
+            Action::LedNextBrightness => (0x34, 0),
+            Action::LedNextAnimationSpeed => (0x35, 0),
+            Action::LedTheme(theme) => (0x36, u16::from(theme)),
+            Action::BtOn => (0x40, 0),
+            Action::BtOff => (0x41, 0),
+            Action::BtSaveHost(host) => (0x42, u16::from(host)),
+            Action::BtConnectHost(host) => (0x43, u16::from(host)),
+            Action::BtDeleteHost(host) => (0x44, u16::from(host)),
+            Action::BtBroadcast => (0x45, 0),
+            Action::BtLegacyMode(enabled) => (0x46, u16::from(enabled)),
+            Action::BtToggleLegacyMode => (0x47, 0),
+            Action::BtHostListQuery => (0x48, 0),
+        };
+        let arg = arg.to_le_bytes();
+        [kind, arg[0], arg[1]]
+    }
+
+    /// The reverse of `to_bytes`, `None` for unknown kinds and
+    /// arguments out of range
+    pub fn from_bytes(bytes: [u8; ACTION_SIZE]) -> Option<Action> {
+        let [kind, arg, _] = bytes;
+        let arg16 = u16::from_le_bytes([bytes[1], bytes[2]]);
+        let action = match kind {
+            0x00 => Action::Nop,
+            0x01 => Action::Reset,
+            0x02 => Action::Transparent,
+            0x03 => Action::UsbToggle,
+            0x04 => Action::NkroToggle,
+            0x05 => Action::MatrixTest,
+            0x06 if arg >= MACRO_COUNT => return None,
+            0x06 => Action::Macro(arg),
+            0x10 => Action::Key(KeyCode::from_u8(arg)?),
+            0x11 => Action::Consumer(arg16),
+            0x12 => Action::System(SystemControl::from_u8(arg)?),
+            0x13 => Action::Mouse(MouseAction::from_u8(arg)?),
+            0x20..=0x23 if arg as usize >= LAYERS.len() => return None,
+            0x20 => Action::LayerMomentary(arg),
+            0x21 => Action::LayerToggle(arg),
+            0x22 => Action::LayerOn(arg),
+            0x23 => Action::LayerOff(arg),
+            0x30 => Action::LedOn,
+            0x31 => Action::LedOff,
+            0x32 => Action::LedToggle,
+            0x33 => Action::LedNextTheme,
+            0x34 => Action::LedNextBrightness,
+            0x35 => Action::LedNextAnimationSpeed,
+            0x36 => Action::LedTheme(arg),
+            0x40 => Action::BtOn,
+            0x41 => Action::BtOff,
+            0x42 => Action::BtSaveHost(arg),
+            0x43 => Action::BtConnectHost(arg),
+            0x44 => Action::BtDeleteHost(arg),
+            0x45 => Action::BtBroadcast,
+            0x46 => Action::BtLegacyMode(arg != 0),
+            0x47 => Action::BtToggleLegacyMode,
+            0x48 => Action::BtHostListQuery,
+            _ => return None,
+        };
+        Some(action)
+    }
+
+    /// Whether this action is carried out by the LED or Bluetooth
+    /// controller rather than by the keyboard itself
+    pub fn is_peripheral(self) -> bool {
+        match self {
+            Action::LedOn
+            | Action::LedOff
+            | Action::LedToggle
+            | Action::LedNextTheme
+            | Action::LedNextBrightness
+            | Action::LedNextAnimationSpeed
+            | Action::LedTheme(_)
+            | Action::BtOn
+            | Action::BtOff
+            | Action::BtSaveHost(_)
+            | Action::BtConnectHost(_)
+            | Action::BtDeleteHost(_)
+            | Action::BtBroadcast
+            | Action::BtLegacyMode(_)
+            | Action::BtToggleLegacyMode
+            | Action::BtHostListQuery => true,
+            _ => false,
+        }
+    }
+}
+
+/// The actions `tools/anne-cli` knows by name
+#[cfg(test)]
+#[path = "../tools/anne-cli/src/actions.rs"]
+mod cli;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::consumer;
+
+    /// One action of each kind, with its name in anne-cli
+    const NAMED: &[(&str, Action)] = &[
+        ("nop", Action::Nop),
+        ("reset", Action::Reset),
+        ("transparent", Action::Transparent),
+        ("usb-toggle", Action::UsbToggle),
+        ("nkro-toggle", Action::NkroToggle),
+        ("matrix-test", Action::MatrixTest),
+        ("macro", Action::Macro(1)),
+        ("key", Action::Key(KeyCode::A)),
+        ("consumer", Action::Consumer(consumer::MUTE)),
+        ("system", Action::System(SystemControl::Sleep)),
+        ("mouse", Action::Mouse(MouseAction::Left)),
+        ("layer-momentary", Action::LayerMomentary(1)),
+        ("layer-toggle", Action::LayerToggle(1)),
+        ("layer-on", Action::LayerOn(1)),
+        ("layer-off", Action::LayerOff(1)),
+        ("led-on", Action::LedOn),
+        ("led-off", Action::LedOff),
+        ("led-toggle", Action::LedToggle),
+        ("led-next-theme", Action::LedNextTheme),
+        ("led-next-brightness", Action::LedNextBrightness),
+        ("led-next-animation-speed", Action::LedNextAnimationSpeed),
+        ("led-theme", Action::LedTheme(1)),
+        ("bt-on", Action::BtOn),
+        ("bt-off", Action::BtOff),
+        ("bt-save-host", Action::BtSaveHost(1)),
+        ("bt-connect-host", Action::BtConnectHost(1)),
+        ("bt-delete-host", Action::BtDeleteHost(1)),
+        ("bt-broadcast", Action::BtBroadcast),
+        ("bt-legacy-mode", Action::BtLegacyMode(true)),
+        ("bt-toggle-legacy-mode", Action::BtToggleLegacyMode),
+        ("bt-host-list-query", Action::BtHostListQuery),
+    ];
+
+    #[test]
+    fn cli_kinds_match() {
+        assert_eq!(NAMED.len(), cli::ACTIONS.len());
+        for &(name, action) in NAMED {
+            let (_, kind, has_arg) = cli::ACTIONS
+                .iter()
+                .find(|(cli_name, _, _)| *cli_name == name)
+                .unwrap_or_else(|| panic!("anne-cli has no {}", name));
+            let bytes = action.to_bytes();
+            assert_eq!(bytes[0], *kind, "{}", name);
+            // The arguments above are all non-zero
+            assert_eq!(bytes[1] != 0, *has_arg, "{}", name);
+        }
+    }
 }
@@ -5,17 +5,30 @@ use crate::led::Led;
 use crate::protocol::{BleOp, KeyboardOp, LedOp, MacroOp, Message, MsgType, SystemOp};
 use crate::serial::bluetooth_usart::BluetoothUsart;
 use crate::serial::{DmaUsart, Serial, Transfer};
+use crate::uid;
 
 use core::convert::Infallible;
 use core::marker::Unsize;
+use heapless::consts::U8;
+use heapless::spsc::Queue;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BluetoothMode {
     Unknown,
     Legacy,
     Ble,
 }
 
+/// What the Bluetooth module last told us about its hosts
+#[derive(Copy, Clone)]
+pub struct BluetoothStatus {
+    pub mode: BluetoothMode,
+    /// See `Bluetooth::saved_hosts`
+    pub saved_hosts: u8,
+    /// See `Bluetooth::connected_host`
+    pub connected_host: u8,
+}
+
 pub struct Bluetooth<BUFFER: 'static + Unsize<[u8]>> {
     pub serial: Serial<BluetoothUsart, BUFFER>,
     pub rx_transfer: Option<Transfer<BUFFER>>,
@@ -26,6 +39,13 @@ pub struct Bluetooth<BUFFER: 'static + Unsize<[u8]>> {
     /// The currently connected slot (1-4), or disconnected (0), or
     /// the current host is not saved (12)
     connected_host: u8,
+    /// Reports waiting for the USART, oldest first, see `send_report`
+    reports: Queue<HidReport, U8>,
+    /// The newest report, whether it was sent yet or not
+    last_report: HidReport,
+    /// `last_report` didn't fit into `reports`, it's queued as soon as
+    /// there's room
+    report_unqueued: bool,
 }
 
 impl<BUFFER> Bluetooth<BUFFER>
@@ -43,6 +63,17 @@ where
             mode: BluetoothMode::Unknown,
             saved_hosts: 0,
             connected_host: 0,
+            reports: Queue::new(),
+            last_report: HidReport::default(),
+            report_unqueued: false,
+        }
+    }
+
+    pub fn status(&self) -> BluetoothStatus {
+        BluetoothStatus {
+            mode: self.mode,
+            saved_hosts: self.saved_hosts,
+            connected_host: self.connected_host,
         }
     }
 
@@ -90,12 +121,43 @@ where
             .send(MsgType::Ble, BleOp::HostListQuery as u8, &[])
     }
 
-    pub fn send_report(&mut self, report: &HidReport) -> nb::Result<(), Infallible> {
-        self.serial.send(
-            MsgType::Keyboard,
-            KeyboardOp::KeyReport as u8,
-            report.as_bytes(),
-        )
+    /// Queue `report` unless it's the same as the last one, and send
+    /// as many queued reports as the USART takes. The rest follow from
+    /// `tx_interrupt`.
+    pub fn send_report(&mut self, report: &HidReport) {
+        if report.as_bytes() != self.last_report.as_bytes() {
+            self.last_report = *report;
+            self.queue_last_report();
+        }
+        self.send_queued_reports();
+    }
+
+    fn queue_last_report(&mut self) {
+        self.report_unqueued = self.reports.enqueue(self.last_report).is_err();
+    }
+
+    fn send_queued_reports(&mut self) {
+        // The oldest report, heapless 0.4 has no `Queue::peek`
+        while let Some(report) = self.reports.iter().next() {
+            let sent = self.serial.send(
+                MsgType::Keyboard,
+                KeyboardOp::KeyReport as u8,
+                report.as_bytes(),
+            );
+            if sent.is_err() {
+                break;
+            }
+            self.reports.dequeue();
+            if self.report_unqueued {
+                self.queue_last_report();
+            }
+        }
+    }
+
+    /// The USART is done sending
+    pub fn tx_interrupt(&mut self) {
+        self.serial.tx_interrupt();
+        self.send_queued_reports();
     }
 
     pub fn update_led(
@@ -123,27 +185,25 @@ where
                     SystemOp::GetId => {
                         const DEVICE_TYPE_KEYBOARD: u8 = 1;
                         const DEVICE_MODEL_ANNE_PRO: u8 = 2;
-                        //const DEVICE_ID = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+                        let id = uid::unique_id();
 
                         // send two packets
                         // nblock = 2
                         // [datalen, nblock, iblock = 0, data...]
                         // [datalen, nblock, iblock = 1, data...]
 
-                        let data1 = [
+                        let mut data1 = [0; 11];
+                        data1[..5].copy_from_slice(&[
                             10,
                             2,
                             0,
                             DEVICE_TYPE_KEYBOARD,
                             DEVICE_MODEL_ANNE_PRO,
-                            1,
-                            2,
-                            3,
-                            4,
-                            5,
-                            6,
-                        ];
-                        let data2 = [8, 2, 1, 7, 8, 9, 10, 11, 12];
+                        ]);
+                        data1[5..].copy_from_slice(&id[..6]);
+                        let mut data2 = [0; 9];
+                        data2[..3].copy_from_slice(&[8, 2, 1]);
+                        data2[3..].copy_from_slice(&id[6..]);
                         self.serial
                             .send(MsgType::System, SystemOp::AckGetId as u8, &data1)
                             .log_error();
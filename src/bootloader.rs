@@ -0,0 +1,37 @@
+use stm32l1::stm32l151::{PWR, RCC, RTC, SCB};
+
+/// Left in RTC_BKP0R by `reboot_to_dfu`. The RTC backup registers keep
+/// their value over a system reset, so a bootloader can check for it
+/// and stay in DFU mode without Escape being held.
+pub const DFU_MAGIC: u32 = 0xDF11_B007;
+
+/// Make the RTC backup registers writable
+fn unlock_backup_registers() {
+    unsafe {
+        (*RCC::ptr()).apb1enr.modify(|_, w| w.pwren().set_bit());
+        (*PWR::ptr()).cr.modify(|_, w| w.dbp().set_bit());
+    }
+}
+
+/// Leave `DFU_MAGIC` for the bootloader and reset
+pub fn reboot_to_dfu() -> ! {
+    crate::heprintln!("rebooting into DFU").ok();
+    unlock_backup_registers();
+    unsafe { (*RTC::ptr()).bkp0r.write(|w| w.bits(DFU_MAGIC)) };
+    SCB::sys_reset()
+}
+
+/// Whether `reboot_to_dfu` asked for DFU mode, but the bootloader
+/// started the firmware anyway. Clears the request.
+///
+/// The factory bootloader only looks at the Escape key, so this is
+/// what happens with it.
+pub fn take_dfu_request() -> bool {
+    let rtc = unsafe { &*RTC::ptr() };
+    if rtc.bkp0r.read().bits() != DFU_MAGIC {
+        return false;
+    }
+    unlock_backup_registers();
+    rtc.bkp0r.write(|w| unsafe { w.bits(0) });
+    true
+}
@@ -1,5 +1,20 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::{SCB, SYST};
 use stm32l1::stm32l151;
 
+/// Core clock after `init_clock`: 16 MHz HSE * 6 / 3
+pub const SYSCLK_HZ: u32 = 32_000_000;
+const CYCLES_PER_US: u32 = SYSCLK_HZ / 1_000_000;
+
+/// Length of one SysTick period in µs
+static TICK_US: AtomicU32 = AtomicU32::new(0);
+/// `now_us()` at the start of the current SysTick period
+static TICK_START_US: AtomicU32 = AtomicU32::new(0);
+/// `now_ms()` at the start of the current SysTick period
+static TICK_START_MS: AtomicU32 = AtomicU32::new(0);
+/// µs between `TICK_START_MS` and the actual start of the period
+static TICK_START_MS_REMAINDER: AtomicU32 = AtomicU32::new(0);
+
 pub fn init_clock(p: &stm32l151::Peripherals) {
     p.USB.cntr.modify(|_, w| w.pdwn().clear_bit());
 
@@ -43,9 +58,201 @@ pub fn init_clock(p: &stm32l151::Peripherals) {
          .gpiopcen().set_bit());
 }
 
-pub fn enable_tick(syst: &mut stm32l151::SYST, reload: u32) {
+/// Start firing SysTick every `tick_us` µs. The SysTick handler has
+/// to call `tick()` for `now_us()` and `now_ms()` to advance.
+pub fn enable_tick(syst: &mut stm32l151::SYST, tick_us: u32) {
+    TICK_US.store(tick_us, Ordering::Relaxed);
     syst.set_clock_source(cortex_m::peripheral::syst::SystClkSource::Core);
-    syst.set_reload(reload);
+    syst.set_reload(tick_us * CYCLES_PER_US - 1);
+    syst.clear_current();
     syst.enable_interrupt();
     syst.enable_counter();
 }
+
+/// Advance the clock by one SysTick period.
+///
+/// Must only be called from the SysTick handler.
+pub fn tick() {
+    let tick_us = TICK_US.load(Ordering::Relaxed);
+    let start_us = TICK_START_US.load(Ordering::Relaxed);
+    let remainder = TICK_START_MS_REMAINDER.load(Ordering::Relaxed) + tick_us;
+    let start_ms = TICK_START_MS.load(Ordering::Relaxed);
+
+    TICK_START_MS.store(start_ms.wrapping_add(remainder / 1000), Ordering::Relaxed);
+    TICK_START_MS_REMAINDER.store(remainder % 1000, Ordering::Relaxed);
+    TICK_START_US.store(start_us.wrapping_add(tick_us), Ordering::Relaxed);
+}
+
+/// Returns (`TICK_START_US`, `TICK_START_MS`, remainder, µs since
+/// then) as one consistent snapshot.
+fn snapshot() -> (u32, u32, u32, u32) {
+    loop {
+        let start_us = TICK_START_US.load(Ordering::Relaxed);
+        let start_ms = TICK_START_MS.load(Ordering::Relaxed);
+        let remainder = TICK_START_MS_REMAINDER.load(Ordering::Relaxed);
+
+        let pending = SCB::is_pendst_pending();
+        let current = SYST::get_current();
+        if pending != SCB::is_pendst_pending() || start_us != TICK_START_US.load(Ordering::Relaxed)
+        {
+            // SysTick wrapped or `tick()` ran while we were reading
+            continue;
+        }
+
+        let mut elapsed = (SYST::get_reload() - current) / CYCLES_PER_US;
+        if pending {
+            // The counter wrapped, but `tick()` hasn't run yet because
+            // we're at the same or higher priority than SysTick.
+            elapsed += TICK_US.load(Ordering::Relaxed);
+        }
+        return (start_us, start_ms, remainder, elapsed);
+    }
+}
+
+/// µs since `enable_tick`, wraps around after about 71 minutes
+pub fn now_us() -> u32 {
+    let (start_us, _, _, elapsed) = snapshot();
+    start_us.wrapping_add(elapsed)
+}
+
+/// ms since `enable_tick`, wraps around after about 49 days
+pub fn now_ms() -> u32 {
+    let (_, start_ms, remainder, elapsed) = snapshot();
+    start_ms.wrapping_add((remainder + elapsed) / 1000)
+}
+
+/// Whether `deadline` (in the same unit as `now`) has passed, taking
+/// wrap-around into account
+pub fn is_due(now: u32, deadline: u32) -> bool {
+    now.wrapping_sub(deadline) as i32 >= 0
+}
+
+/// Busy wait for at least `us` µs. Only meant for very short delays
+/// where scheduling a `Timeouts` entry is too coarse, this doesn't
+/// need SysTick so it's cheap enough to call during a scan.
+pub fn delay_us(us: u32) {
+    cortex_m::asm::delay(us * CYCLES_PER_US);
+}
+
+const TIMEOUT_SLOTS: usize = 8;
+
+/// A set of pending timeouts, each one firing once at its deadline.
+///
+/// `T` describes what to do when the timeout fires, usually an enum
+/// that gets turned into a task spawn.
+pub struct Timeouts<T: Copy> {
+    slots: [Option<(u32, T)>; TIMEOUT_SLOTS],
+}
+
+impl<T: Copy> Timeouts<T> {
+    pub fn new() -> Timeouts<T> {
+        Timeouts {
+            slots: [None; TIMEOUT_SLOTS],
+        }
+    }
+
+    /// Fire `timeout` at `now_ms() == at`. Gives `timeout` back if all
+    /// slots are in use.
+    pub fn schedule_at(&mut self, at: u32, timeout: T) -> Result<(), T> {
+        match self.slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some((at, timeout));
+                Ok(())
+            }
+            None => Err(timeout),
+        }
+    }
+
+    /// Fire `timeout` `ms` milliseconds from now
+    pub fn schedule_in(&mut self, ms: u32, timeout: T) -> Result<(), T> {
+        self.schedule_at(now_ms().wrapping_add(ms), timeout)
+    }
+
+    /// Remove all pending instances of `timeout`
+    pub fn cancel(&mut self, timeout: T)
+    where
+        T: PartialEq,
+    {
+        for slot in self.slots.iter_mut() {
+            if let Some((_, pending)) = *slot {
+                if pending == timeout {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// Take out one timeout whose deadline has passed at `now`
+    pub fn expired(&mut self, now: u32) -> Option<T> {
+        for slot in self.slots.iter_mut() {
+            if let Some((at, timeout)) = *slot {
+                if is_due(now, at) {
+                    *slot = None;
+                    return Some(timeout);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_across_wrap_around() {
+        assert!(is_due(10, 10));
+        assert!(!is_due(9, 10));
+        let deadline = u32::max_value() - 5;
+        assert!(!is_due(u32::max_value() - 6, deadline));
+        assert!(is_due(deadline, deadline));
+        assert!(is_due(3, deadline));
+        assert!(!is_due(u32::max_value(), 4));
+    }
+
+    #[test]
+    fn expired_once_due() {
+        let mut timeouts = Timeouts::new();
+        timeouts.schedule_at(20, 'b').unwrap();
+        timeouts.schedule_at(10, 'a').unwrap();
+        assert_eq!(timeouts.expired(9), None);
+        assert_eq!(timeouts.expired(10), Some('a'));
+        assert_eq!(timeouts.expired(10), None);
+        assert_eq!(timeouts.expired(25), Some('b'));
+        assert_eq!(timeouts.expired(25), None);
+    }
+
+    #[test]
+    fn expired_across_wrap_around() {
+        let mut timeouts = Timeouts::new();
+        let now = u32::max_value() - 1;
+        timeouts.schedule_at(now.wrapping_add(5), 'a').unwrap();
+        assert_eq!(timeouts.expired(now), None);
+        assert_eq!(timeouts.expired(u32::max_value()), None);
+        assert_eq!(timeouts.expired(3), Some('a'));
+    }
+
+    #[test]
+    fn full_slots() {
+        let mut timeouts = Timeouts::new();
+        for i in 0..TIMEOUT_SLOTS as u32 {
+            timeouts.schedule_at(i, 'a').unwrap();
+        }
+        assert_eq!(timeouts.schedule_at(0, 'b'), Err('b'));
+        assert_eq!(timeouts.expired(0), Some('a'));
+        assert_eq!(timeouts.schedule_at(0, 'b'), Ok(()));
+    }
+
+    #[test]
+    fn cancel_removes_every_instance() {
+        let mut timeouts = Timeouts::new();
+        timeouts.schedule_at(10, 'a').unwrap();
+        timeouts.schedule_at(20, 'b').unwrap();
+        timeouts.schedule_at(30, 'a').unwrap();
+        timeouts.cancel('a');
+        assert_eq!(timeouts.expired(100), Some('b'));
+        assert_eq!(timeouts.expired(100), None);
+        timeouts.cancel('c');
+    }
+}
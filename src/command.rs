@@ -0,0 +1,94 @@
+use crate::action::{Action, ACTION_SIZE};
+use crate::debug::UnwrapLog;
+use crate::eeprom::Eeprom;
+use crate::keyboard::Keyboard;
+use crate::layout::LAYERS;
+use crate::usb::rawhid::{RawReport, REPORT_SIZE};
+
+/// Bumped on incompatible changes, reported by `GET_VERSION`
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Commands from the host over the raw HID interface. They are in the
+// upper half so the lower one stays free for VIA's protocol on the
+// same interface, see `via::execute`.
+//
+// Every request is a report starting with the command byte, the
+// response starts with the same byte followed by a status.
+
+/// Lower ones go to `via::execute`
+pub const FIRST_COMMAND: u8 = 0x80;
+
+/// -> [protocol version, length, firmware version string...]
+pub const GET_VERSION: u8 = 0x80;
+/// -> [active layers bit-field, number of layers]
+pub const GET_LAYER_STATE: u8 = 0x81;
+/// [layer, key] -> [action]
+pub const GET_KEYMAP_ENTRY: u8 = 0x82;
+/// [layer, key, action] -> [], saved in the EEPROM
+pub const SET_KEYMAP_ENTRY: u8 = 0x83;
+/// [action] -> [], only LED and Bluetooth actions
+pub const TRIGGER_ACTION: u8 = 0x84;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_UNKNOWN_COMMAND: u8 = 1;
+pub const STATUS_INVALID_ARGUMENT: u8 = 2;
+
+/// Run the command in `request` and return the response. Triggered
+/// actions go to `queue_action`, the same way `Keyboard::process`
+/// hands them on through `KeyboardOutput::queue_peripheral_action`.
+pub fn execute<F>(
+    request: &RawReport,
+    keyboard: &mut Keyboard,
+    eeprom: &mut Eeprom,
+    mut queue_action: F,
+) -> RawReport
+where
+    F: FnMut(Action),
+{
+    let mut response = [0; REPORT_SIZE];
+    response[0] = request[0];
+    let args = &request[1..];
+    let data = &mut response[2..];
+
+    let status = match request[0] {
+        GET_VERSION => {
+            let version = env!("CARGO_PKG_VERSION").as_bytes();
+            data[0] = PROTOCOL_VERSION;
+            data[1] = version.len() as u8;
+            data[2..2 + version.len()].copy_from_slice(version);
+            STATUS_OK
+        }
+        GET_LAYER_STATE => {
+            data[0] = keyboard.active_layers();
+            data[1] = LAYERS.len() as u8;
+            STATUS_OK
+        }
+        GET_KEYMAP_ENTRY => match keyboard.keymap_action(args[0].into(), args[1].into()) {
+            Some(action) => {
+                data[..ACTION_SIZE].copy_from_slice(&action.to_bytes());
+                STATUS_OK
+            }
+            None => STATUS_INVALID_ARGUMENT,
+        },
+        SET_KEYMAP_ENTRY => {
+            let (layer, key) = (args[0].into(), args[1].into());
+            match Action::from_bytes([args[2], args[3], args[4]]) {
+                Some(action) if keyboard.set_keymap_action(layer, key, action) => {
+                    eeprom.store_action(layer, key, action).log_error();
+                    STATUS_OK
+                }
+                _ => STATUS_INVALID_ARGUMENT,
+            }
+        }
+        TRIGGER_ACTION => match Action::from_bytes([args[0], args[1], args[2]]) {
+            Some(action) if action.is_peripheral() => {
+                queue_action(action);
+                STATUS_OK
+            }
+            _ => STATUS_INVALID_ARGUMENT,
+        },
+        _ => STATUS_UNKNOWN_COMMAND,
+    };
+    response[1] = status;
+    response
+}
@@ -0,0 +1,133 @@
+use core::fmt::{self, Write};
+use core::str;
+
+use crate::bluetooth::BluetoothStatus;
+use crate::clock::SYSCLK_HZ;
+use crate::debug::{StageTimer, LATENCY};
+use crate::layout::LAYER_NAMES;
+use bit_field::BitField;
+use heapless::consts::U512;
+use heapless::Vec;
+
+/// Everything the console commands can show, collected from the
+/// resources before running one
+pub struct Status {
+    /// Bit-field of the active layers, see `Keyboard::active_layers`
+    pub layers: u8,
+    pub bluetooth: BluetoothStatus,
+    /// LED controller powered and showing a theme
+    pub led_on: bool,
+    pub led_theme: Option<u8>,
+    pub led_brightness: Option<u8>,
+    pub led_animation_speed: Option<u8>,
+    pub scan_duration_us: u32,
+}
+
+const HELP: &str = "\
+help     this list
+layers   all layers, * marks the active ones
+bt       bluetooth mode and hosts
+led      LED theme
+stats    worst case latency of each stage
+log      recent log output, which is cleared by reading it
+";
+
+pub const PROMPT: &str = "> ";
+
+/// What is left to send once `execute` has written its output
+pub enum Reply {
+    /// The output ends with the next prompt
+    Done,
+    /// The log follows, and then the prompt. It's read from `LOG` a
+    /// bit at a time, as the serial port gets through it.
+    Log,
+}
+
+/// Output of a command, with `\n` turned into `\r\n` for the terminal.
+/// Collected before it's handed to the serial port, so the USB
+/// interrupt isn't held up by formatting.
+#[derive(Default)]
+pub struct Output {
+    pub bytes: Vec<u8, U512>,
+}
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if byte == b'\n' {
+                self.bytes.push(b'\r').map_err(|_| fmt::Error)?;
+            }
+            self.bytes.push(byte).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run the command in `line` and write its output, followed by the
+/// next prompt unless there's more to come
+pub fn execute<W: Write>(line: &[u8], status: &Status, out: &mut W) -> Result<Reply, fmt::Error> {
+    let command = str::from_utf8(line).unwrap_or("").trim();
+    match command {
+        "" => {}
+        "help" => out.write_str(HELP)?,
+        "layers" => layers(status, out)?,
+        "bt" => bluetooth(&status.bluetooth, out)?,
+        "led" => led(status, out)?,
+        "stats" => stats(status, out)?,
+        "log" => return Ok(Reply::Log),
+        _ => writeln!(out, "unknown command {:?}, try help", command)?,
+    }
+    out.write_str(PROMPT)?;
+    Ok(Reply::Done)
+}
+
+fn layers<W: Write>(status: &Status, out: &mut W) -> fmt::Result {
+    for (i, name) in LAYER_NAMES.iter().enumerate() {
+        let active = if status.layers.get_bit(i) { '*' } else { ' ' };
+        writeln!(out, "{} {} {}", active, i, name)?;
+    }
+    Ok(())
+}
+
+fn bluetooth<W: Write>(status: &BluetoothStatus, out: &mut W) -> fmt::Result {
+    writeln!(out, "mode: {:?}", status.mode)?;
+    out.write_str("saved hosts:")?;
+    for host in 1..=4 {
+        if status.saved_hosts.get_bit(host - 1) {
+            write!(out, " {}", host)?;
+        }
+    }
+    match status.connected_host {
+        0 => writeln!(out, "\nconnected: none"),
+        12 => writeln!(out, "\nconnected: unsaved host"),
+        host => writeln!(out, "\nconnected: {}", host),
+    }
+}
+
+fn led<W: Write>(status: &Status, out: &mut W) -> fmt::Result {
+    writeln!(out, "on: {}", status.led_on)?;
+    writeln!(out, "theme: {:?}", status.led_theme)?;
+    writeln!(out, "brightness: {:?}", status.led_brightness)?;
+    writeln!(out, "animation speed: {:?}", status.led_animation_speed)
+}
+
+fn stats<W: Write>(status: &Status, out: &mut W) -> fmt::Result {
+    let stages: [(&str, &StageTimer); 5] = [
+        ("scan", &LATENCY.scan),
+        ("process", &LATENCY.process),
+        ("usb", &LATENCY.usb),
+        ("bluetooth", &LATENCY.bluetooth),
+        ("led", &LATENCY.led),
+    ];
+    for (name, timer) in stages.iter() {
+        let cycles = timer.max();
+        writeln!(
+            out,
+            "{}: {} cycles, {} us",
+            name,
+            cycles,
+            cycles / (SYSCLK_HZ / 1_000_000)
+        )?;
+    }
+    writeln!(out, "last scan: {} us", status.scan_duration_us)
+}
@@ -1,16 +1,89 @@
 // TODO: install exception handler to deal with hio semihosting not being available
 // and just ignore bkpts if no debugger attached
-use core::fmt;
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m::peripheral::DWT;
 
-#[cfg(not(feature = "use_semihosting"))]
+/// Without a debugger attached the output goes to `LOG` instead,
+/// which can be read through the USB serial console
+#[cfg(all(not(test), not(feature = "use_semihosting")))]
+#[macro_export]
+macro_rules! heprintln {
+    ($($arg:tt)*) => {
+        $crate::debug::log(format_args!($($arg)*))
+    };
+}
+
+/// Host tests print to stderr
+#[cfg(test)]
 #[macro_export]
 macro_rules! heprintln {
     ($($arg:tt)*) => {{
-        let res: Result<(), ()> = Ok(());
-        res
+        std::eprintln!($($arg)*);
+        Ok::<(), ()>(())
     }};
 }
 
+const LOG_SIZE: usize = 512;
+
+/// The most recent `LOG_SIZE` bytes of log output
+pub struct LogBuffer {
+    buffer: [u8; LOG_SIZE],
+    /// Index of the oldest byte
+    start: usize,
+    len: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> LogBuffer {
+        LogBuffer {
+            buffer: [0; LOG_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Move the oldest bytes into `buf`, returns how many
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = self.len.min(buf.len());
+        for byte in &mut buf[..count] {
+            *byte = self.buffer[self.start];
+            self.start = (self.start + 1) % LOG_SIZE;
+        }
+        self.len -= count;
+        count
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == LOG_SIZE {
+                // Overwrite the oldest byte
+                self.start = (self.start + 1) % LOG_SIZE;
+                self.len -= 1;
+            }
+            self.buffer[(self.start + self.len) % LOG_SIZE] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+pub static LOG: Mutex<RefCell<LogBuffer>> = Mutex::new(RefCell::new(LogBuffer::new()));
+
+/// Append a line to `LOG`, from any priority
+#[allow(dead_code)]
+pub fn log(args: fmt::Arguments<'_>) -> Result<(), ()> {
+    interrupt::free(|cs| {
+        let mut log = LOG.borrow(cs).borrow_mut();
+        log.write_fmt(args).and_then(|_| log.write_char('\n'))
+    })
+    .map_err(|_| ())
+}
+
 pub trait UnwrapLog {
     fn log_error(self);
 }
@@ -29,3 +102,53 @@ impl<E: fmt::Debug> UnwrapLog for Result<(), E> {
     #[cfg(not(feature = "use_semihosting"))]
     fn log_error(self) {}
 }
+
+/// Worst-case wall time of one pipeline stage, in core clock cycles.
+///
+/// This includes any time spent in higher priority stages that
+/// preempted it, so it is the latency that stage adds.
+pub struct StageTimer {
+    max: AtomicU32,
+}
+
+impl StageTimer {
+    pub const fn new() -> StageTimer {
+        StageTimer {
+            max: AtomicU32::new(0),
+        }
+    }
+
+    /// Run `f`, recording how long it took if that's a new maximum.
+    ///
+    /// Requires the DWT cycle counter to be enabled.
+    pub fn measure<R>(&self, f: impl FnOnce() -> R) -> R {
+        let start = DWT::get_cycle_count();
+        let result = f();
+        let cycles = DWT::get_cycle_count().wrapping_sub(start);
+        if cycles > self.max.load(Ordering::Relaxed) {
+            self.max.store(cycles, Ordering::Relaxed);
+        }
+        result
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max.load(Ordering::Relaxed)
+    }
+}
+
+/// Stage timers of the scan -> process -> output pipeline
+pub struct Latency {
+    pub scan: StageTimer,
+    pub process: StageTimer,
+    pub usb: StageTimer,
+    pub bluetooth: StageTimer,
+    pub led: StageTimer,
+}
+
+pub static LATENCY: Latency = Latency {
+    scan: StageTimer::new(),
+    process: StageTimer::new(),
+    usb: StageTimer::new(),
+    bluetooth: StageTimer::new(),
+    led: StageTimer::new(),
+};
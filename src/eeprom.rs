@@ -0,0 +1,219 @@
+use core::ptr;
+
+use crate::action::Action;
+use crate::keymatrix::{COLUMNS, ROWS};
+use crate::layout::LAYERS;
+use stm32l1::stm32l151::FLASH;
+
+/// Start of the data EEPROM in the address space, 4 KiB on the
+/// STM32L151C8
+const BASE: usize = 0x0808_0000;
+
+const PEKEY1: u32 = 0x89AB_CDEF;
+const PEKEY2: u32 = 0x0203_0405;
+
+/// WRPERR, PGAERR, SIZERR and OPTVERR in FLASH_SR
+const SR_ERRORS: u32 = 0x0F00;
+
+// Layout of the stored settings. Erased words read as 0, so every area
+// is laid out to mean "nothing changed" when it's all zeros, and a new
+// keyboard needs no formatting. Resetting the keymap or the macros
+// only changes a single word, rewriting the whole area would keep
+// interrupts off for more than a second.
+
+/// VIA layout options, see `via::execute`
+pub const LAYOUT_OPTIONS: usize = 0;
+/// Lowest byte: the keymap words written since the last reset start
+/// with this plus one, see `stored_action`
+const KEYMAP_GENERATION: usize = LAYOUT_OPTIONS + 4;
+/// How many bytes of `MACROS` were written since the last reset, the
+/// rest of it reads as zeros
+const MACROS_LENGTH: usize = KEYMAP_GENERATION + 4;
+/// One word per key of every layer, see `stored_action`
+const KEYMAP: usize = MACROS_LENGTH + 4;
+const KEYMAP_SIZE: usize = LAYERS.len() * COLUMNS * ROWS * 4;
+/// The NUL separated macros of `macros::MacroPlayer`
+const MACROS: usize = KEYMAP + KEYMAP_SIZE;
+pub const MACROS_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub struct WriteError {
+    /// The error flags of FLASH_SR
+    pub status: u32,
+}
+
+pub fn read_word(offset: usize) -> u32 {
+    unsafe { ptr::read_volatile((BASE + offset) as *const u32) }
+}
+
+fn read_byte(offset: usize) -> u8 {
+    unsafe { ptr::read_volatile((BASE + offset) as *const u8) }
+}
+
+fn keymap_offset(layer: usize, key: usize) -> usize {
+    KEYMAP + (layer * COLUMNS * ROWS + key) * 4
+}
+
+/// First byte of the keymap words that hold an action, followed by the
+/// action in `Action::to_bytes` format. Words from before the last
+/// reset have a different one. Never 0, which is an unchanged key.
+fn keymap_stored() -> u8 {
+    read_byte(KEYMAP_GENERATION).min(254) + 1
+}
+
+/// The action saved for `key` on `layer`, `None` if it's still the one
+/// from `layout::LAYERS`
+pub fn stored_action(layer: usize, key: usize) -> Option<Action> {
+    let [stored, kind, arg0, arg1] = read_word(keymap_offset(layer, key)).to_le_bytes();
+    if stored != keymap_stored() {
+        return None;
+    }
+    Action::from_bytes([kind, arg0, arg1])
+}
+
+fn macros_length() -> usize {
+    (read_word(MACROS_LENGTH) as usize).min(MACROS_SIZE)
+}
+
+/// The byte at `offset` in the macro buffer, 0 past its end
+pub fn macros_byte(offset: usize) -> u8 {
+    if offset < macros_length() {
+        read_byte(MACROS + offset)
+    } else {
+        0
+    }
+}
+
+/// Copy the macro buffer from `offset` on into `buf`
+pub fn read_macros(offset: usize, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = macros_byte(offset + i);
+    }
+}
+
+/// Write access to the data EEPROM.
+///
+/// Every changed word takes about 3.3 ms to erase and program, during
+/// which the CPU can't fetch from the program flash either, so all
+/// interrupts are held up. Only write in response to the user changing
+/// a setting.
+pub struct Eeprom {
+    flash: FLASH,
+}
+
+impl Eeprom {
+    pub fn new(flash: FLASH) -> Eeprom {
+        Eeprom { flash }
+    }
+
+    /// Write `value` to the word aligned `offset`, unless it's there
+    /// already
+    pub fn write_word(&mut self, offset: usize, value: u32) -> Result<(), WriteError> {
+        if read_word(offset) == value {
+            return Ok(());
+        }
+
+        if self.flash.pecr.read().pelock().bit_is_set() {
+            self.flash
+                .pekeyr
+                .write(|w| unsafe { w.pekeyr().bits(PEKEY1) });
+            self.flash
+                .pekeyr
+                .write(|w| unsafe { w.pekeyr().bits(PEKEY2) });
+        }
+        // With FTDW cleared the word is erased first if needed
+        unsafe { ptr::write_volatile((BASE + offset) as *mut u32, value) };
+        while self.flash.sr.read().bsy().bit_is_set() {}
+        self.flash.pecr.modify(|_, w| w.pelock().set_bit());
+
+        let status = self.flash.sr.read().bits() & SR_ERRORS;
+        if status != 0 {
+            // The error flags are cleared by writing 1
+            self.flash.sr.write(|w| unsafe { w.bits(status) });
+            return Err(WriteError { status });
+        }
+        Ok(())
+    }
+
+    /// Write `data` to any `offset`, keeping the other bytes of the
+    /// words it touches
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), WriteError> {
+        let end = offset + data.len();
+        let mut word_offset = offset & !3;
+        while word_offset < end {
+            let mut word = read_word(word_offset).to_le_bytes();
+            for (i, byte) in word.iter_mut().enumerate() {
+                let position = word_offset + i;
+                if position >= offset && position < end {
+                    *byte = data[position - offset];
+                }
+            }
+            self.write_word(word_offset, u32::from_le_bytes(word))?;
+            word_offset += 4;
+        }
+        Ok(())
+    }
+
+    /// Set the word aligned area at `offset` back to zeros
+    pub fn clear(&mut self, offset: usize, size: usize) -> Result<(), WriteError> {
+        for word_offset in (offset..offset + size).step_by(4) {
+            self.write_word(word_offset, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Save `action` for `key` on `layer`, see `Keyboard::load_keymap`.
+    /// Actions that are the same as in `layout::LAYERS` aren't stored,
+    /// so they follow changes of the built-in layout.
+    pub fn store_action(
+        &mut self,
+        layer: usize,
+        key: usize,
+        action: Action,
+    ) -> Result<(), WriteError> {
+        let value = if action == LAYERS[layer][key] {
+            0
+        } else {
+            let [kind, arg0, arg1] = action.to_bytes();
+            u32::from_le_bytes([keymap_stored(), kind, arg0, arg1])
+        };
+        self.write_word(keymap_offset(layer, key), value)
+    }
+
+    /// Forget all saved actions. Once all 255 generations were used up
+    /// the words are really cleared, which takes about a second.
+    pub fn clear_keymap(&mut self) -> Result<(), WriteError> {
+        match read_byte(KEYMAP_GENERATION) {
+            254 => {
+                self.clear(KEYMAP, KEYMAP_SIZE)?;
+                self.write_word(KEYMAP_GENERATION, 0)
+            }
+            generation => self.write_word(KEYMAP_GENERATION, u32::from(generation) + 1),
+        }
+    }
+
+    /// Write `data` to the macro buffer at `offset`, what's between
+    /// its end so far and `offset` is cleared first
+    pub fn write_macros(&mut self, offset: usize, data: &[u8]) -> Result<(), WriteError> {
+        let length = macros_length();
+        let end = offset + data.len();
+        if length < offset {
+            // The rest of the last word written, then whole words
+            let aligned = ((length + 3) & !3).min(offset);
+            self.write(MACROS + length, &[0; 3][..aligned - length])?;
+            if aligned < offset {
+                self.clear(MACROS + aligned, (offset - aligned + 3) & !3)?;
+            }
+        }
+        self.write(MACROS + offset, data)?;
+        if length < end {
+            self.write_word(MACROS_LENGTH, end as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Forget all macros
+    pub fn clear_macros(&mut self) -> Result<(), WriteError> {
+        self.write_word(MACROS_LENGTH, 0)
+    }
+}
@@ -1,7 +1,24 @@
+use core::mem::size_of;
 use core::slice;
 
+/// Number of bytes needed for one bit per usage from 0x00 up to
+/// `KeyCode::Application` (0x65), rounded up to 0x67
+pub const NKRO_BITMAP_LEN: usize = 13;
+
+/// Bits of the keyboard LED output report
+pub const LED_NUM_LOCK: u8 = 1 << 0;
+pub const LED_CAPS_LOCK: u8 = 1 << 1;
+pub const LED_SCROLL_LOCK: u8 = 1 << 2;
+
+/// Report IDs from `usb::descriptors::HID_REPORT_DESC`. They're only
+/// used in the report protocol, boot reports never carry an ID.
+pub const REPORT_ID_KEYBOARD: u8 = 1;
+pub const REPORT_ID_CONSUMER: u8 = 2;
+pub const REPORT_ID_SYSTEM: u8 = 3;
+
+/// Boot protocol keyboard report, limited to six normal keys
 #[repr(packed)]
-#[derive(Default)]
+#[derive(Clone, Copy, Default)]
 pub struct HidReport {
     pub modifiers: u8,
     _unused: u8,
@@ -12,7 +29,110 @@ impl HidReport {
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
             let p: *const HidReport = self;
-            slice::from_raw_parts(p as *const u8, 8)
+            slice::from_raw_parts(p as *const u8, size_of::<HidReport>())
+        }
+    }
+}
+
+/// Report protocol keyboard report, as described by
+/// `usb::descriptors::HID_REPORT_DESC`.
+///
+/// It contains a boot report so the same descriptor works for both
+/// 6KRO and NKRO: with NKRO the `boot.keys` array is left empty and
+/// all keys go into `bitmap` instead, otherwise `bitmap` stays empty.
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct NkroReport {
+    report_id: u8,
+    pub boot: HidReport,
+    /// One bit per usage, `KeyCode::A` is bit 4 of byte 0
+    pub bitmap: [u8; NKRO_BITMAP_LEN],
+}
+
+impl NkroReport {
+    pub fn new(boot: HidReport, bitmap: [u8; NKRO_BITMAP_LEN]) -> NkroReport {
+        NkroReport {
+            report_id: REPORT_ID_KEYBOARD,
+            boot,
+            bitmap,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let p: *const NkroReport = self;
+            slice::from_raw_parts(p as *const u8, size_of::<NkroReport>())
+        }
+    }
+}
+
+/// Consumer control report with a single usage from the consumer page
+/// (see `keycodes::consumer`), 0 when nothing is pressed
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct ConsumerReport {
+    report_id: u8,
+    pub usage: u16,
+}
+
+impl ConsumerReport {
+    pub fn new(usage: u16) -> ConsumerReport {
+        ConsumerReport {
+            report_id: REPORT_ID_CONSUMER,
+            usage,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let p: *const ConsumerReport = self;
+            slice::from_raw_parts(p as *const u8, size_of::<ConsumerReport>())
+        }
+    }
+}
+
+/// System control report with a single usage from the generic desktop
+/// page (see `keycodes::SystemControl`), 0 when nothing is pressed
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct SystemReport {
+    report_id: u8,
+    pub usage: u8,
+}
+
+impl SystemReport {
+    pub fn new(usage: u8) -> SystemReport {
+        SystemReport {
+            report_id: REPORT_ID_SYSTEM,
+            usage,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let p: *const SystemReport = self;
+            slice::from_raw_parts(p as *const u8, size_of::<SystemReport>())
+        }
+    }
+}
+
+/// Report of the mouse interface, see
+/// `usb::descriptors::MOUSE_REPORT_DESC`. Movement is relative, so
+/// every report is only sent once.
+#[repr(packed)]
+#[derive(Clone, Copy, Default)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+}
+
+impl MouseReport {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            let p: *const MouseReport = self;
+            slice::from_raw_parts(p as *const u8, size_of::<MouseReport>())
         }
     }
 }
@@ -1,29 +1,110 @@
 use crate::action::Action;
 use crate::bluetooth::Bluetooth;
 use crate::debug::UnwrapLog;
-use crate::hidreport::HidReport;
+use crate::eeprom;
+use crate::hidreport::{HidReport, MouseReport, NkroReport, NKRO_BITMAP_LEN};
 use crate::keycodes::KeyCode;
-use crate::keymatrix::{KeyState, COLUMNS, ROWS};
-use crate::layout::LAYERS;
+use crate::keymatrix::{KeyEvent, KeyState, COLUMNS, ROWS};
 use crate::layout::LAYER_BT;
-use crate::led::Led;
-use crate::usb::Usb;
+use crate::layout::{Layout, LAYERS};
+use crate::led::{KeyLights, Led};
+use crate::macros::MacroPlayer;
+use crate::matrixtest::MatrixTest;
+use crate::mousekeys::MouseKeys;
 use bit_field::{BitArray, BitField};
 use core::marker::Unsize;
 use stm32l1::stm32l151::SCB;
 
+/// An output queue had no room, see `KeyboardOutput`
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// Everything `Keyboard::process` hands on to the output stages.
+///
+/// Implementations are expected to queue the work instead of doing
+/// it right away, so that slow peripherals never hold up processing.
+/// Key reports that don't fit into the queue are sent again by the
+/// next `Keyboard::process`, since a lost release means a stuck key.
+/// The key events after them stay in the key matrix until then, so a
+/// short tap isn't lost either.
+pub trait KeyboardOutput {
+    /// The same keys as a boot and as a report protocol report, the
+    /// USB host decides which one gets sent
+    fn queue_usb_report(
+        &mut self,
+        boot_report: &HidReport,
+        report: &NkroReport,
+    ) -> Result<(), QueueFull>;
+    /// Consumer page usage of the pressed media key, or 0. Only
+    /// queued when it changes.
+    fn queue_usb_consumer_report(&mut self, usage: u16) -> Result<(), QueueFull>;
+    /// Usage of the pressed `SystemControl` key, or 0. Only queued
+    /// when it changes.
+    fn queue_usb_system_report(&mut self, usage: u8) -> Result<(), QueueFull>;
+    fn queue_usb_mouse_report(&mut self, report: &MouseReport);
+    /// Ask the suspended USB host to wake up
+    fn queue_usb_wakeup(&mut self);
+    fn queue_bluetooth_report(&mut self, report: &HidReport) -> Result<(), QueueFull>;
+    /// Pressed keys, for LED themes that react to typing
+    fn queue_led_keys(&mut self, state: &KeyState);
+    /// A pressed action that `Action::is_peripheral`
+    fn queue_peripheral_action(&mut self, action: Action);
+    /// Entering (true) or leaving (false) the Bluetooth layer
+    fn queue_bluetooth_mode(&mut self, enabled: bool, send_usb_report: bool);
+    /// Individual key colours, overriding the LED theme
+    fn queue_key_lights(&mut self, lights: KeyLights);
+}
+
 pub struct Keyboard {
+    /// Starts out as `layout::LAYERS` with the changes saved in the
+    /// EEPROM, and can be changed at runtime
+    keymap: [Layout; LAYERS.len()],
     layers: Layers,
-    previous_state: KeyState,
+    /// Keys held down, according to the events processed so far
+    state: KeyState,
     pub send_usb_report: bool,
+    /// The USB host is asleep, the next key press should wake it up
+    pub usb_suspended: bool,
+    /// Report all keys over USB instead of only six at a time, as
+    /// long as the host uses the report protocol
+    nkro: bool,
+    key_slots: KeySlots,
+    /// Last consumer usage sent over USB
+    consumer: u16,
+    /// Last system control usage sent over USB
+    system: u8,
+    /// Last report queued for USB, boot and report protocol
+    usb_report: Option<(HidReport, NkroReport)>,
+    /// Last report queued for Bluetooth
+    bluetooth_report: Option<HidReport>,
+    /// Some report for the held keys didn't fit into its queue
+    reports_pending: bool,
+    mouse_keys: MouseKeys,
+    macros: MacroPlayer,
+    /// A macro report that didn't fit into its queue yet
+    macro_report: Option<HidReport>,
+    matrix_test: Option<MatrixTest>,
 }
 
 impl Keyboard {
     pub const fn new() -> Keyboard {
         Keyboard {
+            keymap: LAYERS,
             layers: Layers::new(),
-            previous_state: [0; 9],
+            state: [0; 9],
             send_usb_report: true,
+            usb_suspended: false,
+            nkro: true,
+            key_slots: KeySlots::new(),
+            consumer: 0,
+            system: 0,
+            usb_report: None,
+            bluetooth_report: None,
+            reports_pending: false,
+            mouse_keys: MouseKeys::new(),
+            macros: MacroPlayer::new(),
+            macro_report: None,
+            matrix_test: None,
         }
     }
 
@@ -34,9 +115,9 @@ impl Keyboard {
     fn get_action(&self, key: usize) -> Action {
         let mut action = Action::Transparent;
 
-        for i in (0..LAYERS.len()).rev() {
+        for i in (0..self.keymap.len()).rev() {
             if self.layers.current.get_bit(i) {
-                action = LAYERS[i][key];
+                action = self.keymap[i][key];
             }
             if action != Action::Transparent {
                 break;
@@ -46,62 +127,310 @@ impl Keyboard {
         action
     }
 
-    pub fn process<BUFFER>(
-        &mut self,
-        state: &KeyState,
-        bluetooth: &mut Bluetooth<BUFFER>,
-        led: &mut Led<BUFFER>,
-        usb: &mut Usb,
-    ) where
-        BUFFER: Unsize<[u8]>,
+    /// Handle `events` in order, sending the reports each of them
+    /// results in. Stops taking events while a report doesn't fit into
+    /// its queue.
+    ///
+    /// Also needs to be called regularly without any events, for
+    /// everything that depends on `now` (in `clock::now_ms()`) alone.
+    pub fn process<E, O>(&mut self, events: E, now: u32, output: &mut O)
+    where
+        E: IntoIterator<Item = KeyEvent>,
+        O: KeyboardOutput,
     {
-        // TODO: might not even need this check after switching to wakeup only handling?
-        if &self.previous_state != state {
-            let mut hid = HidProcessor::default();
+        let was_testing = self.matrix_test.is_some();
+        let mut events = events.into_iter();
+        let mut changed = false;
+        loop {
+            // While testing this retries the empty report that released
+            // the keys held when the test started. Key events don't
+            // change the reports then, so they don't have to wait.
+            if self.reports_pending && !self.is_playing_macro() {
+                self.send_reports(output);
+                if self.reports_pending && !was_testing {
+                    break;
+                }
+            }
+            let event = match events.next() {
+                Some(event) => event,
+                None => break,
+            };
+            self.process_event(&event, output);
+            changed = true;
+            // No reports for the held keys while testing or while a
+            // macro plays
+            if !was_testing && !self.is_playing_macro() {
+                self.send_reports(output);
+            }
+        }
+        if changed && self.matrix_test.is_none() {
+            output.queue_led_keys(&self.state);
+        }
 
+        if let Some(matrix_test) = self.matrix_test.as_mut() {
+            if let Some(lights) = matrix_test.poll(&self.state, now) {
+                output.queue_key_lights(lights);
+            }
+            if was_testing {
+                // No HID output at all while testing. Entering the test
+                // sent an empty report after its event, or retries it
+                // above until it fits.
+                return;
+            }
+        }
+        if self.is_playing_macro() {
+            if self.macro_report.is_none() {
+                self.macro_report = self.macros.poll(now);
+            }
+            if let Some(report) = self.macro_report {
+                if self.send_macro_report(&report, output).is_ok() {
+                    self.macro_report = None;
+                }
+            }
+            if !self.is_playing_macro() {
+                // Back to the keys held meanwhile
+                self.send_reports(output);
+            }
+        }
+
+        // Mouse keys keep moving without any events
+        if let Some(report) = self.mouse_keys.poll(now) {
+            if self.send_usb_report {
+                output.queue_usb_mouse_report(&report);
+            }
+        }
+    }
+
+    /// Whether a macro is playing or its last report is still waiting
+    fn is_playing_macro(&self) -> bool {
+        self.macros.is_playing() || self.macro_report.is_some()
+    }
+
+    /// Send the reports for the keys currently held that differ from
+    /// the last ones sent. If any of them doesn't fit into its queue,
+    /// `reports_pending` is set and the next call tries again.
+    fn send_reports<O>(&mut self, output: &mut O)
+    where
+        O: KeyboardOutput,
+    {
+        let mut hid = HidProcessor::default();
+        if self.matrix_test.is_none() {
             for key in 0..COLUMNS * ROWS {
-                let pressed = state.get_bit(key);
-                let changed = self.previous_state.get_bit(key) != pressed;
-
-                // Only handle currently pressed and changed keys to
-                // cut down on processing time.
-                if pressed || changed {
-                    let action = self.get_action(key);
-                    if pressed && Action::Reset == action {
-                        crate::heprintln!("system reset").ok();
-                        SCB::sys_reset()
-                    }
-                    if pressed && Action::UsbToggle == action {
-                        self.send_usb_report = !self.send_usb_report;
-                        crate::heprintln!("send_usb_report: {:?}", self.send_usb_report).ok();
-                    }
-                    hid.process(&action, pressed, changed);
-                    led.process(&action, pressed, changed);
-                    bluetooth.process(&action, pressed, changed);
-                    self.layers.process(&action, pressed, changed);
+                if self.state.get_bit(key) {
+                    hid.process(&self.get_action(key), true, false);
+                }
+            }
+        }
+
+        self.key_slots.update(&hid.bitmap);
+        hid.report.keys = self.key_slots.report_keys();
+        self.mouse_keys.set_held(hid.mouse);
+
+        let mut result = self.queue_bluetooth_report(&hid.report, output);
+        if self.send_usb_report {
+            let nkro_report = hid.nkro_report(self.nkro);
+            result = result.and(self.queue_usb_report(&hid.report, &nkro_report, output));
+            if hid.consumer != self.consumer {
+                match output.queue_usb_consumer_report(hid.consumer) {
+                    Ok(()) => self.consumer = hid.consumer,
+                    Err(full) => result = Err(full),
                 }
             }
+            if hid.system != self.system {
+                match output.queue_usb_system_report(hid.system) {
+                    Ok(()) => self.system = hid.system,
+                    Err(full) => result = Err(full),
+                }
+            }
+        }
+        self.reports_pending = result.is_err();
+    }
+
+    /// Send a report of a playing macro instead of the held keys
+    fn send_macro_report<O>(&mut self, report: &HidReport, output: &mut O) -> Result<(), QueueFull>
+    where
+        O: KeyboardOutput,
+    {
+        let result = self.queue_bluetooth_report(report, output);
+        if self.send_usb_report {
+            // The keys array works with NKRO too
+            let nkro_report = NkroReport::new(*report, [0; NKRO_BITMAP_LEN]);
+            result.and(self.queue_usb_report(report, &nkro_report, output))
+        } else {
+            result
+        }
+    }
+
+    /// Queue `report` unless it's the last one queued
+    fn queue_bluetooth_report<O>(
+        &mut self,
+        report: &HidReport,
+        output: &mut O,
+    ) -> Result<(), QueueFull>
+    where
+        O: KeyboardOutput,
+    {
+        if let Some(last) = &self.bluetooth_report {
+            if last.as_bytes() == report.as_bytes() {
+                return Ok(());
+            }
+        }
+        output.queue_bluetooth_report(report)?;
+        self.bluetooth_report = Some(*report);
+        Ok(())
+    }
 
-            let bt_layer_current: bool = self.bluetooth_mode_enabled();
-            let bt_layer_next: bool = self.layers.next.get_bit(LAYER_BT as usize);
-            if bt_layer_next && !bt_layer_current {
-                bluetooth.update_led(led, self.send_usb_report).log_error();
-            } else if bt_layer_current && !bt_layer_next {
-                led.theme_mode().log_error();
+    /// Queue `boot_report` and `report` unless they're the last ones
+    /// queued
+    fn queue_usb_report<O>(
+        &mut self,
+        boot_report: &HidReport,
+        report: &NkroReport,
+        output: &mut O,
+    ) -> Result<(), QueueFull>
+    where
+        O: KeyboardOutput,
+    {
+        if let Some((last_boot_report, last_report)) = &self.usb_report {
+            if last_boot_report.as_bytes() == boot_report.as_bytes()
+                && last_report.as_bytes() == report.as_bytes()
+            {
+                return Ok(());
             }
+        }
+        output.queue_usb_report(boot_report, report)?;
+        self.usb_report = Some((*boot_report, *report));
+        Ok(())
+    }
 
+    fn process_event<O>(&mut self, event: &KeyEvent, output: &mut O)
+    where
+        O: KeyboardOutput,
+    {
+        let key = event.index as usize;
+        let pressed = event.pressed;
+        self.state.set_bit(key, pressed);
+
+        let action = self.get_action(key);
+        if pressed && Action::MatrixTest == action {
+            self.toggle_matrix_test(event.time, output);
+        }
+        if let Some(matrix_test) = self.matrix_test.as_mut() {
+            // Keys only light up, but layers still work so the test
+            // can be stopped again.
+            matrix_test.key_event(key, pressed, event.time);
+            self.layers.process(&action, pressed, true);
             self.layers.finish();
+            return;
+        }
 
-            bluetooth.send_report(&hid.report).log_error();
-            led.send_keys(state).log_error();
-            if self.send_usb_report {
-                usb.update_report(&hid.report);
+        if pressed && self.usb_suspended && self.send_usb_report {
+            // Only once, the wakeup takes a while to come through
+            self.usb_suspended = false;
+            output.queue_usb_wakeup();
+        }
+        if pressed && Action::Reset == action {
+            crate::heprintln!("system reset").ok();
+            SCB::sys_reset()
+        }
+        if pressed && Action::UsbToggle == action {
+            self.send_usb_report = !self.send_usb_report;
+            crate::heprintln!("send_usb_report: {:?}", self.send_usb_report).ok();
+        }
+        if pressed && Action::NkroToggle == action {
+            self.nkro = !self.nkro;
+            crate::heprintln!("nkro: {:?}", self.nkro).ok();
+        }
+        if let Action::Macro(index) = action {
+            if pressed {
+                self.macros.start(index, event.time);
             }
+        }
+        if pressed && action.is_peripheral() {
+            output.queue_peripheral_action(action);
+        }
+        if let Action::Key(code) = action {
+            if code.is_normal_key() {
+                if pressed {
+                    self.key_slots.press(code as u8);
+                } else {
+                    self.key_slots.release(code as u8);
+                }
+            }
+        }
+        self.layers.process(&action, pressed, true);
+
+        let bt_layer_current: bool = self.bluetooth_mode_enabled();
+        let bt_layer_next: bool = self.layers.next.get_bit(LAYER_BT as usize);
+        if bt_layer_next != bt_layer_current {
+            output.queue_bluetooth_mode(bt_layer_next, self.send_usb_report);
+        }
+
+        // Finish layer changes right away so that keys pressed later
+        // in the same scan already see the new layers.
+        self.layers.finish();
+    }
+
+    fn toggle_matrix_test<O>(&mut self, now: u32, output: &mut O)
+    where
+        O: KeyboardOutput,
+    {
+        if self.matrix_test.take().is_some() {
+            crate::heprintln!("matrix test stopped").ok();
+            output.queue_bluetooth_mode(self.bluetooth_mode_enabled(), self.send_usb_report);
+        } else {
+            crate::heprintln!("matrix test started").ok();
+            self.matrix_test = Some(MatrixTest::new(&self.state, now));
+        }
+    }
+
+    /// The action of `key` on `layer`, `None` if either doesn't exist
+    pub fn keymap_action(&self, layer: usize, key: usize) -> Option<Action> {
+        self.keymap.get(layer)?.get(key).copied()
+    }
+
+    /// Change the keymap, returns whether `layer` and `key` exist
+    pub fn set_keymap_action(&mut self, layer: usize, key: usize, action: Action) -> bool {
+        match self
+            .keymap
+            .get_mut(layer)
+            .and_then(|layout| layout.get_mut(key))
+        {
+            Some(entry) => {
+                *entry = action;
+                true
+            }
+            None => false,
+        }
+    }
 
-            self.previous_state = *state;
+    /// Apply the changes saved with `Eeprom::store_action` to the
+    /// built-in keymap
+    pub fn load_keymap(&mut self) {
+        for (layer, layout) in self.keymap.iter_mut().enumerate() {
+            for (key, entry) in layout.iter_mut().enumerate() {
+                if let Some(action) = eeprom::stored_action(layer, key) {
+                    *entry = action;
+                }
+            }
         }
     }
 
+    /// Go back to `layout::LAYERS`
+    pub fn reset_keymap(&mut self) {
+        self.keymap = LAYERS;
+    }
+
+    /// Whether `key` is held down, going by the events processed so far
+    pub fn is_pressed(&self, key: usize) -> bool {
+        self.state.get_bit(key)
+    }
+
+    /// Bit-field of the active layers, see `Layers`
+    pub fn active_layers(&self) -> u8 {
+        self.layers.current
+    }
+
     pub fn bluetooth_mode_enabled(&self) -> bool {
         self.layers.current.get_bit(LAYER_BT as usize)
     }
@@ -111,7 +440,7 @@ impl Keyboard {
     }
 }
 
-trait EventProcessor {
+pub trait EventProcessor {
     fn process(&mut self, action: &Action, pressed: bool, changed: bool);
     fn finish(&mut self) {}
 }
@@ -156,9 +485,98 @@ impl EventProcessor for Layers {
 
 #[derive(Default)]
 struct HidProcessor {
+    /// Modifiers only, the keys are filled in from `KeySlots`
     pub report: HidReport,
-    /// Number of normal keys to be sent in `report`
-    i: usize,
+    /// All pressed normal keys
+    pub bitmap: [u8; NKRO_BITMAP_LEN],
+    /// The first pressed consumer usage, the report only has room for
+    /// one
+    pub consumer: u16,
+    /// The first pressed `SystemControl` usage
+    pub system: u8,
+    /// One `MouseAction::bit` per pressed mouse key
+    pub mouse: u16,
+}
+
+impl HidProcessor {
+    /// The report protocol version of `report`, with the keys moved
+    /// into the bitmap if `nkro` is enabled
+    fn nkro_report(&self, nkro: bool) -> NkroReport {
+        let mut report = NkroReport::new(self.report, [0; NKRO_BITMAP_LEN]);
+        if nkro {
+            report.boot.keys = [0; 6];
+            report.bitmap = self.bitmap;
+        }
+        report
+    }
+}
+
+/// Positions of the pressed normal keys in `HidReport::keys`. A key
+/// keeps its slot for as long as it is held, instead of moving
+/// whenever a key before it in the matrix is released.
+struct KeySlots {
+    keys: [u8; 6],
+    /// More keys are held than there are slots
+    overflow: bool,
+}
+
+impl KeySlots {
+    const fn new() -> KeySlots {
+        KeySlots {
+            keys: [0; 6],
+            overflow: false,
+        }
+    }
+
+    /// Give a newly pressed key the first free slot. Called for each
+    /// event in turn, so keys pressed in the same scan are ordered the
+    /// way they went down.
+    fn press(&mut self, code: u8) {
+        if self.keys.contains(&code) {
+            return;
+        }
+        if let Some(slot) = self.keys.iter_mut().find(|key| **key == 0) {
+            *slot = code;
+        }
+    }
+
+    fn release(&mut self, code: u8) {
+        for key in self.keys.iter_mut().filter(|key| **key == code) {
+            *key = 0;
+        }
+    }
+
+    /// Bring the slots in line with the keys actually held. This frees
+    /// the slots of keys whose release went unseen, and gives keys that
+    /// changed meaning with a layer the first free ones.
+    fn update(&mut self, bitmap: &[u8; NKRO_BITMAP_LEN]) {
+        for key in self.keys.iter_mut() {
+            if *key != 0 && !bitmap.get_bit(*key as usize) {
+                *key = 0;
+            }
+        }
+
+        self.overflow = false;
+        for code in 1..NKRO_BITMAP_LEN * 8 {
+            if !bitmap.get_bit(code) || self.keys.contains(&(code as u8)) {
+                continue;
+            }
+            match self.keys.iter_mut().find(|key| **key == 0) {
+                Some(slot) => *slot = code as u8,
+                None => self.overflow = true,
+            }
+        }
+    }
+
+    /// The keys array of the report. With too many keys for it, the
+    /// host is told so instead of getting a plausible but wrong set.
+    fn report_keys(&self) -> [u8; 6] {
+        if self.overflow {
+            [KeyCode::RollOver as u8; 6]
+        } else {
+            self.keys
+        }
+    }
 }
 
 impl EventProcessor for HidProcessor {
@@ -169,10 +587,19 @@ impl EventProcessor for HidProcessor {
                     self.report
                         .modifiers
                         .set_bit(code as usize - KeyCode::LCtrl as usize, true);
-                } else if code.is_normal_key() && self.i < self.report.keys.len() {
-                    self.report.keys[self.i] = code as u8;
-                    self.i += 1;
+                } else if code.is_normal_key() {
+                    self.bitmap.set_bit(code as usize, true);
+                }
+            } else if let Action::Consumer(usage) = *action {
+                if self.consumer == 0 {
+                    self.consumer = usage;
                 }
+            } else if let Action::System(control) = *action {
+                if self.system == 0 {
+                    self.system = control as u8;
+                }
+            } else if let Action::Mouse(mouse) = *action {
+                self.mouse |= mouse.bit();
             }
         }
     }
@@ -221,3 +648,236 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycodes::{consumer, KeyIndex};
+
+    /// Records the keys of each report and the consumer usages, ignores
+    /// everything else
+    #[derive(Default)]
+    struct Recorder {
+        usb: Vec<[u8; 6]>,
+        /// Each usage with the number of key reports before it
+        consumer: Vec<(usize, u16)>,
+        bluetooth: Vec<[u8; 6]>,
+        /// How many more USB reports fit, unlimited if `None`
+        usb_room: Option<usize>,
+    }
+
+    impl Recorder {
+        fn take_usb_room(&mut self) -> Result<(), QueueFull> {
+            match self.usb_room.as_mut() {
+                Some(0) => Err(QueueFull),
+                Some(room) => {
+                    *room -= 1;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl KeyboardOutput for Recorder {
+        fn queue_usb_report(
+            &mut self,
+            boot_report: &HidReport,
+            _report: &NkroReport,
+        ) -> Result<(), QueueFull> {
+            self.take_usb_room()?;
+            self.usb.push({ boot_report.keys });
+            Ok(())
+        }
+        fn queue_usb_consumer_report(&mut self, usage: u16) -> Result<(), QueueFull> {
+            self.take_usb_room()?;
+            self.consumer.push((self.usb.len(), usage));
+            Ok(())
+        }
+        fn queue_usb_system_report(&mut self, _usage: u8) -> Result<(), QueueFull> {
+            Ok(())
+        }
+        fn queue_usb_mouse_report(&mut self, _report: &MouseReport) {}
+        fn queue_usb_wakeup(&mut self) {}
+        fn queue_bluetooth_report(&mut self, report: &HidReport) -> Result<(), QueueFull> {
+            self.bluetooth.push({ report.keys });
+            Ok(())
+        }
+        fn queue_led_keys(&mut self, _state: &KeyState) {}
+        fn queue_peripheral_action(&mut self, _action: Action) {}
+        fn queue_bluetooth_mode(&mut self, _enabled: bool, _send_usb_report: bool) {}
+        fn queue_key_lights(&mut self, _lights: KeyLights) {}
+    }
+
+    fn event(key: KeyIndex, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            index: key as u8,
+            pressed,
+            time: 0,
+        }
+    }
+
+    /// A 6KRO keyboard, so the boot report is what the host sees
+    fn keyboard() -> Keyboard {
+        let mut keyboard = Keyboard::new();
+        keyboard.nkro = false;
+        keyboard
+    }
+
+    const A: u8 = KeyCode::A as u8;
+    const B: u8 = KeyCode::B as u8;
+    const C: u8 = KeyCode::C as u8;
+    const D: u8 = KeyCode::D as u8;
+    const E: u8 = KeyCode::E as u8;
+    const F: u8 = KeyCode::F as u8;
+    const G: u8 = KeyCode::G as u8;
+    const ROLL_OVER: [u8; 6] = [KeyCode::RollOver as u8; 6];
+    const NONE: [u8; 6] = [0; 6];
+
+    fn press_six(keyboard: &mut Keyboard, output: &mut Recorder) {
+        let keys = vec![
+            KeyIndex::A,
+            KeyIndex::B,
+            KeyIndex::C,
+            KeyIndex::D,
+            KeyIndex::E,
+            KeyIndex::F,
+        ];
+        for (time, key) in keys.into_iter().enumerate() {
+            keyboard.process(vec![event(key, true)], time as u32, output);
+        }
+    }
+
+    #[test]
+    fn six_keys_fit() {
+        let mut keyboard = keyboard();
+        let mut output = Recorder::default();
+        press_six(&mut keyboard, &mut output);
+        assert_eq!(output.usb.last(), Some(&[A, B, C, D, E, F]));
+        assert_eq!(output.bluetooth.last(), Some(&[A, B, C, D, E, F]));
+    }
+
+    #[test]
+    fn seventh_key_rolls_over() {
+        let mut keyboard = keyboard();
+        let mut output = Recorder::default();
+        press_six(&mut keyboard, &mut output);
+        keyboard.process(vec![event(KeyIndex::G, true)], 10, &mut output);
+        // Phantom state: every slot says ErrorRollOver, over USB and
+        // Bluetooth alike
+        assert_eq!(output.usb.last(), Some(&ROLL_OVER));
+        assert_eq!(output.bluetooth.last(), Some(&ROLL_OVER));
+
+        // Back to a real key set as soon as one is released, with G in
+        // the freed slot
+        keyboard.process(vec![event(KeyIndex::A, false)], 11, &mut output);
+        assert_eq!(output.usb.last(), Some(&[G, B, C, D, E, F]));
+        assert_eq!(output.bluetooth.last(), Some(&[G, B, C, D, E, F]));
+    }
+
+    #[test]
+    fn slots_follow_event_order() {
+        let mut keyboard = keyboard();
+        let mut output = Recorder::default();
+        let events = vec![
+            event(KeyIndex::D, true),
+            event(KeyIndex::A, true),
+            event(KeyIndex::C, true),
+        ];
+        keyboard.process(events, 0, &mut output);
+        assert_eq!(output.usb.last(), Some(&[D, A, C, 0, 0, 0]));
+
+        // A release and a press in the same scan reuse the slot
+        let events = vec![event(KeyIndex::A, false), event(KeyIndex::B, true)];
+        keyboard.process(events, 1, &mut output);
+        assert_eq!(output.usb.last(), Some(&[D, B, C, 0, 0, 0]));
+    }
+
+    #[test]
+    fn modifiers_dont_roll_over() {
+        let mut keyboard = keyboard();
+        let mut output = Recorder::default();
+        press_six(&mut keyboard, &mut output);
+        let events = vec![event(KeyIndex::LShift, true), event(KeyIndex::LCtrl, true)];
+        keyboard.process(events, 10, &mut output);
+        assert_eq!(output.usb.last(), Some(&[A, B, C, D, E, F]));
+    }
+
+    #[test]
+    fn tap_within_one_scan() {
+        let mut keyboard = keyboard();
+        let mut output = Recorder::default();
+        let events = vec![event(KeyIndex::A, true), event(KeyIndex::A, false)];
+        keyboard.process(events, 0, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0], NONE]);
+        assert_eq!(output.bluetooth, vec![[A, 0, 0, 0, 0, 0], NONE]);
+    }
+
+    #[test]
+    fn full_queue_holds_back_events() {
+        let mut keyboard = keyboard();
+        let mut output = Recorder {
+            usb_room: Some(1),
+            ..Recorder::default()
+        };
+        let mut events = vec![
+            event(KeyIndex::A, true),
+            event(KeyIndex::A, false),
+            event(KeyIndex::B, true),
+        ]
+        .into_iter();
+        keyboard.process(&mut events, 0, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0]]);
+        // The release is waiting for room, the press after it wasn't
+        // taken yet
+        assert_eq!(events.len(), 1);
+
+        output.usb_room = None;
+        keyboard.process(&mut events, 1, &mut output);
+        assert_eq!(
+            output.usb,
+            vec![[A, 0, 0, 0, 0, 0], NONE, [B, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(events.len(), 0);
+    }
+
+    #[test]
+    fn full_queue_at_matrix_test_start() {
+        let mut keyboard = keyboard();
+        keyboard.set_keymap_action(0, KeyIndex::B as usize, Action::MatrixTest);
+        let mut output = Recorder::default();
+        keyboard.process(vec![event(KeyIndex::A, true)], 0, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0]]);
+
+        output.usb_room = Some(0);
+        keyboard.process(vec![event(KeyIndex::B, true)], 1, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0]]);
+
+        // Keys are still tested while the empty report waits for room
+        let mut events = vec![event(KeyIndex::C, true)].into_iter();
+        keyboard.process(&mut events, 2, &mut output);
+        assert_eq!(events.len(), 0);
+
+        output.usb_room = None;
+        keyboard.process(vec![], 3, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0], NONE]);
+        keyboard.process(vec![event(KeyIndex::C, false)], 4, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0], NONE]);
+    }
+
+    #[test]
+    fn media_key_between_key_reports() {
+        let mut keyboard = keyboard();
+        keyboard.set_keymap_action(0, KeyIndex::B as usize, Action::Consumer(consumer::MUTE));
+        let mut output = Recorder::default();
+        let events = vec![
+            event(KeyIndex::A, true),
+            event(KeyIndex::B, true),
+            event(KeyIndex::B, false),
+            event(KeyIndex::A, false),
+        ];
+        keyboard.process(events, 0, &mut output);
+        assert_eq!(output.usb, vec![[A, 0, 0, 0, 0, 0], NONE]);
+        assert_eq!(output.consumer, vec![(1, consumer::MUTE), (1, 0)]);
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 // USB HID KeyCodes
+#[repr(u8)]
 #[derive(PartialOrd, PartialEq, Copy, Clone)]
 pub enum KeyCode {
     No = 0x00,
@@ -125,6 +126,49 @@ impl KeyCode {
     pub fn is_normal_key(self) -> bool {
         self >= KeyCode::A && self <= KeyCode::Application
     }
+
+    pub fn from_u8(b: u8) -> Option<KeyCode> {
+        match b {
+            // Both ranges are contiguous in the enum
+            0x00..=0x65 | 0xE0..=0xE7 => Some(unsafe { core::mem::transmute(b) }),
+            _ => None,
+        }
+    }
+}
+
+/// Usages from the HID consumer page (0x0C) for `Action::Consumer`
+#[allow(dead_code)]
+pub mod consumer {
+    pub const BRIGHTNESS_UP: u16 = 0x6F;
+    pub const BRIGHTNESS_DOWN: u16 = 0x70;
+    pub const NEXT_TRACK: u16 = 0xB5;
+    pub const PREVIOUS_TRACK: u16 = 0xB6;
+    pub const STOP: u16 = 0xB7;
+    pub const PLAY_PAUSE: u16 = 0xCD;
+    pub const MUTE: u16 = 0xE2;
+    pub const VOLUME_UP: u16 = 0xE9;
+    pub const VOLUME_DOWN: u16 = 0xEA;
+}
+
+/// System control usages from the HID generic desktop page (0x01)
+/// for `Action::System`
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum SystemControl {
+    PowerDown = 0x81,
+    Sleep = 0x82,
+    WakeUp = 0x83,
+}
+
+impl SystemControl {
+    pub fn from_u8(b: u8) -> Option<SystemControl> {
+        match b {
+            0x81 => Some(SystemControl::PowerDown),
+            0x82 => Some(SystemControl::Sleep),
+            0x83 => Some(SystemControl::WakeUp),
+            _ => None,
+        }
+    }
 }
 
 /// Index of each physical key in the scan matrix
@@ -1,13 +1,85 @@
 use bit_field::BitArray;
-use embedded_hal::digital::v2::{InputPin, OutputPin};
 use hal::gpio::gpioa::*;
 use hal::gpio::gpiob::*;
 use hal::gpio::{Input, Output};
-use stm32l1::stm32l151::SYST;
+use heapless::consts::U32;
+use heapless::spsc::Queue;
+use stm32l1::stm32l151::{GPIOA, GPIOB};
+
+use crate::clock;
 
 pub const ROWS: usize = 5;
 pub const COLUMNS: usize = 14;
 
+// How often the matrix gets scanned, anywhere from 1 to 8 kHz, and how
+// long a key ignores changes after one was reported. Set at build time
+// through the `SCAN_RATE_HZ` and `DEBOUNCE_MS` environment variables.
+include!(concat!(env!("OUT_DIR"), "/scan_settings.rs"));
+pub const SCAN_PERIOD_US: u32 = 1_000_000 / SCAN_RATE_HZ;
+/// `DEBOUNCE_MS` in ticks, at most 240
+const DEBOUNCE_TICKS: u8 = (DEBOUNCE_MS * SCAN_RATE_HZ / 1_000) as u8;
+/// While `KeyMatrix::idle`, only every this many ticks are scanned
+pub const IDLE_SCAN_DIVIDER: u8 = 10;
+
+/// Time for the row pins to settle after switching columns
+const SETTLE_US: u32 = 2;
+
+#[derive(Copy, Clone)]
+enum Port {
+    A = 0,
+    B = 1,
+}
+
+impl Port {
+    /// Drive all pins in `mask` high or low with a single write
+    fn write(self, mask: u32, high: bool) {
+        let bits = if high { mask } else { mask << 16 };
+        unsafe {
+            match self {
+                Port::A => (*GPIOA::ptr()).bsrr.write(|w| w.bits(bits)),
+                Port::B => (*GPIOB::ptr()).bsrr.write(|w| w.bits(bits)),
+            }
+        }
+    }
+
+    /// Input data of all ports, indexed by `Port`
+    fn read_all() -> [u32; 2] {
+        unsafe {
+            [
+                (*GPIOA::ptr()).idr.read().bits(),
+                (*GPIOB::ptr()).idr.read().bits(),
+            ]
+        }
+    }
+}
+
+/// Port and pin mask of each column, must match `ColumnPins`
+const COLUMN_PINS: [(Port, u32); COLUMNS] = [
+    (Port::A, 1 << 5),
+    (Port::A, 1 << 6),
+    (Port::A, 1 << 7),
+    (Port::B, 1 << 0),
+    (Port::B, 1 << 1),
+    (Port::B, 1 << 12),
+    (Port::B, 1 << 13),
+    (Port::B, 1 << 14),
+    (Port::A, 1 << 8),
+    (Port::A, 1 << 9),
+    (Port::A, 1 << 15),
+    (Port::B, 1 << 3),
+    (Port::B, 1 << 4),
+    (Port::B, 1 << 5),
+];
+
+/// Port and pin mask of each row, must match `RowPins`
+const ROW_PINS: [(Port, u32); ROWS] = [
+    (Port::B, 1 << 9),
+    (Port::B, 1 << 8),
+    (Port::B, 1 << 7),
+    (Port::B, 1 << 6),
+    (Port::A, 1 << 0),
+];
+
 type RowPins = (PB9<Input>, PB8<Input>, PB7<Input>, PB6<Input>, PA0<Input>);
 type ColumnPins = (
     PA5<Output>,
@@ -38,86 +110,103 @@ type ColumnPins = (
 /// activation.
 pub type KeyState = [u8; (ROWS * COLUMNS + 2) / 8]; // [u8; 9]
 
+/// A single key going down or up
+#[derive(Copy, Clone)]
+pub struct KeyEvent {
+    /// Position of the key in the scan matrix, see [`keycodes::KeyIndex`]
+    pub index: u8,
+    pub pressed: bool,
+    /// `clock::now_ms()` of the scan that saw the change
+    pub time: u32,
+}
+
+/// Key events in the order they were sampled
+pub type EventQueue = Queue<KeyEvent, U32>;
+
 pub struct KeyMatrix {
-    /// Stores the currently pressed down keys from last sample.
+    /// Stores the currently pressed down keys, as far as they have
+    /// been reported through `events`.
     pub state: KeyState,
-    row_pins: RowPins,
-    column_pins: ColumnPins,
+    /// Changes detected by `sample` that haven't been processed yet.
+    pub events: EventQueue,
+    /// How long the last `sample` took, needs to stay well below
+    /// `SCAN_PERIOD_US`.
+    pub scan_duration_us: u32,
+    /// Scan less often, e.g. while the USB host is suspended
+    pub idle: bool,
+    /// Ticks skipped since the last scan while `idle`
+    skipped: u8,
+    /// Ticks left until each key may change again. Debouncing is eager:
+    /// the first change is reported right away and the bouncing after
+    /// it is ignored.
+    debounce: [u8; ROWS * COLUMNS],
+    // Only held on to so nobody else can reconfigure them, `sample`
+    // accesses the ports directly.
+    _row_pins: RowPins,
+    _column_pins: ColumnPins,
 }
 
 impl KeyMatrix {
     pub fn new(row_pins: RowPins, column_pins: ColumnPins) -> Self {
         Self {
             state: [0; 9],
-            row_pins,
-            column_pins,
+            events: Queue::new(),
+            scan_duration_us: 0,
+            idle: false,
+            skipped: 0,
+            debounce: [0; ROWS * COLUMNS],
+            _row_pins: row_pins,
+            _column_pins: column_pins,
         }
     }
 
-    pub fn sample(&mut self, syst: &SYST) {
-        for column in 0..COLUMNS {
-            self.enable_column(column);
+    /// Scan all keys and queue an event for every key whose state
+    /// differs from `state` and isn't bouncing, in matrix order.
+    pub fn sample(&mut self) {
+        if self.idle && self.skipped < IDLE_SCAN_DIVIDER - 1 {
+            self.skipped += 1;
+            return;
+        }
+        let ticks = self.skipped + 1;
+        self.skipped = 0;
+        for debounce in self.debounce.iter_mut() {
+            *debounce = debounce.saturating_sub(ticks);
+        }
+
+        let start = clock::now_us();
+        let time = clock::now_ms();
+        for (column, &(column_port, column_mask)) in COLUMN_PINS.iter().enumerate() {
+            column_port.write(column_mask, true);
 
             // Busy wait a short while before sampling the keys
             // to let the pins settle
-            let current_tick = syst.cvr.read();
-            let wait_until_tick = current_tick - 100;
-            while syst.cvr.read() > wait_until_tick {}
-
-            self.state
-                .set_bit(column, self.row_pins.0.is_high().unwrap());
-            self.state
-                .set_bit(column + COLUMNS, self.row_pins.1.is_high().unwrap());
-            self.state
-                .set_bit(column + 2 * COLUMNS, self.row_pins.2.is_high().unwrap());
-            self.state
-                .set_bit(column + 3 * COLUMNS, self.row_pins.3.is_high().unwrap());
-            self.state
-                .set_bit(column + 4 * COLUMNS, self.row_pins.4.is_high().unwrap());
-
-            self.disable_column(column);
-        }
-    }
+            clock::delay_us(SETTLE_US);
+
+            let inputs = Port::read_all();
+
+            column_port.write(column_mask, false);
 
-    fn enable_column(&mut self, column: usize) {
-        match column {
-            0 => self.column_pins.0.set_high(),
-            1 => self.column_pins.1.set_high(),
-            2 => self.column_pins.2.set_high(),
-            3 => self.column_pins.3.set_high(),
-            4 => self.column_pins.4.set_high(),
-            5 => self.column_pins.5.set_high(),
-            6 => self.column_pins.6.set_high(),
-            7 => self.column_pins.7.set_high(),
-            8 => self.column_pins.8.set_high(),
-            9 => self.column_pins.9.set_high(),
-            10 => self.column_pins.10.set_high(),
-            11 => self.column_pins.11.set_high(),
-            12 => self.column_pins.12.set_high(),
-            13 => self.column_pins.13.set_high(),
-            _ => Ok(()),
+            for (row, &(row_port, row_mask)) in ROW_PINS.iter().enumerate() {
+                let pressed = inputs[row_port as usize] & row_mask != 0;
+                self.update(column + row * COLUMNS, pressed, time);
+            }
         }
-        .unwrap()
+        self.scan_duration_us = clock::now_us().wrapping_sub(start);
     }
 
-    fn disable_column(&mut self, column: usize) {
-        match column {
-            0 => self.column_pins.0.set_low(),
-            1 => self.column_pins.1.set_low(),
-            2 => self.column_pins.2.set_low(),
-            3 => self.column_pins.3.set_low(),
-            4 => self.column_pins.4.set_low(),
-            5 => self.column_pins.5.set_low(),
-            6 => self.column_pins.6.set_low(),
-            7 => self.column_pins.7.set_low(),
-            8 => self.column_pins.8.set_low(),
-            9 => self.column_pins.9.set_low(),
-            10 => self.column_pins.10.set_low(),
-            11 => self.column_pins.11.set_low(),
-            12 => self.column_pins.12.set_low(),
-            13 => self.column_pins.13.set_low(),
-            _ => Ok(()),
+    fn update(&mut self, index: usize, pressed: bool, time: u32) {
+        if self.state.get_bit(index) != pressed && self.debounce[index] == 0 {
+            let event = KeyEvent {
+                index: index as u8,
+                pressed,
+                time,
+            };
+            // If the queue is full the state is left alone, so the
+            // change gets picked up again by the next scan.
+            if self.events.enqueue(event).is_ok() {
+                self.state.set_bit(index, pressed);
+                self.debounce[index] = DEBOUNCE_TICKS;
+            }
         }
-        .unwrap()
     }
 }
@@ -1,7 +1,9 @@
 use crate::action::Action;
 use crate::action::Action::*;
 use crate::keycodes::KeyCode::*;
+use crate::keycodes::{consumer, SystemControl};
 use crate::keymatrix::{COLUMNS, ROWS};
+use crate::mousekeys::MouseAction;
 
 /*
   ,-----------------------------------------------------------------------------.
@@ -25,6 +27,9 @@ pub const LAYER_FN: u8 = 1;
 pub const LAYER_FN2: u8 = 2;
 pub const LAYER_BT: u8 = 3;
 
+/// For the serial console, in the order of `LAYERS`
+pub const LAYER_NAMES: [&str; 4] = ["base", "fn", "fn2", "bt"];
+
 // activate by indexing into LAYERS
 const FN_M: Action = LayerMomentary(LAYER_FN);
 const FN2_M: Action = LayerMomentary(LAYER_FN2);
@@ -33,6 +38,25 @@ const LED_NT: Action = LedNextTheme;
 const LED_NB: Action = LedNextBrightness;
 const LED_NAS: Action = LedNextAnimationSpeed;
 const BT_ON: Action = LayerOn(LAYER_BT);
+const PREV: Action = Consumer(consumer::PREVIOUS_TRACK);
+const PLAY: Action = Consumer(consumer::PLAY_PAUSE);
+const NEXT: Action = Consumer(consumer::NEXT_TRACK);
+const MUTE: Action = Consumer(consumer::MUTE);
+const VOL_DN: Action = Consumer(consumer::VOLUME_DOWN);
+const VOL_UP: Action = Consumer(consumer::VOLUME_UP);
+const BRI_DN: Action = Consumer(consumer::BRIGHTNESS_DOWN);
+const BRI_UP: Action = Consumer(consumer::BRIGHTNESS_UP);
+const POWER: Action = System(SystemControl::PowerDown);
+const SLEEP: Action = System(SystemControl::Sleep);
+const MS_UP: Action = Mouse(MouseAction::Up);
+const MS_DN: Action = Mouse(MouseAction::Down);
+const MS_LT: Action = Mouse(MouseAction::Left);
+const MS_RT: Action = Mouse(MouseAction::Right);
+const MS_WU: Action = Mouse(MouseAction::WheelUp);
+const MS_WD: Action = Mouse(MouseAction::WheelDown);
+const MS_B1: Action = Mouse(MouseAction::Button1);
+const MS_B2: Action = Mouse(MouseAction::Button2);
+const MS_B3: Action = Mouse(MouseAction::Button3);
 
 pub const BASE: Layout = layout![
     Escape   N1     N2   N3 N4 N5    N6 N7 N8    N9  N0     Minus    Equal     BSpace
@@ -45,17 +69,17 @@ pub const BASE: Layout = layout![
 pub const FN: Layout = layout![
   Grave F1   F2   F3    F4        F5      F6     F7     F8   F9         F10    F11    F12 __
   __    __   Up   __    LedToggle LED_NAS LED_NB LED_NT Up   Scrolllock Pause  Home   End PScreen
-  __    Left Down Right __        __      __     Left   Down Right      PgUp   PgDown No  __
-  __    __   __   __    __        BT_ON   __     __     __   Insert     Delete No     No  __
+  __    Left Down Right BRI_DN    BRI_UP  __     Left   Down Right      PgUp   PgDown No  __
+  __    PREV PLAY NEXT  MUTE      BT_ON   VOL_DN VOL_UP __   Insert     Delete No     No  __
   __    __   __   No    No        Reset   No     No     No   No         __     __     __  __
 ];
 
 pub const FN2: Layout = layout![
-    LedOff LedOn LED_NT LED_NAS LED_NB __ __ __ __ __ __ __ __ __
-    __     __    __     __      __     __ __ __ __ __ __ __ __ __
-    __     __    __     __      __     __ __ __ __ __ __ __ No __
-    __     __    __     __      __     __ __ __ __ __ __ __ __ __
-    __     __    __     No      No     __ No No No No __ __ __ __
+    LedOff LedOn LED_NT LED_NAS LED_NB __         __         __ __ __ __ __ POWER SLEEP
+    __     MS_WU MS_B1  MS_UP   MS_B2  MatrixTest __         __ __ __ __ __ __    __
+    __     MS_WD MS_LT  MS_DN   MS_RT  MS_B3      __         __ __ __ __ __ No    __
+    __     __    __     __      __     __         NkroToggle __ __ __ __ __ __    __
+    __     __    __     No      No     __         No         No No No __ __ __    __
 ];
 
 #[rustfmt::skip]
@@ -1,6 +1,7 @@
 use crate::bluetooth::BluetoothMode;
+use crate::hidreport::LED_CAPS_LOCK;
 use crate::keycodes::KeyIndex;
-use crate::keymatrix::KeyState;
+use crate::keymatrix::{KeyState, COLUMNS};
 use crate::protocol::{LedOp, Message, MsgType};
 use crate::serial::led_usart::LedUsart;
 use crate::serial::{Serial, Transfer};
@@ -10,19 +11,56 @@ use core::marker::Unsize;
 use embedded_hal::digital::v2::OutputPin;
 use hal::gpio::gpioc::PC15;
 use hal::gpio::{Input, Output};
-use stm32l1::stm32l151::SYST;
+use heapless::consts::U14;
+use heapless::Vec;
 
+/// How long to keep the LED controller powered off to reset it
+pub const RESET_MS: u32 = 5;
+/// How long the LED controller needs after power on before it
+/// accepts commands
+pub const BOOT_MS: u32 = 5;
+
+const CAPS_LOCK_KEY: u8 = KeyIndex::Capslock as u8;
+
+#[derive(Copy, Clone, PartialEq)]
 pub enum LedMode {
-    _Off,
+    Off,
     On,
     Flash,
 }
 
+/// Colour of a single key, see `Led::set_key_lights`
+#[derive(Copy, Clone, PartialEq)]
+pub struct KeyLight {
+    /// Position in the scan matrix, see [`keycodes::KeyIndex`]
+    pub key: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub mode: LedMode,
+}
+
+/// Up to one matrix row worth of `KeyLight`s, which comfortably fits
+/// into a single message to the LED controller
+pub type KeyLights = Vec<KeyLight, U14>;
+
 pub struct Led<BUFFER: 'static + Unsize<[u8]>> {
     pub serial: Serial<LedUsart, BUFFER>,
     pub rx_transfer: Option<Transfer<BUFFER>>,
     pub pc15: PC15<Output>,
     pub state: bool,
+    /// Theme as last acknowledged by the LED controller
+    pub theme: Option<u8>,
+    pub brightness: Option<u8>,
+    pub animation_speed: Option<u8>,
+    /// Num/Caps/Scroll Lock state set by the USB host, see
+    /// `hidreport::LED_CAPS_LOCK` and friends
+    pub host_leds: u8,
+    /// Individual key lights are shown instead of the theme
+    key_lights: bool,
+    /// The Caps Lock light has to be sent again once the LED
+    /// controller is done with the current message
+    caps_lock_pending: bool,
 }
 
 impl<BUFFER> Led<BUFFER>
@@ -40,6 +78,12 @@ where
             rx_transfer: Some(rx_transfer),
             pc15: pc15.into_output().pull_up(),
             state: false,
+            theme: None,
+            brightness: None,
+            animation_speed: None,
+            host_leds: 0,
+            key_lights: false,
+            caps_lock_pending: false,
         }
     }
 
@@ -54,18 +98,18 @@ where
         Ok(())
     }
 
-    pub fn poke(&mut self, syst: &SYST) -> nb::Result<(), Infallible> {
-        self.off()?;
-
-        // TODO: introduce proper delay()
-        let wait_until_tick = 0;
-        while syst.cvr.read() > wait_until_tick {}
-
-        self.on()?;
-
-        while syst.cvr.read() > wait_until_tick {}
+    /// Turn the lights off while the USB host sleeps, without
+    /// forgetting whether they were on
+    pub fn suspend(&mut self) -> nb::Result<(), Infallible> {
+        self.lights_off()
+    }
 
-        Ok(())
+    pub fn resume(&mut self) -> nb::Result<(), Infallible> {
+        if self.state {
+            self.theme_mode()
+        } else {
+            Ok(())
+        }
     }
 
     pub fn toggle(&mut self) -> nb::Result<(), Infallible> {
@@ -73,14 +117,24 @@ where
         if self.state {
             self.theme_mode()
         } else {
-            self.set_theme(15)
+            self.lights_off()
         }
     }
 
+    fn lights_off(&mut self) -> nb::Result<(), Infallible> {
+        self.serial
+            .send(MsgType::Led, LedOp::ThemeMode as u8, &[15])?;
+        self.key_lights = false;
+        self.caps_lock_pending = false;
+        Ok(())
+    }
+
     // next_* cycles through themes/brightness/speed
     pub fn next_theme(&mut self) -> nb::Result<(), Infallible> {
         self.serial
-            .send(MsgType::Led, LedOp::ConfigCmd as u8, &[1, 0, 0])
+            .send(MsgType::Led, LedOp::ConfigCmd as u8, &[1, 0, 0])?;
+        self.theme_shown();
+        Ok(())
     }
 
     pub fn next_brightness(&mut self) -> nb::Result<(), Infallible> {
@@ -95,7 +149,9 @@ where
 
     pub fn set_theme(&mut self, theme: u8) -> nb::Result<(), Infallible> {
         self.serial
-            .send(MsgType::Led, LedOp::ThemeMode as u8, &[theme])
+            .send(MsgType::Led, LedOp::ThemeMode as u8, &[theme])?;
+        self.theme_shown();
+        Ok(())
     }
 
     pub fn send_keys(&mut self, state: &KeyState) -> nb::Result<(), Infallible> {
@@ -111,14 +167,105 @@ where
         self.serial.send(MsgType::Led, LedOp::GetThemeId as u8, &[])
     }
 
+    /// Show individual key lights, `payload` as for
+    /// `LedOp::SetIndividualKeys`. The Caps Lock light goes on top.
     pub fn set_keys(&mut self, payload: &[u8]) -> nb::Result<(), Infallible> {
         self.serial
-            .send(MsgType::Led, LedOp::SetIndividualKeys as u8, payload)
+            .send(MsgType::Led, LedOp::SetIndividualKeys as u8, payload)?;
+        self.key_lights = true;
+        self.caps_lock_pending = self.host_leds & LED_CAPS_LOCK != 0;
+        Ok(())
+    }
+
+    /// Like `set_keys`, but the Capslock key is left alone unless it is
+    /// one of `lights`
+    pub fn set_key_lights(&mut self, lights: &[KeyLight]) -> nb::Result<(), Infallible> {
+        let caps_lock_pending = self.caps_lock_pending;
+        self.send_key_lights(lights)?;
+        self.key_lights = true;
+        self.caps_lock_pending = if lights.iter().any(|light| light.key == CAPS_LOCK_KEY) {
+            false
+        } else {
+            caps_lock_pending || self.host_leds & LED_CAPS_LOCK != 0
+        };
+        Ok(())
+    }
+
+    fn send_key_lights(&mut self, lights: &[KeyLight]) -> nb::Result<(), Infallible> {
+        let mut payload = [0; 2 + 5 * COLUMNS];
+        let count = lights.len().min(COLUMNS);
+        payload[0] = 0xca;
+        payload[1] = count as u8;
+        for (bytes, light) in payload[2..].chunks_mut(5).zip(&lights[..count]) {
+            bytes.copy_from_slice(&[
+                light.key,
+                light.red,
+                light.green,
+                light.blue,
+                light.mode as u8,
+            ]);
+        }
+        self.serial.send(
+            MsgType::Led,
+            LedOp::SetIndividualKeys as u8,
+            &payload[..2 + 5 * count],
+        )
+    }
+
+    /// Light up the Capslock key white while the host has Caps Lock
+    /// enabled. Num and Scroll Lock have no key of their own.
+    pub fn set_host_leds(&mut self, leds: u8) -> nb::Result<(), Infallible> {
+        let changed = (self.host_leds ^ leds) & LED_CAPS_LOCK != 0;
+        self.host_leds = leds;
+        if !changed {
+            Ok(())
+        } else if leds & LED_CAPS_LOCK == 0 && self.state && !self.key_lights {
+            // Hand the key back to the theme
+            self.theme_mode()
+        } else {
+            self.caps_lock_pending = true;
+            self.send_caps_lock()
+        }
+    }
+
+    /// Send the Caps Lock light if it's pending: white while Caps Lock
+    /// is on, dark otherwise
+    fn send_caps_lock(&mut self) -> nb::Result<(), Infallible> {
+        if !self.caps_lock_pending {
+            return Ok(());
+        }
+        let on = self.host_leds & LED_CAPS_LOCK != 0;
+        let level = if on { 0xff } else { 0 };
+        self.send_key_lights(&[KeyLight {
+            key: CAPS_LOCK_KEY,
+            red: level,
+            green: level,
+            blue: level,
+            mode: if on { LedMode::On } else { LedMode::Off },
+        }])?;
+        self.caps_lock_pending = false;
+        Ok(())
+    }
+
+    /// A theme replaced all key lights, including the Caps Lock one
+    fn theme_shown(&mut self) {
+        self.key_lights = false;
+        self.caps_lock_pending = self.host_leds & LED_CAPS_LOCK != 0;
+    }
+
+    /// The USART is done sending, so a pending Caps Lock light can go
+    /// out after the message that replaced it
+    pub fn tx_interrupt(&mut self) {
+        self.serial.tx_interrupt();
+        self.send_caps_lock().ok();
     }
 
     pub fn theme_mode(&mut self) -> nb::Result<(), Infallible> {
         self.state = true;
-        self.serial.send(MsgType::Led, LedOp::ThemeMode as u8, &[])
+        self.serial
+            .send(MsgType::Led, LedOp::ThemeMode as u8, &[])?;
+        self.theme_shown();
+        Ok(())
     }
 
     pub fn bluetooth_mode(
@@ -217,11 +364,17 @@ where
                 match LedOp::from(message.operation) {
                     LedOp::AckThemeMode => {
                         // data: [theme id]
-                        //crate::heprintln!("Led AckThemeMode {:?}", message.data).ok();
+                        if let [theme] = *message.data {
+                            self.theme = Some(theme);
+                        }
                     }
                     LedOp::AckConfigCmd => {
                         // data: [theme id, brightness, animation speed]
-                        //crate::heprintln!("Led AckConfigCmd {:?}", message.data).ok();
+                        if let [theme, brightness, animation_speed] = *message.data {
+                            self.theme = Some(theme);
+                            self.brightness = Some(brightness);
+                            self.animation_speed = Some(animation_speed);
+                        }
                     }
                     LedOp::AckSetIndividualKeys => {
                         // data: [202]
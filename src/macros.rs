@@ -0,0 +1,205 @@
+use crate::eeprom::{self, MACROS_SIZE};
+use crate::hidreport::HidReport;
+use crate::keycodes::KeyCode;
+use bit_field::BitField;
+
+/// Number of macros in the buffer, for `Action::Macro`
+pub const MACRO_COUNT: u8 = 16;
+
+// The buffer holds MACRO_COUNT NUL terminated macros in the format of
+// QMK's `send_string`, as edited by VIA: plain ASCII characters are
+// typed out, SS_QMK_PREFIX starts a code with a key or a delay.
+const SS_QMK_PREFIX: u8 = 1;
+const SS_TAP_CODE: u8 = 1;
+const SS_DOWN_CODE: u8 = 2;
+const SS_UP_CODE: u8 = 3;
+/// Followed by the decimal delay in ms and a '|'
+const SS_DELAY_CODE: u8 = 4;
+
+/// Types out a macro one report per `poll`, while the keys held on the
+/// keyboard are ignored
+pub struct MacroPlayer {
+    /// Offset of the next byte in the macro buffer, `None` when idle
+    next: Option<usize>,
+    modifiers: u8,
+    keys: [u8; 6],
+    /// Keys to release in the next step, after typing a character
+    tap: Option<(KeyCode, bool)>,
+    /// Don't continue before this `clock::now_ms()`
+    wait_until: u32,
+}
+
+impl MacroPlayer {
+    pub const fn new() -> MacroPlayer {
+        MacroPlayer {
+            next: None,
+            modifiers: 0,
+            keys: [0; 6],
+            tap: None,
+            wait_until: 0,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Start macro number `index`, replacing the one playing
+    pub fn start(&mut self, index: u8, now: u32) {
+        let mut offset = 0;
+        for _ in 0..index {
+            while offset < MACROS_SIZE && eeprom::macros_byte(offset) != 0 {
+                offset += 1;
+            }
+            offset += 1;
+        }
+        *self = MacroPlayer::new();
+        self.next = Some(offset);
+        self.wait_until = now;
+    }
+
+    /// Play the next step, returns the report to send if there is one
+    pub fn poll(&mut self, now: u32) -> Option<HidReport> {
+        let next = self.next?;
+        if (now.wrapping_sub(self.wait_until) as i32) < 0 {
+            return None;
+        }
+        if let Some((key, shift)) = self.tap.take() {
+            self.release(key);
+            if shift {
+                self.release(KeyCode::LShift);
+            }
+            return Some(self.report());
+        }
+
+        match eeprom::macros_byte(next) {
+            0 => {
+                // Done, let go of everything still held
+                *self = MacroPlayer::new();
+            }
+            SS_QMK_PREFIX => {
+                let key = KeyCode::from_u8(eeprom::macros_byte(next + 2));
+                self.next = Some(next + 3);
+                match (eeprom::macros_byte(next + 1), key) {
+                    (SS_TAP_CODE, Some(key)) => {
+                        self.press(key);
+                        self.tap = Some((key, false));
+                    }
+                    (SS_DOWN_CODE, Some(key)) => self.press(key),
+                    (SS_UP_CODE, Some(key)) => self.release(key),
+                    (SS_DELAY_CODE, _) => {
+                        self.delay(next + 2, now);
+                        return None;
+                    }
+                    _ => return None,
+                }
+            }
+            character => {
+                self.next = Some(next + 1);
+                let (key, shift) = ascii_key(character)?;
+                if shift {
+                    self.press(KeyCode::LShift);
+                }
+                self.press(key);
+                self.tap = Some((key, shift));
+            }
+        }
+        Some(self.report())
+    }
+
+    /// Parse the delay starting at `offset` and wait that long
+    fn delay(&mut self, mut offset: usize, now: u32) {
+        let mut ms: u32 = 0;
+        loop {
+            match eeprom::macros_byte(offset) {
+                digit @ b'0'..=b'9' => {
+                    ms = ms
+                        .saturating_mul(10)
+                        .saturating_add(u32::from(digit - b'0'))
+                }
+                b'|' => {
+                    offset += 1;
+                    break;
+                }
+                // Unterminated, the NUL ends the macro on the next step
+                _ => break,
+            }
+            offset += 1;
+        }
+        self.next = Some(offset);
+        self.wait_until = now.wrapping_add(ms);
+    }
+
+    fn press(&mut self, key: KeyCode) {
+        if key.is_modifier() {
+            self.modifiers
+                .set_bit(key as usize - KeyCode::LCtrl as usize, true);
+        } else if key.is_normal_key() && !self.keys.contains(&(key as u8)) {
+            if let Some(slot) = self.keys.iter_mut().find(|slot| **slot == 0) {
+                *slot = key as u8;
+            }
+        }
+    }
+
+    fn release(&mut self, key: KeyCode) {
+        if key.is_modifier() {
+            self.modifiers
+                .set_bit(key as usize - KeyCode::LCtrl as usize, false);
+        } else if let Some(slot) = self.keys.iter_mut().find(|slot| **slot == key as u8) {
+            *slot = 0;
+        }
+    }
+
+    fn report(&self) -> HidReport {
+        let mut report = HidReport::default();
+        report.modifiers = self.modifiers;
+        report.keys = self.keys;
+        report
+    }
+}
+
+/// Characters other than letters and digits, with their key on a US
+/// layout and whether it needs shift
+#[rustfmt::skip]
+const SYMBOLS: [(u8, KeyCode, bool); 38] = [
+    (b'\n', KeyCode::Enter, false), (0x1B, KeyCode::Escape, false),
+    (0x08, KeyCode::BSpace, false), (b'\t', KeyCode::Tab, false),
+    (b' ', KeyCode::Space, false),
+    (b'-', KeyCode::Minus, false), (b'_', KeyCode::Minus, true),
+    (b'=', KeyCode::Equal, false), (b'+', KeyCode::Equal, true),
+    (b'[', KeyCode::LBracket, false), (b'{', KeyCode::LBracket, true),
+    (b']', KeyCode::RBracket, false), (b'}', KeyCode::RBracket, true),
+    (b'\\', KeyCode::BSlash, false), (b'|', KeyCode::BSlash, true),
+    (b';', KeyCode::SColon, false), (b':', KeyCode::SColon, true),
+    (b'\'', KeyCode::Quote, false), (b'"', KeyCode::Quote, true),
+    (b'`', KeyCode::Grave, false), (b'~', KeyCode::Grave, true),
+    (b',', KeyCode::Comma, false), (b'<', KeyCode::Comma, true),
+    (b'.', KeyCode::Dot, false), (b'>', KeyCode::Dot, true),
+    (b'/', KeyCode::Slash, false), (b'?', KeyCode::Slash, true),
+    (b'!', KeyCode::N1, true), (b'@', KeyCode::N2, true),
+    (b'#', KeyCode::N3, true), (b'$', KeyCode::N4, true),
+    (b'%', KeyCode::N5, true), (b'^', KeyCode::N6, true),
+    (b'&', KeyCode::N7, true), (b'*', KeyCode::N8, true),
+    (b'(', KeyCode::N9, true), (b')', KeyCode::N0, true),
+    (b'0', KeyCode::N0, false),
+];
+
+/// The key typing `character` on a US layout, and whether it needs
+/// shift
+fn ascii_key(character: u8) -> Option<(KeyCode, bool)> {
+    match character {
+        b'a'..=b'z' | b'A'..=b'Z' => {
+            let letter = character.to_ascii_lowercase() - b'a';
+            let key = KeyCode::from_u8(KeyCode::A as u8 + letter)?;
+            Some((key, character.is_ascii_uppercase()))
+        }
+        b'1'..=b'9' => {
+            let key = KeyCode::from_u8(KeyCode::N1 as u8 + character - b'1')?;
+            Some((key, false))
+        }
+        _ => SYMBOLS
+            .iter()
+            .find(|(symbol, _, _)| *symbol == character)
+            .map(|&(_, key, shift)| (key, shift)),
+    }
+}
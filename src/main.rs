@@ -1,12 +1,15 @@
+#![feature(const_if_match, const_loop)]
 #![feature(unsize)]
-#![no_main]
-#![no_std]
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+// Host tests only exercise the parts that don't touch the hardware
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
-#[cfg(not(feature = "use_semihosting"))]
+#[cfg(all(not(test), not(feature = "use_semihosting")))]
 extern crate panic_abort;
-#[cfg(feature = "use_semihosting")]
+#[cfg(all(not(test), feature = "use_semihosting"))]
 extern crate panic_semihosting;
-#[cfg(feature = "use_semihosting")]
+#[cfg(all(not(test), feature = "use_semihosting"))]
 use cortex_m_semihosting::heprintln;
 
 mod debug;
@@ -14,30 +17,62 @@ mod debug;
 #[macro_use]
 mod action;
 mod bluetooth;
+mod bootloader;
 mod clock;
+mod command;
+mod console;
+mod eeprom;
 mod hidreport;
 mod keyboard;
 mod keycodes;
 mod keymatrix;
 mod layout;
 mod led;
+mod macros;
+mod matrixtest;
+mod mousekeys;
 mod protocol;
 mod serial;
+mod uid;
 mod usb;
+mod via;
 
+use core::{iter, mem};
 use hal::dma::DmaExt;
 use hal::gpio::GpioExt;
-use rtfm::app;
+use rtfm::{app, Mutex};
 
+use crate::action::Action;
 use crate::bluetooth::Bluetooth;
-use crate::keyboard::Keyboard;
-use crate::keymatrix::KeyMatrix;
-use crate::led::Led;
+use crate::clock::Timeouts;
+use crate::debug::{UnwrapLog, LATENCY};
+use crate::eeprom::Eeprom;
+use crate::hidreport::{HidReport, MouseReport, NkroReport};
+use crate::keyboard::{EventProcessor, Keyboard, KeyboardOutput, QueueFull};
+use crate::keymatrix::{KeyMatrix, KeyState};
+use crate::led::{KeyLights, Led};
 use crate::serial::bluetooth_usart::BluetoothUsart;
 use crate::serial::led_usart::LedUsart;
 use crate::serial::Serial;
+use crate::usb::cdc::ConsoleLine;
+use crate::usb::rawhid::RawReport;
 use crate::usb::Usb;
 
+// The firmware is split into stages connected by the bounded task
+// queues of RTFM, from highest to lowest priority:
+//
+// 3. `SysTick` scans the key matrix
+// 2. `process_keys` turns key events into reports and actions,
+//    the `usb_*` tasks and `USB_LP` talk to the USB host,
+//    `raw_hid_request` answers commands and VIA requests from it
+// 1. `bluetooth_report`, `led_keys`, `peripheral_action`,
+//    `bluetooth_mode`, `key_lights`, `usb_power` and `host_leds` feed
+//    the LED and Bluetooth UARTs, `console_command` answers the USB
+//    serial console
+//
+// so a slow UART can never hold up scanning or USB. See
+// `debug::LATENCY` for the measured worst case of each stage.
+#[cfg(not(test))]
 #[app(device = stm32l1::stm32l151)]
 const APP: () = {
     static mut KEYBOARD: Keyboard = Keyboard::new();
@@ -48,17 +83,31 @@ const APP: () = {
     static mut BLUETOOTH: Bluetooth<[u8; 0x80]> = ();
     static mut LED: Led<[u8; 0x80]> = ();
     static mut KEY_MATRIX: KeyMatrix = ();
-    static mut SYST: stm32l1::stm32l151::SYST = ();
+    static mut TIMEOUTS: Timeouts<Timeout> = ();
     static mut EXTI: stm32l1::stm32l151::EXTI = ();
     static mut USB: Usb = ();
+    static mut EEPROM: Eeprom = ();
 
-    #[init(resources = [BLUETOOTH_BUFFERS, LED_BUFFERS])]
+    #[init(resources = [BLUETOOTH_BUFFERS, LED_BUFFERS, KEYBOARD])]
     fn init() -> init::LateResources {
         // re-locate vector table to 0x80004000 because bootloader uses 0x80000000
         unsafe { core.SCB.vtor.write(0x4000) };
 
+        // cycle counter for debug::LATENCY
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+
         clock::init_clock(&device);
-        clock::enable_tick(&mut core.SYST, 100_000);
+        clock::enable_tick(&mut core.SYST, keymatrix::SCAN_PERIOD_US);
+        let mut timeouts = Timeouts::new();
+
+        resources.KEYBOARD.load_keymap();
+        if bootloader::take_dfu_request() {
+            crate::heprintln!(
+                "the bootloader ignored the DFU request, hold Escape while resetting"
+            )
+            .ok();
+        }
 
         let dma = device.DMA1.split();
         let gpioa = device.GPIOA.split();
@@ -104,8 +153,11 @@ const APP: () = {
         let (led_send_buffer, led_receive_buffer) = resources.LED_BUFFERS.split_at_mut(1);
         let led_serial = Serial::new(led_usart, &mut led_send_buffer[0]);
         let mut led = Led::new(led_serial, &mut led_receive_buffer[0], gpioc.pc15);
-        led.poke(&core.SYST).unwrap();
-        led.theme_mode().unwrap();
+        // Power cycle the LED controller, see `Timeout::LedPowerOn`
+        led.off().unwrap();
+        timeouts
+            .schedule_in(led::RESET_MS, Timeout::LedPowerOn)
+            .ok();
 
         let bluetooth_usart = BluetoothUsart::new(
             device.USART2,
@@ -126,21 +178,212 @@ const APP: () = {
             BLUETOOTH: bluetooth,
             KEY_MATRIX: key_matrix,
             LED: led,
-            SYST: core.SYST,
+            TIMEOUTS: timeouts,
             EXTI: device.EXTI,
             USB: usb,
+            EEPROM: Eeprom::new(device.FLASH),
         }
     }
 
-    #[exception(resources = [BLUETOOTH, LED, KEY_MATRIX, SYST, KEYBOARD, USB])]
+    #[exception(
+        priority = 3,
+        resources = [KEY_MATRIX, TIMEOUTS],
+        spawn = [process_keys, on_timeout]
+    )]
     fn SysTick() {
-        resources.KEY_MATRIX.sample(&resources.SYST);
-        resources.KEYBOARD.process(
-            &resources.KEY_MATRIX.state,
-            &mut resources.BLUETOOTH,
-            &mut resources.LED,
-            &mut resources.USB,
-        );
+        clock::tick();
+
+        LATENCY.scan.measure(|| {
+            resources.KEY_MATRIX.sample();
+            // Fails if processing is still pending, in which case it
+            // will pick up the new events as well.
+            spawn.process_keys().ok();
+        });
+
+        let now = clock::now_ms();
+        while let Some(timeout) = resources.TIMEOUTS.expired(now) {
+            if let Err(timeout) = spawn.on_timeout(timeout) {
+                // Queue is full, try again on the next tick
+                resources.TIMEOUTS.schedule_at(now, timeout).ok();
+                break;
+            }
+        }
+    }
+
+    #[task(capacity = 4, resources = [LED, TIMEOUTS, USB], spawn = [usb_power])]
+    fn on_timeout(timeout: Timeout) {
+        match timeout {
+            Timeout::UsbWakeupDone => {
+                resources.USB.lock(|usb| usb.end_remote_wakeup());
+                spawn.usb_power(false).ok();
+            }
+            Timeout::LedPowerOn => {
+                resources.LED.on().log_error();
+                resources
+                    .TIMEOUTS
+                    .lock(|timeouts| timeouts.schedule_in(led::BOOT_MS, Timeout::LedReady))
+                    .ok();
+            }
+            Timeout::LedReady => resources.LED.theme_mode().log_error(),
+        }
+    }
+
+    #[task(
+        priority = 2,
+        resources = [KEY_MATRIX, KEYBOARD],
+        spawn = [
+            usb_report,
+            usb_consumer_report,
+            usb_system_report,
+            usb_mouse_report,
+            usb_wakeup,
+            bluetooth_report,
+            led_keys,
+            peripheral_action,
+            bluetooth_mode,
+            key_lights
+        ]
+    )]
+    fn process_keys() {
+        let mut output = spawn;
+        LATENCY.process.measure(|| {
+            let key_matrix = &mut resources.KEY_MATRIX;
+            let events = iter::from_fn(|| key_matrix.lock(|matrix| matrix.events.dequeue()));
+            resources
+                .KEYBOARD
+                .process(events, clock::now_ms(), &mut output);
+        })
+    }
+
+    #[task(priority = 2, capacity = 4, resources = [USB])]
+    fn usb_report(boot_report: HidReport, report: NkroReport) {
+        LATENCY
+            .usb
+            .measure(|| resources.USB.update_report(&boot_report, &report))
+    }
+
+    #[task(priority = 2, capacity = 4, resources = [USB])]
+    fn usb_consumer_report(usage: u16) {
+        resources.USB.update_consumer_report(usage)
+    }
+
+    #[task(priority = 2, capacity = 4, resources = [USB])]
+    fn usb_system_report(usage: u8) {
+        resources.USB.update_system_report(usage)
+    }
+
+    #[task(priority = 2, capacity = 4, resources = [USB])]
+    fn usb_mouse_report(report: MouseReport) {
+        resources.USB.send_mouse_report(&report)
+    }
+
+    #[task(priority = 2, resources = [USB, TIMEOUTS])]
+    fn usb_wakeup() {
+        if resources.USB.remote_wakeup() {
+            resources
+                .TIMEOUTS
+                .lock(|timeouts| {
+                    timeouts.schedule_in(usb::REMOTE_WAKEUP_MS, Timeout::UsbWakeupDone)
+                })
+                .ok();
+        }
+    }
+
+    #[task(priority = 2, resources = [KEYBOARD, USB, EEPROM], spawn = [peripheral_action])]
+    fn raw_hid_request(request: RawReport) {
+        let keyboard = resources.KEYBOARD;
+        let eeprom = resources.EEPROM;
+        let response = if request[0] >= command::FIRST_COMMAND {
+            command::execute(&request, keyboard, eeprom, |action| {
+                spawn.peripheral_action(action).ok();
+            })
+        } else {
+            via::execute(&request, keyboard, eeprom)
+        };
+        resources.USB.send_raw_hid_response(&response);
+    }
+
+    // The USB host suspended or resumed the bus
+    #[task(capacity = 2, resources = [KEYBOARD, KEY_MATRIX, LED])]
+    fn usb_power(suspended: bool) {
+        // Typing over Bluetooth goes on while the USB host sleeps, so
+        // only save power if the keys go to USB
+        let power_save = resources.KEYBOARD.lock(|keyboard| {
+            keyboard.usb_suspended = suspended;
+            suspended && keyboard.send_usb_report
+        });
+        let was_saving = resources
+            .KEY_MATRIX
+            .lock(|matrix| mem::replace(&mut matrix.idle, power_save));
+        if power_save && !was_saving {
+            resources.LED.suspend().log_error();
+        } else if !power_save && was_saving {
+            resources.LED.resume().log_error();
+        }
+    }
+
+    #[task(capacity = 2, resources = [LED])]
+    fn host_leds(leds: u8) {
+        resources.LED.set_host_leds(leds).log_error();
+    }
+
+    #[task(resources = [KEYBOARD, KEY_MATRIX, BLUETOOTH, LED, USB])]
+    fn console_command(line: ConsoleLine) {
+        let led = &resources.LED;
+        let status = console::Status {
+            layers: resources.KEYBOARD.lock(|keyboard| keyboard.active_layers()),
+            bluetooth: resources.BLUETOOTH.status(),
+            led_on: led.state,
+            led_theme: led.theme,
+            led_brightness: led.brightness,
+            led_animation_speed: led.animation_speed,
+            scan_duration_us: resources.KEY_MATRIX.lock(|matrix| matrix.scan_duration_us),
+        };
+        let mut output = console::Output::default();
+        let reply = console::execute(&line, &status, &mut output);
+        resources.USB.lock(|usb| {
+            usb.console_write(&output.bytes);
+            if let Ok(console::Reply::Log) = reply {
+                usb.console_log(console::PROMPT.as_bytes());
+            }
+        });
+    }
+
+    #[task(capacity = 4, resources = [BLUETOOTH])]
+    fn bluetooth_report(report: HidReport) {
+        LATENCY
+            .bluetooth
+            .measure(|| resources.BLUETOOTH.send_report(&report))
+    }
+
+    #[task(capacity = 2, resources = [LED])]
+    fn led_keys(state: KeyState) {
+        LATENCY
+            .led
+            .measure(|| resources.LED.send_keys(&state).log_error())
+    }
+
+    #[task(capacity = 8, resources = [BLUETOOTH, LED])]
+    fn peripheral_action(action: Action) {
+        resources.LED.process(&action, true, true);
+        resources.BLUETOOTH.process(&action, true, true);
+    }
+
+    #[task(capacity = 2, resources = [BLUETOOTH, LED])]
+    fn bluetooth_mode(enabled: bool, send_usb_report: bool) {
+        if enabled {
+            resources
+                .BLUETOOTH
+                .update_led(&mut resources.LED, send_usb_report)
+                .log_error();
+        } else {
+            resources.LED.theme_mode().log_error();
+        }
+    }
+
+    #[task(capacity = 2, resources = [LED])]
+    fn key_lights(lights: KeyLights) {
+        resources.LED.set_key_lights(&lights).log_error();
     }
 
     #[idle]
@@ -150,31 +393,52 @@ const APP: () = {
         }
     }
 
-    #[interrupt(priority = 1, resources = [USB])]
+    #[interrupt(
+        priority = 2,
+        resources = [USB],
+        spawn = [usb_power, host_leds, console_command, raw_hid_request]
+    )]
     fn USB_LP() {
-        resources.USB.interrupt()
+        let usb = resources.USB;
+        let was_suspended = usb.is_suspended();
+        let leds = usb.host_leds();
+        usb.interrupt();
+        if usb.is_suspended() != was_suspended {
+            spawn.usb_power(usb.is_suspended()).ok();
+        }
+        if usb.host_leds() != leds {
+            spawn.host_leds(usb.host_leds()).ok();
+        }
+        if let Some(line) = usb.take_console_line() {
+            spawn.console_command(line).ok();
+        }
+        if let Some(request) = usb.take_raw_hid_request() {
+            spawn.raw_hid_request(request).ok();
+        }
     }
 
     #[interrupt(binds = DMA1_CHANNEL2, resources = [LED])]
     fn led_tx() {
-        resources.LED.serial.tx_interrupt()
+        resources.LED.tx_interrupt()
     }
 
-    #[interrupt(binds = DMA1_CHANNEL3, resources = [LED, KEYBOARD])]
+    #[interrupt(binds = DMA1_CHANNEL3, resources = [LED])]
     fn led_rx() {
         resources.LED.poll()
     }
 
-    #[interrupt(binds = DMA1_CHANNEL6, resources = [BLUETOOTH, KEY_MATRIX, LED, KEYBOARD])]
+    #[interrupt(binds = DMA1_CHANNEL6, resources = [BLUETOOTH, LED, KEYBOARD])]
     fn bluetooth_rx() {
+        let bluetooth = resources.BLUETOOTH;
+        let led = resources.LED;
         resources
-            .BLUETOOTH
-            .poll(&mut resources.LED, &mut resources.KEYBOARD)
+            .KEYBOARD
+            .lock(|keyboard| bluetooth.poll(led, keyboard))
     }
 
     #[interrupt(binds = DMA1_CHANNEL7, resources = [BLUETOOTH])]
     fn bluetooth_tx() {
-        resources.BLUETOOTH.serial.tx_interrupt()
+        resources.BLUETOOTH.tx_interrupt()
     }
 
     #[interrupt(resources = [EXTI])]
@@ -205,4 +469,81 @@ const APP: () = {
     fn EXTI4() {
         unsafe { resources.EXTI.pr.write(|w| w.bits(0xffff)) };
     }
+
+    // Software task dispatchers, one per priority level
+    extern "C" {
+        fn USART1();
+        fn SPI1();
+    }
 };
+
+/// Deferred work, scheduled through `TIMEOUTS`
+#[derive(Copy, Clone, PartialEq)]
+pub enum Timeout {
+    /// The LED controller has been held off for `led::RESET_MS`
+    LedPowerOn,
+    /// The LED controller had `led::BOOT_MS` to boot and accepts
+    /// commands
+    LedReady,
+    /// Resume signalling started by `Usb::remote_wakeup` went on for
+    /// long enough
+    UsbWakeupDone,
+}
+
+/// Log work that was dropped because the queue of its task was full
+fn log_dropped<T>(task: &str, result: Result<(), T>) {
+    if result.is_err() {
+        crate::heprintln!("{} queue full, dropped", task).ok();
+    }
+}
+
+#[cfg(not(test))]
+impl<'a> KeyboardOutput for process_keys::Spawn<'a> {
+    fn queue_usb_report(
+        &mut self,
+        boot_report: &HidReport,
+        report: &NkroReport,
+    ) -> Result<(), QueueFull> {
+        self.usb_report(*boot_report, *report)
+            .map_err(|_| QueueFull)
+    }
+
+    fn queue_usb_consumer_report(&mut self, usage: u16) -> Result<(), QueueFull> {
+        self.usb_consumer_report(usage).map_err(|_| QueueFull)
+    }
+
+    fn queue_usb_system_report(&mut self, usage: u8) -> Result<(), QueueFull> {
+        self.usb_system_report(usage).map_err(|_| QueueFull)
+    }
+
+    fn queue_usb_mouse_report(&mut self, report: &MouseReport) {
+        log_dropped("usb_mouse_report", self.usb_mouse_report(*report));
+    }
+
+    fn queue_usb_wakeup(&mut self) {
+        log_dropped("usb_wakeup", self.usb_wakeup());
+    }
+
+    fn queue_bluetooth_report(&mut self, report: &HidReport) -> Result<(), QueueFull> {
+        self.bluetooth_report(*report).map_err(|_| QueueFull)
+    }
+
+    fn queue_led_keys(&mut self, state: &KeyState) {
+        log_dropped("led_keys", self.led_keys(*state));
+    }
+
+    fn queue_peripheral_action(&mut self, action: Action) {
+        log_dropped("peripheral_action", self.peripheral_action(action));
+    }
+
+    fn queue_bluetooth_mode(&mut self, enabled: bool, send_usb_report: bool) {
+        log_dropped(
+            "bluetooth_mode",
+            self.bluetooth_mode(enabled, send_usb_report),
+        );
+    }
+
+    fn queue_key_lights(&mut self, lights: KeyLights) {
+        log_dropped("key_lights", self.key_lights(lights));
+    }
+}
@@ -0,0 +1,196 @@
+use crate::clock;
+use crate::keymatrix::{KeyState, COLUMNS, ROWS};
+use crate::led::{KeyLight, KeyLights, LedMode};
+use bit_field::BitArray;
+
+/// Keys held down for longer than this are considered stuck
+const STUCK_MS: u32 = 5000;
+/// How often to send the lights of the next matrix row to the LED
+/// controller
+const REFRESH_MS: u32 = 20;
+
+/// Hardware check for `Action::MatrixTest`.
+///
+/// Every key lights up white while pressed and stays green once it
+/// has been released, so a working keyboard ends up all green. Keys
+/// held for longer than `STUCK_MS` flash red.
+///
+/// The lights are refreshed one matrix row at a time rather than on
+/// every change, so a message dropped by the LED UART only ever shows
+/// up for a moment.
+pub struct MatrixTest {
+    /// Keys that have been pressed and released since the test started
+    seen: KeyState,
+    stuck: KeyState,
+    /// `clock::now_ms()` at which each key was last pressed
+    pressed_at: [u32; COLUMNS * ROWS],
+    next_row: usize,
+    next_refresh: u32,
+}
+
+impl MatrixTest {
+    /// Start a test with `state` held down at `now`
+    pub fn new(state: &KeyState, now: u32) -> MatrixTest {
+        let mut pressed_at = [0; COLUMNS * ROWS];
+        for (key, time) in pressed_at.iter_mut().enumerate() {
+            if state.get_bit(key) {
+                *time = now;
+            }
+        }
+
+        MatrixTest {
+            seen: [0; 9],
+            stuck: [0; 9],
+            pressed_at,
+            next_row: 0,
+            next_refresh: now,
+        }
+    }
+
+    pub fn key_event(&mut self, key: usize, pressed: bool, time: u32) {
+        if pressed {
+            self.pressed_at[key] = time;
+        } else {
+            self.seen.set_bit(key, true);
+            self.stuck.set_bit(key, false);
+        }
+    }
+
+    /// Check for stuck keys, and return the lights of the next row if
+    /// it's time to refresh them
+    pub fn poll(&mut self, state: &KeyState, now: u32) -> Option<KeyLights> {
+        for key in 0..COLUMNS * ROWS {
+            if state.get_bit(key) && now.wrapping_sub(self.pressed_at[key]) >= STUCK_MS {
+                self.stuck.set_bit(key, true);
+            }
+        }
+
+        if !clock::is_due(now, self.next_refresh) {
+            return None;
+        }
+        self.next_refresh = now.wrapping_add(REFRESH_MS);
+
+        let row = self.next_row;
+        self.next_row = (row + 1) % ROWS;
+
+        let mut lights = KeyLights::new();
+        for key in row * COLUMNS..(row + 1) * COLUMNS {
+            let (red, green, blue, mode) = if self.stuck.get_bit(key) {
+                (0xff, 0x00, 0x00, LedMode::Flash)
+            } else if state.get_bit(key) {
+                (0xff, 0xff, 0xff, LedMode::On)
+            } else if self.seen.get_bit(key) {
+                (0x00, 0xff, 0x00, LedMode::On)
+            } else {
+                (0x00, 0x00, 0x00, LedMode::Off)
+            };
+            let light = KeyLight {
+                key: key as u8,
+                red,
+                green,
+                blue,
+                mode,
+            };
+            // A row always fits into KeyLights
+            lights.push(light).ok();
+        }
+        Some(lights)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// The lights of all rows, polling `REFRESH_MS` apart from `now` on
+    fn all_rows(test: &mut MatrixTest, state: &KeyState, now: &mut u32) -> Vec<KeyLight> {
+        let mut all = Vec::new();
+        for _ in 0..ROWS {
+            all.extend(test.poll(state, *now).expect("row not refreshed"));
+            *now += REFRESH_MS;
+        }
+        all
+    }
+
+    fn colour(lights: &[KeyLight], key: usize) -> &'static str {
+        let light = lights
+            .iter()
+            .find(|light| light.key as usize == key)
+            .unwrap();
+        match (light.red, light.green, light.blue, light.mode) {
+            (0x00, 0x00, 0x00, LedMode::Off) => "off",
+            (0xff, 0xff, 0xff, LedMode::On) => "white",
+            (0x00, 0xff, 0x00, LedMode::On) => "green",
+            (0xff, 0x00, 0x00, LedMode::Flash) => "flashing red",
+            _ => "unexpected",
+        }
+    }
+
+    #[test]
+    fn rows_take_turns() {
+        let state = [0; 9];
+        let mut test = MatrixTest::new(&state, 100);
+        for cycle in 0..2 {
+            for row in 0..ROWS {
+                let now = 100 + (cycle * ROWS + row) as u32 * REFRESH_MS;
+                let lights = test.poll(&state, now).unwrap();
+                assert_eq!(lights.len(), COLUMNS);
+                assert_eq!(lights[0].key as usize, row * COLUMNS);
+                // Not again before the next refresh
+                assert!(test.poll(&state, now + REFRESH_MS - 1).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn seen_after_release() {
+        let mut state = [0; 9];
+        let mut now = 0;
+        let mut test = MatrixTest::new(&state, now);
+        assert_eq!(colour(&all_rows(&mut test, &state, &mut now), 15), "off");
+
+        state.set_bit(15, true);
+        test.key_event(15, true, now);
+        assert_eq!(colour(&all_rows(&mut test, &state, &mut now), 15), "white");
+
+        state.set_bit(15, false);
+        test.key_event(15, false, now);
+        let lights = all_rows(&mut test, &state, &mut now);
+        assert_eq!(colour(&lights, 15), "green");
+        assert_eq!(colour(&lights, 16), "off");
+    }
+
+    #[test]
+    fn stuck_after_stuck_ms() {
+        let mut state = [0; 9];
+        let mut test = MatrixTest::new(&state, 0);
+        state.set_bit(3, true);
+        test.key_event(3, true, 10);
+
+        let mut now = 10 + STUCK_MS - 1;
+        assert_eq!(colour(&all_rows(&mut test, &state, &mut now), 3), "white");
+        assert_eq!(
+            colour(&all_rows(&mut test, &state, &mut now), 3),
+            "flashing red"
+        );
+
+        // A key that works after all is no longer stuck
+        state.set_bit(3, false);
+        test.key_event(3, false, now);
+        assert_eq!(colour(&all_rows(&mut test, &state, &mut now), 3), "green");
+    }
+
+    #[test]
+    fn held_at_start_counts_from_start() {
+        let mut state = [0; 9];
+        state.set_bit(5, true);
+        let mut test = MatrixTest::new(&state, 1000);
+        let mut now = 1000 + STUCK_MS - 1;
+        assert_eq!(colour(&all_rows(&mut test, &state, &mut now), 5), "white");
+        assert_eq!(
+            colour(&all_rows(&mut test, &state, &mut now), 5),
+            "flashing red"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use crate::clock;
+use crate::hidreport::MouseReport;
+
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum MouseAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    Button1,
+    Button2,
+    Button3,
+    Button4,
+    Button5,
+}
+
+impl MouseAction {
+    /// Bit of this action in `MouseKeys::set_held`
+    pub fn bit(self) -> u16 {
+        1 << self as u16
+    }
+
+    pub fn from_u8(b: u8) -> Option<MouseAction> {
+        match b {
+            0 => Some(MouseAction::Up),
+            1 => Some(MouseAction::Down),
+            2 => Some(MouseAction::Left),
+            3 => Some(MouseAction::Right),
+            4 => Some(MouseAction::WheelUp),
+            5 => Some(MouseAction::WheelDown),
+            6 => Some(MouseAction::Button1),
+            7 => Some(MouseAction::Button2),
+            8 => Some(MouseAction::Button3),
+            9 => Some(MouseAction::Button4),
+            10 => Some(MouseAction::Button5),
+            _ => None,
+        }
+    }
+}
+
+/// In the order of the report's button bits
+const BUTTONS: [MouseAction; 5] = [
+    MouseAction::Button1,
+    MouseAction::Button2,
+    MouseAction::Button3,
+    MouseAction::Button4,
+    MouseAction::Button5,
+];
+
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum Curve {
+    Linear,
+    /// Slow at first for fine positioning, then quickly faster
+    Quadratic,
+}
+
+/// How movement speeds up while a key is held
+#[derive(Copy, Clone)]
+pub struct Acceleration {
+    /// Pause after the first step, so a short tap moves exactly once
+    pub delay_ms: u32,
+    /// Time between steps after that
+    pub interval_ms: u32,
+    /// Distance of the first steps
+    pub min_speed: u8,
+    /// Distance of each step once fully accelerated, at most 127
+    pub max_speed: u8,
+    /// Time from the end of `delay_ms` until `max_speed` is reached
+    pub time_to_max_ms: u32,
+    pub curve: Curve,
+}
+
+impl Acceleration {
+    fn speed(&self, elapsed_ms: u32) -> i8 {
+        let t = elapsed_ms.min(self.time_to_max_ms);
+        let range = u32::from(self.max_speed - self.min_speed);
+        let extra = match self.curve {
+            Curve::Linear => range * t / self.time_to_max_ms,
+            Curve::Quadratic => range * t * t / (self.time_to_max_ms * self.time_to_max_ms),
+        };
+        (u32::from(self.min_speed) + extra).min(127) as i8
+    }
+}
+
+pub const POINTER_ACCELERATION: Acceleration = Acceleration {
+    delay_ms: 300,
+    interval_ms: 16,
+    min_speed: 2,
+    max_speed: 20,
+    time_to_max_ms: 1_500,
+    curve: Curve::Quadratic,
+};
+
+pub const WHEEL_ACCELERATION: Acceleration = Acceleration {
+    delay_ms: 300,
+    interval_ms: 80,
+    min_speed: 1,
+    max_speed: 4,
+    time_to_max_ms: 2_000,
+    curve: Curve::Linear,
+};
+
+/// Movement along one or two axes that accelerates while held
+struct Motion {
+    acceleration: Acceleration,
+    /// `clock::now_ms()` at which the motion started, `None` while
+    /// standing still
+    start: Option<u32>,
+    next_step: u32,
+}
+
+impl Motion {
+    const fn new(acceleration: Acceleration) -> Motion {
+        Motion {
+            acceleration,
+            start: None,
+            next_step: 0,
+        }
+    }
+
+    /// Speed of the step due at `now`, if any
+    fn step(&mut self, moving: bool, now: u32) -> Option<i8> {
+        if !moving {
+            self.start = None;
+            return None;
+        }
+
+        let accel = &self.acceleration;
+        match self.start {
+            None => {
+                self.start = Some(now);
+                self.next_step = now.wrapping_add(accel.delay_ms);
+                Some(accel.min_speed as i8)
+            }
+            Some(start) if clock::is_due(now, self.next_step) => {
+                self.next_step = now.wrapping_add(accel.interval_ms);
+                let elapsed = now.wrapping_sub(start).saturating_sub(accel.delay_ms);
+                Some(accel.speed(elapsed))
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Turns held `Action::Mouse` keys into mouse reports. Unlike the
+/// keyboard reports these are relative, so they have to be generated
+/// on every scan tick for as long as a movement key is held.
+pub struct MouseKeys {
+    /// One bit per held `MouseAction`
+    held: u16,
+    pointer: Motion,
+    wheel: Motion,
+    /// Buttons of the last report
+    buttons: u8,
+}
+
+impl MouseKeys {
+    pub const fn new() -> MouseKeys {
+        MouseKeys {
+            held: 0,
+            pointer: Motion::new(POINTER_ACCELERATION),
+            wheel: Motion::new(WHEEL_ACCELERATION),
+            buttons: 0,
+        }
+    }
+
+    /// Replace the held actions, one `MouseAction::bit` each
+    pub fn set_held(&mut self, actions: u16) {
+        self.held = actions;
+    }
+
+    fn is_held(&self, action: MouseAction) -> bool {
+        self.held & action.bit() != 0
+    }
+
+    /// The direction of the pair of actions, -1, 0 or 1
+    fn direction(&self, negative: MouseAction, positive: MouseAction) -> i8 {
+        self.is_held(positive) as i8 - self.is_held(negative) as i8
+    }
+
+    /// The report to send at `now`, if anything changed
+    pub fn poll(&mut self, now: u32) -> Option<MouseReport> {
+        let mut buttons = 0;
+        for (i, button) in BUTTONS.iter().enumerate() {
+            if self.is_held(*button) {
+                buttons |= 1 << i;
+            }
+        }
+
+        let x = self.direction(MouseAction::Left, MouseAction::Right);
+        let y = self.direction(MouseAction::Up, MouseAction::Down);
+        let wheel = self.direction(MouseAction::WheelDown, MouseAction::WheelUp);
+
+        let mut report = MouseReport {
+            buttons,
+            ..MouseReport::default()
+        };
+        if let Some(speed) = self.pointer.step(x != 0 || y != 0, now) {
+            report.x = x * speed;
+            report.y = y * speed;
+        }
+        if let Some(speed) = self.wheel.step(wheel != 0, now) {
+            report.wheel = wheel * speed;
+        }
+
+        if report.x == 0 && report.y == 0 && report.wheel == 0 && buttons == self.buttons {
+            return None;
+        }
+        self.buttons = buttons;
+        Some(report)
+    }
+}
@@ -0,0 +1,26 @@
+use core::ptr;
+
+/// Where the 96 bit unique device ID is on category 1 and 2 devices
+/// like the STM32L151C8. It's not contiguous.
+const UID_ADDRESSES: [usize; 3] = [0x1FF8_0050, 0x1FF8_0054, 0x1FF8_0064];
+
+/// Factory programmed ID, different for every chip
+pub fn unique_id() -> [u8; 12] {
+    let mut id = [0; 12];
+    for (bytes, &address) in id.chunks_mut(4).zip(UID_ADDRESSES.iter()) {
+        let word = unsafe { ptr::read_volatile(address as *const u32) };
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    id
+}
+
+/// `unique_id` as upper case hex digits, lowest byte first
+pub fn unique_id_hex() -> [u8; 24] {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    let mut hex = [0; 24];
+    for (digits, byte) in hex.chunks_mut(2).zip(unique_id().iter()) {
+        digits[0] = DIGITS[usize::from(byte >> 4)];
+        digits[1] = DIGITS[usize::from(byte & 0xF)];
+    }
+    hex
+}
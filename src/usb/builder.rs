@@ -0,0 +1,321 @@
+//! Descriptors built at compile time from typed items, so that lengths
+//! and counts can't get out of sync with the bytes they describe.
+//!
+//! Both `report_descriptor!` and `descriptor!` turn a `const` list of
+//! items into a `[u8; N]` array. The bytes are looked up one by one,
+//! which is slow but only ever runs in the compiler.
+
+/// The items of a HID report descriptor, see the HID spec 6.2.2.
+/// Each one is encoded as a short item with the smallest data size
+/// that holds its value.
+#[derive(Clone, Copy)]
+pub enum ReportItem {
+    UsagePage(u16),
+    Usage(u16),
+    UsageMinimum(u16),
+    UsageMaximum(u16),
+    LogicalMinimum(i32),
+    LogicalMaximum(i32),
+    ReportId(u8),
+    ReportSize(u8),
+    ReportCount(u8),
+    Input(u8),
+    Output(u8),
+    Collection(u8),
+    EndCollection,
+}
+
+// Data bits of Input and Output items
+pub const DATA: u8 = 0x00;
+pub const CONSTANT: u8 = 0x01;
+pub const ARRAY: u8 = 0x00;
+pub const VARIABLE: u8 = 0x02;
+pub const ABSOLUTE: u8 = 0x00;
+pub const RELATIVE: u8 = 0x04;
+
+// Collection types
+pub const PHYSICAL: u8 = 0x00;
+pub const APPLICATION: u8 = 0x01;
+
+impl ReportItem {
+    /// The prefix byte without the size, and the data
+    const fn parts(self) -> (u8, u32, bool) {
+        match self {
+            ReportItem::UsagePage(page) => (0x04, page as u32, false),
+            ReportItem::Usage(usage) => (0x08, usage as u32, false),
+            ReportItem::UsageMinimum(usage) => (0x18, usage as u32, false),
+            ReportItem::UsageMaximum(usage) => (0x28, usage as u32, false),
+            ReportItem::LogicalMinimum(value) => (0x14, value as u32, true),
+            ReportItem::LogicalMaximum(value) => (0x24, value as u32, true),
+            ReportItem::ReportId(id) => (0x84, id as u32, false),
+            ReportItem::ReportSize(bits) => (0x74, bits as u32, false),
+            ReportItem::ReportCount(count) => (0x94, count as u32, false),
+            ReportItem::Input(flags) => (0x80, flags as u32, false),
+            ReportItem::Output(flags) => (0x90, flags as u32, false),
+            ReportItem::Collection(kind) => (0xA0, kind as u32, false),
+            ReportItem::EndCollection => (0xC0, 0, false),
+        }
+    }
+
+    /// Number of data bytes, 0, 1, 2 or 4
+    const fn data_len(self) -> usize {
+        let (_, data, signed) = self.parts();
+        if let ReportItem::EndCollection = self {
+            0
+        } else if signed {
+            let value = data as i32;
+            if value >= -0x80 && value < 0x80 {
+                1
+            } else if value >= -0x8000 && value < 0x8000 {
+                2
+            } else {
+                4
+            }
+        } else if data <= 0xFF {
+            1
+        } else if data <= 0xFFFF {
+            2
+        } else {
+            4
+        }
+    }
+
+    const fn len(self) -> usize {
+        1 + self.data_len()
+    }
+
+    const fn byte(self, index: usize) -> u8 {
+        let (prefix, data, _) = self.parts();
+        if index == 0 {
+            let size = match self.data_len() {
+                4 => 3,
+                len => len as u8,
+            };
+            prefix | size
+        } else {
+            (data >> ((index - 1) * 8)) as u8
+        }
+    }
+}
+
+pub const fn report_len(items: &[ReportItem]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < items.len() {
+        len += items[i].len();
+        i += 1;
+    }
+    len
+}
+
+pub const fn report_byte(items: &[ReportItem], mut index: usize) -> u8 {
+    let mut i = 0;
+    while index >= items[i].len() {
+        index -= items[i].len();
+        i += 1;
+    }
+    items[i].byte(index)
+}
+
+/// Standard and class specific descriptors, as they go into a
+/// configuration descriptor
+#[derive(Clone, Copy)]
+pub enum Descriptor {
+    /// wTotalLength and bNumInterfaces are those of the whole list, so
+    /// it has to come first
+    Configuration {
+        value: u8,
+        string: u8,
+        attributes: u8,
+        max_power_ma: u16,
+    },
+    InterfaceAssociation {
+        first_interface: u8,
+        count: u8,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+    },
+    Interface {
+        number: u8,
+        endpoints: u8,
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        string: u8,
+    },
+    Endpoint {
+        address: u8,
+        attributes: u8,
+        max_packet_size: u16,
+        interval: u8,
+    },
+    /// The HID class descriptor, with a single report descriptor of
+    /// `report_len` bytes
+    Hid { report_len: usize },
+    /// Any other descriptor, the bytes after bLength
+    Raw(&'static [u8]),
+}
+
+// bmAttributes of Configuration
+pub const BUS_POWERED: u8 = 0x80;
+pub const REMOTE_WAKEUP: u8 = 0x20;
+
+// bmAttributes of Endpoint
+pub const BULK: u8 = 0x02;
+pub const INTERRUPT: u8 = 0x03;
+
+/// Bit 7 of an endpoint address
+pub const IN: u8 = 0x80;
+
+impl Descriptor {
+    const fn len(self) -> usize {
+        match self {
+            Descriptor::Configuration { .. } => 9,
+            Descriptor::InterfaceAssociation { .. } => 8,
+            Descriptor::Interface { .. } => 9,
+            Descriptor::Endpoint { .. } => 7,
+            Descriptor::Hid { .. } => 9,
+            Descriptor::Raw(bytes) => 1 + bytes.len(),
+        }
+    }
+
+    /// Byte `index` of the descriptor, which is one of `items`
+    const fn byte(self, index: usize, items: &[Descriptor]) -> u8 {
+        if index == 0 {
+            return self.len() as u8;
+        }
+        match self {
+            Descriptor::Configuration {
+                value,
+                string,
+                attributes,
+                max_power_ma,
+            } => {
+                let total_len = descriptors_len(items);
+                let bytes = [
+                    0x02,
+                    total_len as u8,
+                    (total_len >> 8) as u8,
+                    interface_count(items),
+                    value,
+                    string,
+                    attributes,
+                    (max_power_ma / 2) as u8,
+                ];
+                bytes[index - 1]
+            }
+            Descriptor::InterfaceAssociation {
+                first_interface,
+                count,
+                class,
+                subclass,
+                protocol,
+            } => {
+                let bytes = [0x0B, first_interface, count, class, subclass, protocol, 0];
+                bytes[index - 1]
+            }
+            Descriptor::Interface {
+                number,
+                endpoints,
+                class,
+                subclass,
+                protocol,
+                string,
+            } => {
+                let bytes = [
+                    0x04, number, 0, endpoints, class, subclass, protocol, string,
+                ];
+                bytes[index - 1]
+            }
+            Descriptor::Endpoint {
+                address,
+                attributes,
+                max_packet_size,
+                interval,
+            } => {
+                let bytes = [
+                    0x05,
+                    address,
+                    attributes,
+                    max_packet_size as u8,
+                    (max_packet_size >> 8) as u8,
+                    interval,
+                ];
+                bytes[index - 1]
+            }
+            Descriptor::Hid { report_len } => {
+                // HID 1.11, not localized, one report descriptor
+                let bytes = [
+                    0x21,
+                    0x11,
+                    0x01,
+                    0,
+                    1,
+                    0x22,
+                    report_len as u8,
+                    (report_len >> 8) as u8,
+                ];
+                bytes[index - 1]
+            }
+            Descriptor::Raw(bytes) => bytes[index - 1],
+        }
+    }
+}
+
+pub const fn descriptors_len(items: &[Descriptor]) -> usize {
+    let mut len = 0;
+    let mut i = 0;
+    while i < items.len() {
+        len += items[i].len();
+        i += 1;
+    }
+    len
+}
+
+const fn interface_count(items: &[Descriptor]) -> u8 {
+    let mut count = 0;
+    let mut i = 0;
+    while i < items.len() {
+        if let Descriptor::Interface { .. } = items[i] {
+            count += 1;
+        }
+        i += 1;
+    }
+    count
+}
+
+pub const fn descriptors_byte(items: &[Descriptor], mut index: usize) -> u8 {
+    let mut i = 0;
+    while index >= items[i].len() {
+        index -= items[i].len();
+        i += 1;
+    }
+    items[i].byte(index, items)
+}
+
+/// A report descriptor array from a `&[ReportItem]` constant
+macro_rules! report_descriptor {
+    ($items:expr) => {{
+        let mut bytes = [0; $crate::usb::builder::report_len($items)];
+        let mut i = 0;
+        while i < bytes.len() {
+            bytes[i] = $crate::usb::builder::report_byte($items, i);
+            i += 1;
+        }
+        bytes
+    }};
+}
+
+/// A descriptor array from a `&[Descriptor]` constant
+macro_rules! descriptor {
+    ($items:expr) => {{
+        let mut bytes = [0; $crate::usb::builder::descriptors_len($items)];
+        let mut i = 0;
+        while i < bytes.len() {
+            bytes[i] = $crate::usb::builder::descriptors_byte($items, i);
+            i += 1;
+        }
+        bytes
+    }};
+}
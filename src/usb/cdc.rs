@@ -0,0 +1,224 @@
+use crate::debug::LOG;
+use crate::usb::endpoint::{Endpoint, EndpointConfig, EndpointType};
+use crate::usb::pma::PMA;
+use cortex_m::interrupt;
+use heapless::consts::{U512, U64};
+use heapless::spsc::Queue;
+use heapless::Vec;
+use stm32l1::stm32l151::USB;
+
+/// Interface numbers from `descriptors::CONF_DESC`
+pub const COMM_INTERFACE: u16 = 2;
+
+/// Never used, but hosts expect a communications interface to have one
+pub const NOTIFICATION_ENDPOINT: EndpointConfig = EndpointConfig {
+    number: 3,
+    ep_type: EndpointType::Interrupt,
+    tx_size: 8,
+    rx_size: 0,
+};
+pub const DATA_ENDPOINT: EndpointConfig = EndpointConfig {
+    number: 4,
+    ep_type: EndpointType::Bulk,
+    tx_size: 64,
+    rx_size: 64,
+};
+
+/// 115200 baud, 1 stop bit, no parity, 8 data bits. None of it
+/// matters over USB, it's only kept for GET_LINE_CODING.
+const DEFAULT_LINE_CODING: [u8; 7] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+/// DTR bit of SET_CONTROL_LINE_STATE
+const CONTROL_LINE_DTR: u16 = 1;
+
+/// A command typed into the console, without the line ending
+pub type ConsoleLine = Vec<u8, U64>;
+
+/// The USB serial port, a line based console for `console::execute`
+pub struct UsbSerial {
+    endpoint: Endpoint,
+    pub line_coding: [u8; 7],
+    /// A terminal is open on the host, nothing is sent otherwise
+    connected: bool,
+    /// Output waiting to be sent
+    tx: Queue<u8, U512>,
+    /// A packet is on its way to the host
+    busy: bool,
+    /// The line being typed
+    line: ConsoleLine,
+    /// Last received byte was a CR, so a following LF is ignored
+    after_cr: bool,
+    /// A finished line waiting for `take_line`. Until then no more
+    /// input is accepted, so the host can't overrun us.
+    pending_line: Option<ConsoleLine>,
+    /// `LOG` is being sent, followed by this once it's empty
+    log_then: Option<&'static [u8]>,
+}
+
+impl UsbSerial {
+    pub fn new(endpoint: Endpoint) -> UsbSerial {
+        UsbSerial {
+            endpoint,
+            line_coding: DEFAULT_LINE_CODING,
+            connected: false,
+            tx: Queue::new(),
+            busy: false,
+            line: Vec::new(),
+            after_cr: false,
+            pending_line: None,
+            log_then: None,
+        }
+    }
+
+    /// Back to the defaults after a bus reset
+    pub fn reset(&mut self) {
+        *self = UsbSerial::new(self.endpoint);
+    }
+
+    /// Greets a newly opened terminal, the greeting goes out with the
+    /// next `send`
+    pub fn set_control_line_state(&mut self, value: u16) {
+        let connected = value & CONTROL_LINE_DTR != 0;
+        if connected && !self.connected {
+            self.connected = true;
+            self.queue(b"anne-key console, type help for commands\r\n> ");
+        }
+        self.connected = connected;
+        if !connected {
+            self.log_then = None;
+        }
+    }
+
+    /// Send everything in `LOG`, followed by `then`. The log is moved
+    /// into the output queue as that gets room, so only what's sent is
+    /// taken out of it.
+    pub fn write_log(&mut self, usb: &USB, pma: &PMA, then: &'static [u8]) {
+        if self.connected {
+            self.log_then = Some(then);
+            self.send(usb, pma);
+        }
+    }
+
+    /// Move as much of `LOG` into the output queue as fits, with `\n`
+    /// turned into `\r\n`
+    fn queue_log(&mut self) {
+        let then = match self.log_then {
+            Some(then) => then,
+            None => return,
+        };
+        // Room for every byte to be a line ending
+        let mut buffer = [0; 64];
+        let wanted = (self.room() / 2).min(buffer.len());
+        let count = interrupt::free(|cs| LOG.borrow(cs).borrow_mut().read(&mut buffer[..wanted]));
+        for &byte in &buffer[..count] {
+            if byte == b'\n' {
+                self.tx.enqueue(b'\r').ok();
+            }
+            self.tx.enqueue(byte).ok();
+        }
+        // Less than asked for means the log is done
+        if count < wanted && self.room() >= then.len() {
+            self.log_then = None;
+            self.queue(then);
+        }
+    }
+
+    /// Free space in the output queue
+    fn room(&self) -> usize {
+        self.tx.capacity() - self.tx.len()
+    }
+
+    /// Queue `data` for the host, whatever doesn't fit is dropped
+    pub fn write(&mut self, usb: &USB, pma: &PMA, data: &[u8]) {
+        self.queue(data);
+        self.send(usb, pma);
+    }
+
+    fn queue(&mut self, data: &[u8]) {
+        if !self.connected {
+            return;
+        }
+        for &byte in data {
+            if self.tx.enqueue(byte).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Start sending queued output, unless a packet is on its way
+    pub fn send(&mut self, usb: &USB, pma: &PMA) {
+        self.queue_log();
+        if self.busy || self.tx.is_empty() {
+            return;
+        }
+        let mut packet = [0; 64];
+        let mut size = 0;
+        while size < packet.len() {
+            match self.tx.dequeue() {
+                Some(byte) => packet[size] = byte,
+                None => break,
+            }
+            size += 1;
+        }
+        self.endpoint.write(pma, &packet[..size]);
+        self.endpoint.register(usb).toggle_tx_keep_ctr();
+        self.busy = true;
+    }
+
+    /// The finished line, if any. Accepts input again afterwards.
+    pub fn take_line(&mut self, usb: &USB) -> Option<ConsoleLine> {
+        let line = self.pending_line.take();
+        if line.is_some() {
+            self.endpoint.register(usb).toggle_rx_keep_ctr();
+        }
+        line
+    }
+
+    pub fn ctr(&mut self, usb: &USB, pma: &PMA) {
+        let register = self.endpoint.register(usb);
+        if register.ctr_tx() {
+            register.clear_ctr_tx();
+            self.busy = false;
+        }
+        if register.ctr_rx() {
+            register.clear_ctr_rx();
+            let mut packet = [0; 64];
+            let size = self.endpoint.read(pma, &mut packet);
+            self.receive(usb, pma, &packet[..size]);
+            if self.pending_line.is_none() {
+                register.toggle_rx_keep_ctr();
+            }
+        }
+        self.send(usb, pma);
+    }
+
+    /// Basic line editing, with everything echoed back since terminals
+    /// usually don't do that themselves
+    fn receive(&mut self, usb: &USB, pma: &PMA, data: &[u8]) {
+        for &byte in data {
+            let after_cr = self.after_cr;
+            self.after_cr = byte == b'\r';
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    self.write(usb, pma, b"\r\n");
+                    self.pending_line = Some(core::mem::replace(&mut self.line, Vec::new()));
+                    // The rest of the packet is dropped, one command
+                    // at a time
+                    return;
+                }
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if self.line.pop().is_some() {
+                        self.write(usb, pma, b"\x08 \x08");
+                    }
+                }
+                b' '..=b'~' => {
+                    if self.line.push(byte).is_ok() {
+                        self.write(usb, pma, &[byte]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
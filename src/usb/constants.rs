@@ -1,7 +1,5 @@
 #![allow(dead_code)]
 
-use core::mem::transmute;
-
 #[repr(u8)]
 #[non_exhaustive]
 #[derive(Debug, Copy, Clone)]
@@ -18,15 +16,97 @@ pub enum UsbRequest {
     GetInterface = 0x0A,
     SetInterface = 0x0B,
     SynchFrame = 0x0C,
+    /// Anything the host might send that isn't in the spec
+    Unknown = 0xFF,
 }
 
 impl From<u8> for UsbRequest {
     #[inline]
     fn from(b: u8) -> Self {
-        unsafe { transmute(b) }
+        match b {
+            0x00 => UsbRequest::GetStatus,
+            0x01 => UsbRequest::ClearFeature,
+            0x02 => UsbRequest::Two,
+            0x03 => UsbRequest::SetFeature,
+            0x05 => UsbRequest::SetAddress,
+            0x06 => UsbRequest::GetDescriptor,
+            0x07 => UsbRequest::SetDescriptor,
+            0x08 => UsbRequest::GetConfiguration,
+            0x09 => UsbRequest::SetConfiguration,
+            0x0A => UsbRequest::GetInterface,
+            0x0B => UsbRequest::SetInterface,
+            0x0C => UsbRequest::SynchFrame,
+            _ => UsbRequest::Unknown,
+        }
     }
 }
 
+/// Class specific requests of HID interfaces
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum HidRequest {
+    GetReport = 0x01,
+    GetIdle = 0x02,
+    GetProtocol = 0x03,
+    SetReport = 0x09,
+    SetIdle = 0x0A,
+    SetProtocol = 0x0B,
+}
+
+impl HidRequest {
+    pub fn from_u8(b: u8) -> Option<HidRequest> {
+        match b {
+            0x01 => Some(HidRequest::GetReport),
+            0x02 => Some(HidRequest::GetIdle),
+            0x03 => Some(HidRequest::GetProtocol),
+            0x09 => Some(HidRequest::SetReport),
+            0x0A => Some(HidRequest::SetIdle),
+            0x0B => Some(HidRequest::SetProtocol),
+            _ => None,
+        }
+    }
+}
+
+/// Class specific requests of CDC-ACM communications interfaces
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CdcRequest {
+    SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
+    SetControlLineState = 0x22,
+}
+
+impl CdcRequest {
+    pub fn from_u8(b: u8) -> Option<CdcRequest> {
+        match b {
+            0x20 => Some(CdcRequest::SetLineCoding),
+            0x21 => Some(CdcRequest::GetLineCoding),
+            0x22 => Some(CdcRequest::SetControlLineState),
+            _ => None,
+        }
+    }
+}
+
+/// Run-time mode requests of the DFU class
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DfuRequest {
+    Detach = 0x00,
+    GetStatus = 0x03,
+    GetState = 0x05,
+}
+
+impl DfuRequest {
+    pub fn from_u8(b: u8) -> Option<DfuRequest> {
+        match b {
+            0x00 => Some(DfuRequest::Detach),
+            0x03 => Some(DfuRequest::GetStatus),
+            0x05 => Some(DfuRequest::GetState),
+            _ => None,
+        }
+    }
+}
+
+/// Feature selector of SET_FEATURE/CLEAR_FEATURE to the device
+pub const DEVICE_REMOTE_WAKEUP: u16 = 1;
+
 #[repr(u8)]
 #[non_exhaustive]
 #[derive(Debug, Copy, Clone)]
@@ -42,12 +122,27 @@ pub enum UsbDescriptorType {
     Bos = 0x0F,
     Hid = 0x21,
     HidReport = 0x22,
+    /// Anything the host might ask for that we don't know about
+    Unknown = 0xFF,
 }
 
 impl From<u8> for UsbDescriptorType {
     #[inline]
     fn from(b: u8) -> Self {
-        unsafe { transmute(b) }
+        match b {
+            1 => UsbDescriptorType::Device,
+            2 => UsbDescriptorType::Configuration,
+            3 => UsbDescriptorType::StringDesc,
+            4 => UsbDescriptorType::Interface,
+            5 => UsbDescriptorType::Endpoint,
+            6 => UsbDescriptorType::DeviceQualifier,
+            7 => UsbDescriptorType::OtherSpeedConfiguration,
+            0x0A => UsbDescriptorType::Debug,
+            0x0F => UsbDescriptorType::Bos,
+            0x21 => UsbDescriptorType::Hid,
+            0x22 => UsbDescriptorType::HidReport,
+            _ => UsbDescriptorType::Unknown,
+        }
     }
 }
 
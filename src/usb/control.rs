@@ -0,0 +1,1062 @@
+//! Control transfers on endpoint 0: the SETUP/DATA/STATUS phases and
+//! the routing of requests to the device and its classes.
+//!
+//! IN data stages longer than `MAX_PACKET_SIZE` go out one packet per
+//! completed IN transaction. A data stage that is shorter than the host
+//! asked for but a multiple of the packet size ends with an empty
+//! packet, so the host doesn't wait for more.
+//!
+//! The endpoint itself is behind `ControlEndpoint`, so the tests can
+//! run sequences of host requests through it without the USB
+//! peripheral.
+
+use core::cmp::min;
+
+use crate::usb::cdc::{self, UsbSerial};
+use crate::usb::constants::{
+    CdcRequest, DfuRequest, HidRequest, UsbDescriptorType, UsbDeviceState, UsbRequest,
+    DEVICE_REMOTE_WAKEUP,
+};
+use crate::usb::descriptors;
+use crate::usb::dfu;
+use crate::usb::hid::{self, UsbHid};
+use crate::usb::rawhid;
+
+pub const MAX_PACKET_SIZE: usize = 64;
+
+/// String descriptor with `uid::unique_id_hex` in UTF-16
+pub const SERIAL_NUMBER_LEN: usize = 2 + 24 * 2;
+
+/// The operations on endpoint 0 that a control transfer needs. Each of
+/// them also acknowledges the transaction that was just completed.
+pub trait ControlEndpoint {
+    /// Copy the SETUP or OUT packet that was received into `buf`,
+    /// returns how many bytes were copied
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+    /// Send the first packet of an IN data stage. The host may end the
+    /// transfer with its status packet at any point, so OUT is
+    /// accepted as well.
+    fn start_in(&mut self, packet: &[u8]);
+    /// Send the next packet of an IN data stage
+    fn continue_in(&mut self, packet: &[u8]);
+    /// Send the empty IN packet of a status stage
+    fn send_status(&mut self);
+    /// Accept the next OUT packet
+    fn receive(&mut self);
+    /// Reject the request, until the next SETUP
+    fn stall(&mut self);
+    /// Leave both directions NAK, waiting for the host
+    fn wait(&mut self);
+    /// Switch to the address from SET_ADDRESS
+    fn set_address(&mut self, address: u8);
+}
+
+/// What completed on endpoint 0
+#[derive(Copy, Clone, Debug)]
+pub enum Transaction {
+    Setup,
+    Out,
+    In,
+}
+
+/// Requests with a data stage from the host
+#[derive(Copy, Clone)]
+enum ControlOut {
+    /// SET_REPORT for this report type
+    SetReport(u8),
+    SetLineCoding,
+}
+
+/// Where endpoint 0 is within a control transfer
+#[derive(Copy, Clone)]
+enum ControlPhase {
+    /// Waiting for the next SETUP
+    Idle,
+    /// Sending `control_in`, followed by an empty packet if `zlp` is set
+    DataIn { zlp: bool },
+    /// Waiting for the host to acknowledge the data stage with an empty
+    /// OUT packet
+    StatusOut,
+    /// Waiting for the data stage of the request
+    DataOut(ControlOut),
+    /// Our empty IN packet acknowledging the request is on its way
+    StatusIn,
+}
+
+pub struct Control {
+    pub device_state: UsbDeviceState,
+    phase: ControlPhase,
+    /// Data of the current control IN transfer that didn't fit into
+    /// the packets sent so far
+    control_in: &'static [u8],
+    /// Address from SET_ADDRESS, which only takes effect once the status
+    /// stage is done
+    pending_address: Option<u8>,
+    /// The host allows us to wake it up from suspend
+    pub remote_wakeup_enabled: bool,
+    /// String descriptor 3, unique to the chip
+    serial_number: [u8; SERIAL_NUMBER_LEN],
+    /// DFU_DETACH was received, its status stage is still to be done
+    detach_pending: bool,
+    /// DFU_DETACH was acknowledged, time to reboot into the bootloader
+    pub detached: bool,
+}
+
+impl Control {
+    pub fn new(serial_number: [u8; SERIAL_NUMBER_LEN]) -> Control {
+        Control {
+            device_state: UsbDeviceState::Disconnected,
+            phase: ControlPhase::Idle,
+            control_in: &[],
+            pending_address: None,
+            remote_wakeup_enabled: false,
+            serial_number,
+            detach_pending: false,
+            detached: false,
+        }
+    }
+
+    /// Back to the default state after a bus reset
+    pub fn reset(&mut self) {
+        *self = Control {
+            device_state: UsbDeviceState::Default,
+            ..Control::new(self.serial_number)
+        };
+    }
+
+    /// Handle a completed transaction on endpoint 0
+    pub fn ctr<E>(
+        &mut self,
+        transaction: Transaction,
+        ep: &mut E,
+        hid: &mut UsbHid,
+        serial: &mut UsbSerial,
+    ) where
+        E: ControlEndpoint,
+    {
+        match transaction {
+            Transaction::Setup => self.setup(ep, hid, serial),
+            Transaction::Out => self.control_out(ep, hid, serial),
+            Transaction::In => self.tx(ep),
+        }
+    }
+
+    /// Whether an IN data stage of `size` bytes needs a zero length
+    /// packet at the end. The host only knows the transfer is over on a
+    /// short packet or once it has `length` bytes.
+    fn needs_zlp(size: usize, length: u16) -> bool {
+        size != 0 && size < length as usize && size % MAX_PACKET_SIZE == 0
+    }
+
+    /// Start sending `data` to the host, truncated to the `length` it
+    /// asked for. Anything beyond `MAX_PACKET_SIZE` is sent from `tx`
+    /// once the first packet has gone out.
+    fn start_control_in<E: ControlEndpoint>(
+        &mut self,
+        ep: &mut E,
+        data: &'static [u8],
+        length: u16,
+    ) {
+        let data = &data[..min(length as usize, data.len())];
+        let (packet, rest) = data.split_at(min(data.len(), MAX_PACKET_SIZE));
+        self.control_in = rest;
+        self.phase = ControlPhase::DataIn {
+            zlp: Control::needs_zlp(data.len(), length),
+        };
+        ep.start_in(packet);
+    }
+
+    /// Send `data` that fits into a single packet, truncated to the
+    /// `length` the host asked for
+    fn control_in_packet<E: ControlEndpoint>(&mut self, ep: &mut E, data: &[u8], length: u16) {
+        let size = min(min(length as usize, data.len()), MAX_PACKET_SIZE);
+        self.control_in = &[];
+        self.phase = ControlPhase::DataIn {
+            zlp: Control::needs_zlp(size, length),
+        };
+        ep.start_in(&data[..size]);
+    }
+
+    /// Status stage of a request without data stage, or of a control
+    /// OUT transfer: an empty IN packet
+    fn control_ack<E: ControlEndpoint>(&mut self, ep: &mut E) {
+        self.phase = ControlPhase::StatusIn;
+        ep.send_status();
+    }
+
+    /// Reject the request, the next SETUP is still accepted
+    fn control_stall<E: ControlEndpoint>(&mut self, ep: &mut E) {
+        self.phase = ControlPhase::Idle;
+        self.control_in = &[];
+        ep.stall();
+    }
+
+    /// An IN packet on endpoint 0 was sent
+    fn tx<E: ControlEndpoint>(&mut self, ep: &mut E) {
+        match self.phase {
+            ControlPhase::DataIn { zlp } => {
+                if !self.control_in.is_empty() {
+                    let size = min(self.control_in.len(), MAX_PACKET_SIZE);
+                    let (packet, rest) = self.control_in.split_at(size);
+                    self.control_in = rest;
+                    ep.continue_in(packet);
+                } else if zlp {
+                    self.phase = ControlPhase::DataIn { zlp: false };
+                    ep.continue_in(&[]);
+                } else {
+                    // All sent, the endpoint stays NAK until the host
+                    // acknowledges
+                    self.phase = ControlPhase::StatusOut;
+                    ep.wait();
+                }
+            }
+            ControlPhase::StatusIn => {
+                if self.detach_pending {
+                    self.detached = true;
+                }
+                if let Some(address) = self.pending_address.take() {
+                    ep.set_address(address);
+                    self.device_state = UsbDeviceState::Addressed;
+                }
+                self.phase = ControlPhase::Idle;
+                ep.wait();
+            }
+            _ => ep.wait(),
+        }
+    }
+
+    /// Data stage of a control OUT transfer, or status stage of a
+    /// control IN transfer
+    fn control_out<E: ControlEndpoint>(
+        &mut self,
+        ep: &mut E,
+        hid: &mut UsbHid,
+        serial: &mut UsbSerial,
+    ) {
+        let mut data = [0; 8];
+        let count = ep.read(&mut data);
+
+        match self.phase {
+            ControlPhase::DataOut(ControlOut::SetReport(report_type)) => {
+                hid.set_report(report_type, &data[..count]);
+                self.control_ack(ep);
+            }
+            ControlPhase::DataOut(ControlOut::SetLineCoding) => {
+                if count == serial.line_coding.len() {
+                    serial.line_coding.copy_from_slice(&data[..count]);
+                }
+                self.control_ack(ep);
+            }
+            _ => {
+                // The end of an IN transfer, possibly cut short by the
+                // host because it already had what it wanted
+                self.phase = ControlPhase::Idle;
+                self.control_in = &[];
+                ep.receive();
+            }
+        }
+    }
+
+    fn get_device_descriptor<E: ControlEndpoint>(&mut self, ep: &mut E, value: u16, length: u16) {
+        let descriptor_type = UsbDescriptorType::from((value >> 8) as u8);
+        let index = (value & 0xff) as u8;
+        let descriptor: Option<&'static [u8]> = match descriptor_type {
+            UsbDescriptorType::Configuration => Some(&descriptors::CONF_DESC),
+            UsbDescriptorType::Device => Some(&descriptors::DEV_DESC),
+            UsbDescriptorType::DeviceQualifier => Some(&descriptors::DEVICE_QUALIFIER),
+            UsbDescriptorType::StringDesc => match index {
+                0 => Some(&descriptors::LANG_STR),
+                1 => Some(&descriptors::MANUFACTURER_STR),
+                2 => Some(&descriptors::PRODUCT_STR),
+                3 => {
+                    // Not static, but short enough for one packet
+                    let serial_number = self.serial_number;
+                    self.control_in_packet(ep, &serial_number, length);
+                    return;
+                }
+                4 => Some(&descriptors::CONF_STR),
+                5 => Some(&descriptors::INTERFACE_STR),
+                _ => None,
+            },
+            UsbDescriptorType::Debug => None,
+            _ => {
+                crate::heprintln!("get descriptor {:x}", value).ok();
+                None
+            }
+        };
+        match descriptor {
+            Some(bytes) => self.start_control_in(ep, bytes, length),
+            None => self.control_stall(ep),
+        }
+    }
+
+    fn setup<E: ControlEndpoint>(&mut self, ep: &mut E, hid: &mut UsbHid, serial: &mut UsbSerial) {
+        let mut setup = [0; 8];
+        ep.read(&mut setup);
+        let request16 = u16::from_le_bytes([setup[0], setup[1]]);
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+        let length = u16::from_le_bytes([setup[6], setup[7]]);
+
+        // A SETUP aborts whatever transfer was going on
+        self.phase = ControlPhase::Idle;
+        self.control_in = &[];
+
+        let request_type = (request16 & 0xff) as u8;
+        if request_type & 0x60 == 0x20 {
+            let request = (request16 >> 8) as u8;
+            if index == cdc::COMM_INTERFACE {
+                self.cdc_request(ep, serial, request_type, request, value, length);
+            } else if index == dfu::INTERFACE {
+                self.dfu_request(ep, request_type, request, length);
+            } else {
+                self.hid_request(ep, hid, request_type, request, value, index, length);
+            }
+            return;
+        }
+
+        let request = UsbRequest::from(((request16 & 0xff00) >> 8) as u8);
+        match (request_type, request) {
+            (0x80, UsbRequest::GetStatus) => {
+                // Bit 0 would be self powered
+                let status = u8::from(self.remote_wakeup_enabled) << 1;
+                self.control_in_packet(ep, &[status, 0], length);
+            }
+            (0x00, UsbRequest::ClearFeature) if value == DEVICE_REMOTE_WAKEUP => {
+                self.remote_wakeup_enabled = false;
+                self.control_ack(ep);
+            }
+            (0x00, UsbRequest::SetFeature) if value == DEVICE_REMOTE_WAKEUP => {
+                self.remote_wakeup_enabled = true;
+                self.control_ack(ep);
+            }
+            (0x00, UsbRequest::SetAddress) => {
+                // Still answer the status stage on address 0
+                self.pending_address = Some(value as u8);
+                self.control_ack(ep);
+            }
+            (0x80, UsbRequest::GetDescriptor) => {
+                self.get_device_descriptor(ep, value, length);
+            }
+            (0x80, UsbRequest::GetConfiguration) => {
+                let configuration = match self.device_state {
+                    UsbDeviceState::Configured => 1,
+                    _ => 0,
+                };
+                self.control_in_packet(ep, &[configuration], length);
+            }
+            (0x00, UsbRequest::SetConfiguration) => {
+                self.device_state = if value == 0 {
+                    UsbDeviceState::Addressed
+                } else {
+                    UsbDeviceState::Configured
+                };
+                self.control_ack(ep);
+            }
+
+            (0x81, UsbRequest::GetStatus) | (0x82, UsbRequest::GetStatus) => {
+                self.control_in_packet(ep, &[0, 0], length);
+            }
+            (0x81, UsbRequest::GetInterface) => {
+                // No alternate settings
+                self.control_in_packet(ep, &[0], length);
+            }
+            (0x01, UsbRequest::SetInterface) if value == 0 => {
+                self.control_ack(ep);
+            }
+            (0x02, UsbRequest::ClearFeature) => {
+                // Endpoint halt, which is never set
+                self.control_ack(ep);
+            }
+            (0x81, UsbRequest::GetDescriptor) => {
+                let descriptor_type = UsbDescriptorType::from((value >> 8) as u8);
+                // The interface is in the index
+                let descriptor: &'static [u8] = match (descriptor_type, index) {
+                    (UsbDescriptorType::Hid, hid::KEYBOARD_INTERFACE) => &descriptors::HID_DESC,
+                    (UsbDescriptorType::HidReport, hid::KEYBOARD_INTERFACE) => {
+                        &descriptors::HID_REPORT_DESC
+                    }
+                    (UsbDescriptorType::Hid, hid::MOUSE_INTERFACE) => &descriptors::MOUSE_HID_DESC,
+                    (UsbDescriptorType::HidReport, hid::MOUSE_INTERFACE) => {
+                        &descriptors::MOUSE_REPORT_DESC
+                    }
+                    (UsbDescriptorType::Hid, rawhid::INTERFACE) => &descriptors::RAW_HID_DESC,
+                    (UsbDescriptorType::HidReport, rawhid::INTERFACE) => {
+                        &descriptors::RAW_REPORT_DESC
+                    }
+                    _ => {
+                        crate::heprintln!("get interface descriptor {:x} {}", value, index).ok();
+                        self.control_stall(ep);
+                        return;
+                    }
+                };
+                self.start_control_in(ep, descriptor, length);
+            }
+            _ => {
+                crate::heprintln!("rt {:x} {:?} {:x}", request_type, request, request16).ok();
+                self.control_stall(ep);
+            }
+        }
+    }
+
+    /// Class specific requests to the HID interfaces, in `index`
+    #[allow(clippy::too_many_arguments)]
+    fn hid_request<E: ControlEndpoint>(
+        &mut self,
+        ep: &mut E,
+        hid: &mut UsbHid,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) {
+        let report_type = (value >> 8) as u8;
+        let report_id = value as u8;
+        match (request_type, HidRequest::from_u8(request)) {
+            (0xa1, Some(HidRequest::GetReport)) => {
+                let mut buffer = [0; 32];
+                let size = match hid.get_report(index, report_type, report_id) {
+                    Some(report) => {
+                        buffer[..report.len()].copy_from_slice(report);
+                        report.len()
+                    }
+                    None => {
+                        self.control_stall(ep);
+                        return;
+                    }
+                };
+                self.control_in_packet(ep, &buffer[..size], length);
+            }
+            (0x21, Some(HidRequest::SetReport)) => {
+                if length == 0 {
+                    self.control_ack(ep);
+                } else {
+                    // The report follows in the data stage, see
+                    // `control_out`
+                    self.phase = ControlPhase::DataOut(ControlOut::SetReport(report_type));
+                    ep.receive();
+                }
+            }
+            (0xa1, Some(HidRequest::GetIdle)) => {
+                let idle_rate = match index {
+                    hid::KEYBOARD_INTERFACE => hid.idle_rate,
+                    _ => 0,
+                };
+                self.control_in_packet(ep, &[idle_rate], length);
+            }
+            (0x21, Some(HidRequest::SetIdle)) => {
+                // The mouse only ever reports changes anyway
+                if index == hid::KEYBOARD_INTERFACE {
+                    hid.idle_rate = (value >> 8) as u8;
+                }
+                self.control_ack(ep);
+            }
+            (0xa1, Some(HidRequest::GetProtocol)) if index == hid::KEYBOARD_INTERFACE => {
+                let protocol = hid.protocol;
+                self.control_in_packet(ep, &[protocol], length);
+            }
+            (0x21, Some(HidRequest::SetProtocol)) if index == hid::KEYBOARD_INTERFACE => {
+                hid.set_protocol(value as u8);
+                self.control_ack(ep);
+            }
+            _ => {
+                crate::heprintln!("hid rt {:x} {:x} {:x}", request_type, request, value).ok();
+                self.control_stall(ep);
+            }
+        }
+    }
+
+    /// Class specific requests to the CDC communications interface
+    fn cdc_request<E: ControlEndpoint>(
+        &mut self,
+        ep: &mut E,
+        serial: &mut UsbSerial,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        length: u16,
+    ) {
+        match (request_type, CdcRequest::from_u8(request)) {
+            (0xa1, Some(CdcRequest::GetLineCoding)) => {
+                let line_coding = serial.line_coding;
+                self.control_in_packet(ep, &line_coding, length);
+            }
+            (0x21, Some(CdcRequest::SetLineCoding)) => {
+                // See `control_out`
+                self.phase = ControlPhase::DataOut(ControlOut::SetLineCoding);
+                ep.receive();
+            }
+            (0x21, Some(CdcRequest::SetControlLineState)) => {
+                serial.set_control_line_state(value);
+                self.control_ack(ep);
+            }
+            _ => {
+                crate::heprintln!("cdc rt {:x} {:x} {:x}", request_type, request, value).ok();
+                self.control_stall(ep);
+            }
+        }
+    }
+
+    fn dfu_request<E: ControlEndpoint>(
+        &mut self,
+        ep: &mut E,
+        request_type: u8,
+        request: u8,
+        length: u16,
+    ) {
+        match (request_type, DfuRequest::from_u8(request)) {
+            (0x21, Some(DfuRequest::Detach)) => {
+                // We detach ourselves, see bitWillDetach in the
+                // descriptor
+                self.detach_pending = true;
+                self.control_ack(ep);
+            }
+            (0xa1, Some(DfuRequest::GetStatus)) => self.start_control_in(ep, &dfu::STATUS, length),
+            (0xa1, Some(DfuRequest::GetState)) => self.start_control_in(ep, &dfu::STATE, length),
+            _ => {
+                crate::heprintln!("dfu rt {:x} {:x}", request_type, request).ok();
+                self.control_stall(ep);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::endpoint::Endpoint;
+    use crate::usb::pma::PmaAllocator;
+    use std::vec::Vec;
+
+    /// Endpoint 0 as the host sees it
+    #[derive(Default)]
+    struct MockEndpoint {
+        /// The SETUP or OUT packet for the next `read`
+        rx: Vec<u8>,
+        /// The IN packet waiting for the host
+        tx: Option<Vec<u8>>,
+        /// An OUT packet would be accepted
+        receiving: bool,
+        stalled: bool,
+        address: u8,
+    }
+
+    impl ControlEndpoint for MockEndpoint {
+        fn read(&mut self, buf: &mut [u8]) -> usize {
+            let count = min(buf.len(), self.rx.len());
+            buf[..count].copy_from_slice(&self.rx[..count]);
+            count
+        }
+
+        fn start_in(&mut self, packet: &[u8]) {
+            self.tx = Some(packet.to_vec());
+            self.receiving = true;
+        }
+
+        fn continue_in(&mut self, packet: &[u8]) {
+            self.tx = Some(packet.to_vec());
+        }
+
+        fn send_status(&mut self) {
+            self.tx = Some(Vec::new());
+            self.receiving = true;
+        }
+
+        fn receive(&mut self) {
+            self.receiving = true;
+        }
+
+        fn stall(&mut self) {
+            self.stalled = true;
+        }
+
+        fn wait(&mut self) {}
+
+        fn set_address(&mut self, address: u8) {
+            self.address = address;
+        }
+    }
+
+    const SERIAL_NUMBER: [u8; SERIAL_NUMBER_LEN] = [0x32; SERIAL_NUMBER_LEN];
+
+    /// The host side of endpoint 0, checking every transfer completes
+    /// the way the spec says
+    struct Host {
+        control: Control,
+        ep: MockEndpoint,
+        hid: UsbHid,
+        serial: UsbSerial,
+    }
+
+    impl Host {
+        fn new() -> Host {
+            let mut allocator = PmaAllocator::new(6);
+            let keyboard = Endpoint::allocate(hid::KEYBOARD_ENDPOINT, &mut allocator);
+            let mouse = Endpoint::allocate(hid::MOUSE_ENDPOINT, &mut allocator);
+            let serial = Endpoint::allocate(cdc::DATA_ENDPOINT, &mut allocator);
+            let mut host = Host {
+                control: Control::new(SERIAL_NUMBER),
+                ep: MockEndpoint::default(),
+                hid: UsbHid::new(keyboard, mouse),
+                serial: UsbSerial::new(serial),
+            };
+            host.bus_reset();
+            host
+        }
+
+        fn bus_reset(&mut self) {
+            self.control.reset();
+            self.hid.reset();
+            self.serial.reset();
+            self.ep = MockEndpoint::default();
+        }
+
+        fn ctr(&mut self, transaction: Transaction) {
+            self.control
+                .ctr(transaction, &mut self.ep, &mut self.hid, &mut self.serial);
+        }
+
+        fn setup(&mut self, setup: [u8; 8]) {
+            self.ep.tx = None;
+            self.ep.receiving = false;
+            self.ep.stalled = false;
+            self.ep.rx = setup.to_vec();
+            self.ctr(Transaction::Setup);
+        }
+
+        /// A control read, returns its data stage
+        fn control_in(&mut self, setup: [u8; 8]) -> Vec<u8> {
+            self.setup(setup);
+            assert!(!self.ep.stalled, "{:02x?} stalled", setup);
+            let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+            let mut data = Vec::new();
+            loop {
+                let packet = self.ep.tx.take().expect("no IN packet");
+                assert!(packet.len() <= MAX_PACKET_SIZE);
+                data.extend_from_slice(&packet);
+                self.ctr(Transaction::In);
+                if packet.len() < MAX_PACKET_SIZE || data.len() >= length {
+                    break;
+                }
+            }
+            assert!(data.len() <= length, "{:02x?} sent too much", setup);
+            assert_eq!(self.ep.tx, None, "{:02x?} sent too much", setup);
+            // Status stage
+            assert!(self.ep.receiving);
+            self.ep.rx.clear();
+            self.ep.receiving = false;
+            self.ctr(Transaction::Out);
+            assert!(self.ep.receiving);
+            data
+        }
+
+        /// A control write, with `data` as its data stage if any
+        fn control_out(&mut self, setup: [u8; 8], data: &[u8]) {
+            self.setup(setup);
+            assert!(!self.ep.stalled, "{:02x?} stalled", setup);
+            if !data.is_empty() {
+                assert!(self.ep.receiving && self.ep.tx.is_none());
+                self.ep.rx = data.to_vec();
+                self.ctr(Transaction::Out);
+            }
+            // Status stage
+            assert_eq!(self.ep.tx.take(), Some(Vec::new()));
+            self.ctr(Transaction::In);
+        }
+
+        fn stalls(&mut self, setup: [u8; 8]) -> bool {
+            self.setup(setup);
+            self.ep.stalled
+        }
+
+        fn replay(&mut self, steps: &[Step]) {
+            for step in steps {
+                match *step {
+                    Step::In(setup, ref expected) => {
+                        assert_eq!(&self.control_in(setup)[..], &expected[..], "{:02x?}", setup)
+                    }
+                    Step::Out(setup, ref data) => self.control_out(setup, data),
+                    Step::Stall(setup) => assert!(self.stalls(setup), "{:02x?}", setup),
+                    Step::Reset => self.bus_reset(),
+                }
+            }
+        }
+    }
+
+    enum Step {
+        /// A control read, with the data the host should get
+        In([u8; 8], Vec<u8>),
+        /// A control write, with its data stage
+        Out([u8; 8], Vec<u8>),
+        /// A request the device doesn't support
+        Stall([u8; 8]),
+        Reset,
+    }
+
+    fn request(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+        let [value_lo, value_hi] = value.to_le_bytes();
+        let [index_lo, index_hi] = index.to_le_bytes();
+        let [length_lo, length_hi] = length.to_le_bytes();
+        [
+            request_type,
+            request,
+            value_lo,
+            value_hi,
+            index_lo,
+            index_hi,
+            length_lo,
+            length_hi,
+        ]
+    }
+
+    fn get_descriptor(descriptor_type: u8, index: u8, language: u16, length: u16) -> [u8; 8] {
+        request(
+            0x80,
+            0x06,
+            u16::from(descriptor_type) << 8 | u16::from(index),
+            language,
+            length,
+        )
+    }
+
+    fn get_report_descriptor(interface: u16, length: u16) -> [u8; 8] {
+        request(0x81, 0x06, 0x2200, interface, length)
+    }
+
+    fn set_address(address: u16) -> [u8; 8] {
+        request(0x00, 0x05, address, 0, 0)
+    }
+
+    const SET_CONFIGURATION: [u8; 8] = [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    fn set_idle(interface: u16) -> [u8; 8] {
+        request(0x21, 0x0a, 0, interface, 0)
+    }
+
+    fn truncated(data: &[u8], length: usize) -> Vec<u8> {
+        data[..min(data.len(), length)].to_vec()
+    }
+
+    fn string(index: u8) -> Vec<u8> {
+        match index {
+            0 => descriptors::LANG_STR.to_vec(),
+            1 => descriptors::MANUFACTURER_STR.to_vec(),
+            2 => descriptors::PRODUCT_STR.to_vec(),
+            3 => SERIAL_NUMBER.to_vec(),
+            _ => unreachable!(),
+        }
+    }
+
+    const LINE_CODING: [u8; 7] = [0x00, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x08];
+
+    fn configured(host: &Host) -> bool {
+        match host.control.device_state {
+            UsbDeviceState::Configured => true,
+            _ => false,
+        }
+    }
+
+    // The enumerations below are written by hand from the descriptor
+    // constants, approximating the order in which each host asks for
+    // things. They aren't captures of real traffic.
+
+    /// Roughly what Linux 5.x does when the keyboard is plugged in and
+    /// the serial console opened
+    #[test]
+    fn linux_enumeration() {
+        let total = descriptors::CONF_DESC.len() as u16;
+        let mut host = Host::new();
+        host.replay(&[
+            Step::In(get_descriptor(1, 0, 0, 64), descriptors::DEV_DESC.to_vec()),
+            Step::Reset,
+            Step::Out(set_address(9), Vec::new()),
+            Step::In(get_descriptor(1, 0, 0, 18), descriptors::DEV_DESC.to_vec()),
+            Step::In(
+                get_descriptor(2, 0, 0, 9),
+                descriptors::CONF_DESC[..9].to_vec(),
+            ),
+            Step::In(
+                get_descriptor(2, 0, 0, total),
+                descriptors::CONF_DESC.to_vec(),
+            ),
+            Step::In(get_descriptor(3, 0, 0, 255), string(0)),
+            Step::In(get_descriptor(3, 2, 0x0409, 255), string(2)),
+            Step::In(get_descriptor(3, 1, 0x0409, 255), string(1)),
+            Step::In(get_descriptor(3, 3, 0x0409, 255), string(3)),
+            Step::Out(SET_CONFIGURATION, Vec::new()),
+            Step::Out(set_idle(hid::KEYBOARD_INTERFACE), Vec::new()),
+            Step::In(
+                get_report_descriptor(
+                    hid::KEYBOARD_INTERFACE,
+                    descriptors::HID_REPORT_DESC.len() as u16,
+                ),
+                descriptors::HID_REPORT_DESC.to_vec(),
+            ),
+            Step::Out(set_idle(hid::MOUSE_INTERFACE), Vec::new()),
+            Step::In(
+                get_report_descriptor(
+                    hid::MOUSE_INTERFACE,
+                    descriptors::MOUSE_REPORT_DESC.len() as u16,
+                ),
+                descriptors::MOUSE_REPORT_DESC.to_vec(),
+            ),
+            Step::Out(set_idle(rawhid::INTERFACE), Vec::new()),
+            Step::In(
+                get_report_descriptor(rawhid::INTERFACE, descriptors::RAW_REPORT_DESC.len() as u16),
+                descriptors::RAW_REPORT_DESC.to_vec(),
+            ),
+            // Num Lock from the report protocol, with the report ID
+            Step::Out(
+                request(0x21, 0x09, 0x0201, hid::KEYBOARD_INTERFACE, 2),
+                vec![0x01, 0x01],
+            ),
+            // The serial console
+            Step::Out(
+                request(0x21, 0x22, 0x0003, cdc::COMM_INTERFACE, 0),
+                Vec::new(),
+            ),
+            Step::Out(
+                request(0x21, 0x20, 0, cdc::COMM_INTERFACE, 7),
+                LINE_CODING.to_vec(),
+            ),
+            Step::In(
+                request(0xa1, 0x21, 0, cdc::COMM_INTERFACE, 7),
+                LINE_CODING.to_vec(),
+            ),
+        ]);
+        assert_eq!(host.ep.address, 9);
+        assert!(configured(&host));
+        assert_eq!(host.hid.leds, 0x01);
+        assert_eq!(host.serial.line_coding, LINE_CODING);
+    }
+
+    /// Roughly macOS 10.15: only 8 bytes of the device descriptor at
+    /// first, strings read as their length and then in full, and remote
+    /// wakeup enabled before sleeping
+    #[test]
+    fn macos_enumeration() {
+        let total = descriptors::CONF_DESC.len() as u16;
+        let mut host = Host::new();
+        let mut steps = vec![
+            Step::In(
+                get_descriptor(1, 0, 0, 8),
+                descriptors::DEV_DESC[..8].to_vec(),
+            ),
+            Step::Reset,
+            Step::Out(set_address(3), Vec::new()),
+            Step::In(get_descriptor(1, 0, 0, 18), descriptors::DEV_DESC.to_vec()),
+            Step::In(
+                get_descriptor(2, 0, 0, total),
+                descriptors::CONF_DESC.to_vec(),
+            ),
+            Step::In(get_descriptor(3, 0, 0, 2), string(0)[..2].to_vec()),
+            Step::In(get_descriptor(3, 0, 0, 4), string(0)),
+        ];
+        for &index in &[2, 1, 3] {
+            let length = u16::from(string(index)[0]);
+            steps.push(Step::In(
+                get_descriptor(3, index, 0x0409, 2),
+                string(index)[..2].to_vec(),
+            ));
+            steps.push(Step::In(
+                get_descriptor(3, index, 0x0409, length),
+                string(index),
+            ));
+        }
+        steps.extend(vec![
+            Step::In(request(0x80, 0x00, 0, 0, 2), vec![0, 0]),
+            Step::Out(SET_CONFIGURATION, Vec::new()),
+            Step::In(request(0x80, 0x08, 0, 0, 1), vec![1]),
+            Step::Out(set_idle(hid::KEYBOARD_INTERFACE), Vec::new()),
+            Step::In(
+                get_report_descriptor(
+                    hid::KEYBOARD_INTERFACE,
+                    descriptors::HID_REPORT_DESC.len() as u16,
+                ),
+                descriptors::HID_REPORT_DESC.to_vec(),
+            ),
+            Step::Out(
+                request(0x21, 0x09, 0x0200, hid::KEYBOARD_INTERFACE, 1),
+                vec![0x02],
+            ),
+            // Going to sleep
+            Step::Out(request(0x00, 0x03, 0x0001, 0, 0), Vec::new()),
+            Step::In(request(0x80, 0x00, 0, 0, 2), vec![0x02, 0]),
+        ]);
+        host.replay(&steps);
+        assert_eq!(host.ep.address, 3);
+        assert!(configured(&host));
+        assert!(host.control.remote_wakeup_enabled);
+        assert_eq!(host.hid.leds, 0x02);
+    }
+
+    /// Roughly Windows 10: the whole configuration asked for with 255,
+    /// the Microsoft OS string descriptor probed, and the report
+    /// descriptor asked for with 64 bytes more than it has
+    #[test]
+    fn windows_enumeration() {
+        let mut host = Host::new();
+        host.replay(&[
+            Step::In(get_descriptor(1, 0, 0, 64), descriptors::DEV_DESC.to_vec()),
+            Step::Reset,
+            Step::Out(set_address(12), Vec::new()),
+            Step::In(get_descriptor(1, 0, 0, 18), descriptors::DEV_DESC.to_vec()),
+            Step::In(
+                get_descriptor(2, 0, 0, 255),
+                truncated(&descriptors::CONF_DESC, 255),
+            ),
+            Step::Stall(get_descriptor(3, 0xee, 0, 18)),
+            Step::In(
+                get_descriptor(6, 0, 0, 10),
+                descriptors::DEVICE_QUALIFIER.to_vec(),
+            ),
+            Step::In(get_descriptor(3, 0, 0, 255), string(0)),
+            Step::In(get_descriptor(3, 2, 0x0409, 255), string(2)),
+            Step::In(get_descriptor(3, 3, 0x0409, 255), string(3)),
+            Step::Out(SET_CONFIGURATION, Vec::new()),
+            Step::Out(set_idle(hid::KEYBOARD_INTERFACE), Vec::new()),
+            Step::In(
+                get_report_descriptor(
+                    hid::KEYBOARD_INTERFACE,
+                    descriptors::HID_REPORT_DESC.len() as u16 + 0x40,
+                ),
+                descriptors::HID_REPORT_DESC.to_vec(),
+            ),
+            Step::Out(
+                request(0x21, 0x09, 0x0201, hid::KEYBOARD_INTERFACE, 2),
+                vec![0x01, 0x00],
+            ),
+            Step::In(
+                request(0xa1, 0x21, 0, cdc::COMM_INTERFACE, 7),
+                LINE_CODING.to_vec(),
+            ),
+            Step::Out(
+                request(0x21, 0x22, 0x0001, cdc::COMM_INTERFACE, 0),
+                Vec::new(),
+            ),
+        ]);
+        assert_eq!(host.ep.address, 12);
+        assert!(configured(&host));
+        assert_eq!(host.hid.leds, 0x00);
+    }
+
+    /// 128 bytes, two full packets
+    static LONG: [u8; 2 * MAX_PACKET_SIZE] = [0x55; 2 * MAX_PACKET_SIZE];
+
+    impl Host {
+        /// Start sending `data` as the answer to a request for `length`
+        /// bytes
+        fn start_in(&mut self, data: &'static [u8], length: u16) {
+            self.setup(get_descriptor(1, 0, 0, length));
+            self.control.start_control_in(&mut self.ep, data, length);
+        }
+
+        /// Take IN packets until the device stops sending, returns their
+        /// sizes
+        fn packet_sizes(&mut self) -> Vec<usize> {
+            let mut sizes = Vec::new();
+            while let Some(packet) = self.ep.tx.take() {
+                sizes.push(packet.len());
+                self.ctr(Transaction::In);
+            }
+            sizes
+        }
+    }
+
+    #[test]
+    fn zlp_only_when_short_of_length_on_packet_boundary() {
+        assert!(Control::needs_zlp(64, 255));
+        assert!(Control::needs_zlp(128, 129));
+        assert!(!Control::needs_zlp(64, 64));
+        assert!(!Control::needs_zlp(18, 64));
+        assert!(!Control::needs_zlp(0, 64));
+    }
+
+    #[test]
+    fn multi_packet_ends_with_zlp() {
+        let mut host = Host::new();
+        host.start_in(&LONG, 255);
+        assert_eq!(host.packet_sizes(), vec![64, 64, 0]);
+    }
+
+    #[test]
+    fn multi_packet_of_exact_length_has_no_zlp() {
+        let mut host = Host::new();
+        host.start_in(&LONG, 128);
+        assert_eq!(host.packet_sizes(), vec![64, 64]);
+    }
+
+    #[test]
+    fn multi_packet_truncated_to_length() {
+        let mut host = Host::new();
+        host.start_in(&LONG, 100);
+        assert_eq!(host.packet_sizes(), vec![64, 36]);
+        host.start_in(&LONG, 64);
+        assert_eq!(host.packet_sizes(), vec![64]);
+    }
+
+    #[test]
+    fn host_ends_data_stage_early() {
+        let mut host = Host::new();
+        host.start_in(&LONG, 255);
+        assert_eq!(host.ep.tx.take().map(|packet| packet.len()), Some(64));
+        host.ctr(Transaction::In);
+        assert!(host.ep.tx.take().is_some());
+        // The status OUT instead of the next IN
+        host.ep.rx.clear();
+        host.ctr(Transaction::Out);
+        assert!(host.ep.receiving);
+        host.ctr(Transaction::In);
+        assert_eq!(host.ep.tx, None);
+    }
+
+    #[test]
+    fn setup_aborts_data_stage() {
+        let mut host = Host::new();
+        host.start_in(&LONG, 255);
+        assert!(host.ep.tx.take().is_some());
+        assert_eq!(
+            host.control_in(get_descriptor(1, 0, 0, 18)),
+            descriptors::DEV_DESC.to_vec()
+        );
+    }
+
+    #[test]
+    fn address_waits_for_status_stage() {
+        let mut host = Host::new();
+        host.setup(set_address(5));
+        assert_eq!(host.ep.tx.take(), Some(Vec::new()));
+        assert_eq!(host.ep.address, 0);
+        host.ctr(Transaction::In);
+        assert_eq!(host.ep.address, 5);
+    }
+
+    #[test]
+    fn dfu_detach_waits_for_status_stage() {
+        let mut host = Host::new();
+        host.replay(&[
+            Step::Out(set_address(5), vec![]),
+            Step::Out(request(0x00, 0x09, 1, 0, 0), vec![]),
+            Step::In(
+                request(0xa1, 0x03, 0, dfu::INTERFACE, 6),
+                dfu::STATUS.to_vec(),
+            ),
+        ]);
+        host.setup(request(0x21, 0x00, 1000, dfu::INTERFACE, 0));
+        assert_eq!(host.ep.tx.take(), Some(Vec::new()));
+        assert!(!host.control.detached);
+        host.ctr(Transaction::In);
+        assert!(host.control.detached);
+    }
+
+    #[test]
+    fn stall_is_cleared_by_next_setup() {
+        let mut host = Host::new();
+        assert!(host.stalls(request(0x80, 0x0c, 0, 0, 2)));
+        assert_eq!(
+            host.control_in(get_descriptor(1, 0, 0, 18)),
+            descriptors::DEV_DESC.to_vec()
+        );
+    }
+}
@@ -1,102 +1,278 @@
 #![cfg_attr(rustfmt, rustfmt_skip)]
 
+// VENDOR_ID, PRODUCT_ID, DEVICE_RELEASE and the string descriptors
+// other than LANG_STR, generated by build.rs. The serial number comes
+// from the chip, see `Usb::new`.
+include!(concat!(env!("OUT_DIR"), "/usb_identity.rs"));
+
+use super::builder::ReportItem::*;
+use super::builder::*;
+
 pub const DEV_DESC: [u8; 18] = [
     0x12,        // bLength
     0x01,        // bDescriptorType (Device)
     0x00, 0x02,  // bcdUSB 2.00
-    0x00,        // bDeviceClass (Use class information in the Interface Descriptors)
-    0x00,        // bDeviceSubClass
-    0x00,        // bDeviceProtocol
+    0xEF,        // bDeviceClass (Miscellaneous)
+    0x02,        // bDeviceSubClass (Common Class)
+    0x01,        // bDeviceProtocol (Interface Association Descriptor)
     0x40,        // bMaxPacketSize0 64
-    0xFF, 0xFF,  // idVendor 0xFFFF
-    0xFF, 0xFF,  // idProduct 0xFFFF
-    0x01, 0x00,  // bcdDevice 0.01
+    VENDOR_ID as u8, (VENDOR_ID >> 8) as u8,            // idVendor
+    PRODUCT_ID as u8, (PRODUCT_ID >> 8) as u8,          // idProduct
+    DEVICE_RELEASE as u8, (DEVICE_RELEASE >> 8) as u8,  // bcdDevice
     0x01,        // iManufacturer (String Index)
     0x02,        // iProduct (String Index)
     0x03,        // iSerialNumber (String Index)
     0x01,        // bNumConfigurations 1
 ];
 
-pub const CONF_DESC: [u8; 34] = [
-    0x09,        // bLength
-    0x02,        // bDescriptorType (Configuration)
-    0x22, 0x00,  // wTotalLength
-    0x01,        // bNumInterfaces
-    0x01,        // bConfigurationValue
-    0x04,        // iConfiguration (String Index)
-    0x80,        // bmAttributes
-    0xFA,        // bMaxPower 500mA
-
-    0x09,        // bLength
-    0x04,        // bDescriptorType (Interface)
-    0x00,        // bInterfaceNumber 0
-    0x00,        // bAlternateSetting
-    0x01,        // bNumEndpoints 1
-    0x03,        // bInterfaceClass
-    0x01,        // bInterfaceSubClass
-    0x01,        // bInterfaceProtocol
-    0x05,        // iInterface (String Index)
-
-    0x09,        // bLength
-    0x21,        // bDescriptorType (HID)
-    0x11, 0x01,  // bcdHID 1.11
-    0x00,        // bCountryCode
-    0x01,        // bNumDescriptors
-    0x22,        // bDescriptorType[0] (HID)
-    0x3f, 0x00,  // wDescriptorLength[0] 63
-
-    0x07,        // bLength
-    0x05,        // bDescriptorType (Endpoint)
-    0x81,        // bEndpointAddress (IN/D2H)
-    0x03,        // bmAttributes (Interrupt)
-    0x40, 0x00,  // wMaxPacketSize 64
-    0x01,        // bInterval 1 (unit depends on device speed)
+const CONFIGURATION: &[Descriptor] = &[
+    Descriptor::Configuration {
+        value: 1,
+        string: 4,
+        attributes: BUS_POWERED | REMOTE_WAKEUP,
+        max_power_ma: 500,
+    },
+
+    // Keyboard
+    Descriptor::Interface {
+        number: 0,
+        endpoints: 1,
+        class: 0x03,     // HID
+        subclass: 0x01,  // Boot Interface
+        protocol: 0x01,  // Keyboard
+        string: 5,
+    },
+    Descriptor::Hid { report_len: HID_REPORT_DESC.len() },
+    Descriptor::Endpoint { address: IN | 1, attributes: INTERRUPT, max_packet_size: 64, interval: 1 },
+
+    // Mouse
+    Descriptor::Interface {
+        number: 1,
+        endpoints: 1,
+        class: 0x03,  // HID
+        subclass: 0,
+        protocol: 0,
+        string: 0,
+    },
+    Descriptor::Hid { report_len: MOUSE_REPORT_DESC.len() },
+    Descriptor::Endpoint { address: IN | 2, attributes: INTERRUPT, max_packet_size: 4, interval: 1 },
+
+    // CDC-ACM serial console
+    Descriptor::InterfaceAssociation {
+        first_interface: 2,
+        count: 2,
+        class: 0x02,     // Communications
+        subclass: 0x02,  // Abstract Control Model
+        protocol: 0,
+    },
+    Descriptor::Interface {
+        number: 2,
+        endpoints: 1,
+        class: 0x02,     // Communications
+        subclass: 0x02,  // Abstract Control Model
+        protocol: 0,
+        string: 0,
+    },
+    Descriptor::Raw(&[
+        0x24,        // bDescriptorType (CS_INTERFACE)
+        0x00,        // bDescriptorSubtype (Header)
+        0x10, 0x01,  // bcdCDC 1.10
+    ]),
+    Descriptor::Raw(&[
+        0x24,        // bDescriptorType (CS_INTERFACE)
+        0x01,        // bDescriptorSubtype (Call Management)
+        0x00,        // bmCapabilities
+        0x03,        // bDataInterface 3
+    ]),
+    Descriptor::Raw(&[
+        0x24,        // bDescriptorType (CS_INTERFACE)
+        0x02,        // bDescriptorSubtype (Abstract Control Management)
+        0x02,        // bmCapabilities (Line Coding and Serial State)
+    ]),
+    Descriptor::Raw(&[
+        0x24,        // bDescriptorType (CS_INTERFACE)
+        0x06,        // bDescriptorSubtype (Union)
+        0x02,        // bControlInterface 2
+        0x03,        // bSubordinateInterface0 3
+    ]),
+    Descriptor::Endpoint { address: IN | 3, attributes: INTERRUPT, max_packet_size: 8, interval: 255 },
+    Descriptor::Interface {
+        number: 3,
+        endpoints: 2,
+        class: 0x0A,  // CDC Data
+        subclass: 0,
+        protocol: 0,
+        string: 0,
+    },
+    Descriptor::Endpoint { address: 4, attributes: BULK, max_packet_size: 64, interval: 0 },
+    Descriptor::Endpoint { address: IN | 4, attributes: BULK, max_packet_size: 64, interval: 0 },
+
+    // Raw HID
+    Descriptor::Interface {
+        number: 4,
+        endpoints: 2,
+        class: 0x03,  // HID
+        subclass: 0,
+        protocol: 0,
+        string: 0,
+    },
+    Descriptor::Hid { report_len: RAW_REPORT_DESC.len() },
+    Descriptor::Endpoint { address: IN | 5, attributes: INTERRUPT, max_packet_size: 32, interval: 1 },
+    Descriptor::Endpoint { address: 5, attributes: INTERRUPT, max_packet_size: 32, interval: 1 },
+
+    // DFU run-time
+    Descriptor::Interface {
+        number: 5,
+        endpoints: 0,
+        class: 0xFE,     // Application Specific
+        subclass: 0x01,  // Device Firmware Upgrade
+        protocol: 0x01,  // Run-time
+        string: 0,
+    },
+    Descriptor::Raw(&[
+        0x21,        // bDescriptorType (DFU Functional)
+        0x09,        // bmAttributes (Will Detach, Can Download)
+        0xFF, 0x00,  // wDetachTimeOut 255 ms
+        0x00, 0x08,  // wTransferSize 2048
+        0x1A, 0x01,  // bcdDFUVersion 1.1a (DfuSe)
+    ]),
 ];
 
-pub const HID_DESC: [u8; 9] = [
-    0x09,        // bLength
-    0x21,        // bDescriptorType (HID)
-    0x11, 0x01,  // bcdHID 1.11
-    0x00,        // bCountryCode
-    0x01,        // bNumDescriptors
-    0x22,        // bDescriptorType[0] (HID)
-    0x3f, 0x00,  // wDescriptorLength[0] 63
+pub const CONF_DESC: [u8; descriptors_len(CONFIGURATION)] = descriptor!(CONFIGURATION);
+
+const HID: &[Descriptor] = &[Descriptor::Hid { report_len: HID_REPORT_DESC.len() }];
+pub const HID_DESC: [u8; descriptors_len(HID)] = descriptor!(HID);
+
+const KEYBOARD_REPORT: &[ReportItem] = &[
+    UsagePage(0x01),  // Generic Desktop Ctrls
+    Usage(0x06),      // Keyboard
+    Collection(APPLICATION),
+    ReportId(1),
+    // Modifiers
+    UsagePage(0x07),  // Kbrd/Keypad
+    UsageMinimum(0xE0),
+    UsageMaximum(0xE7),
+    LogicalMinimum(0),
+    LogicalMaximum(1),
+    ReportSize(1),
+    ReportCount(8),
+    Input(DATA | VARIABLE | ABSOLUTE),
+    ReportCount(1),
+    ReportSize(8),
+    Input(CONSTANT),
+    // LEDs
+    ReportCount(5),
+    ReportSize(1),
+    UsagePage(0x08),  // LEDs
+    UsageMinimum(0x01),  // Num Lock
+    UsageMaximum(0x05),  // Kana
+    Output(DATA | VARIABLE | ABSOLUTE),
+    ReportCount(1),
+    ReportSize(3),
+    Output(CONSTANT),
+    // Boot protocol keys
+    ReportCount(6),
+    ReportSize(8),
+    LogicalMaximum(101),
+    UsagePage(0x07),  // Kbrd/Keypad
+    UsageMinimum(0x00),
+    UsageMaximum(0x65),
+    Input(DATA | ARRAY | ABSOLUTE),
+    // NKRO bitmap
+    ReportCount(104),
+    ReportSize(1),
+    LogicalMaximum(1),
+    UsageMinimum(0x00),
+    UsageMaximum(0x67),
+    Input(DATA | VARIABLE | ABSOLUTE),
+    EndCollection,
+
+    UsagePage(0x0C),  // Consumer
+    Usage(0x01),      // Consumer Control
+    Collection(APPLICATION),
+    ReportId(2),
+    LogicalMinimum(0),
+    LogicalMaximum(1023),
+    UsageMinimum(0x00),   // Unassigned
+    UsageMaximum(0x3FF),
+    ReportCount(1),
+    ReportSize(16),
+    Input(DATA | ARRAY | ABSOLUTE),
+    EndCollection,
+
+    UsagePage(0x01),  // Generic Desktop Ctrls
+    Usage(0x80),      // Sys Control
+    Collection(APPLICATION),
+    ReportId(3),
+    LogicalMinimum(0),
+    LogicalMaximum(131),
+    UsageMinimum(0x00),  // Undefined
+    UsageMaximum(0x83),  // Sys Wake Up
+    ReportCount(1),
+    ReportSize(8),
+    Input(DATA | ARRAY | ABSOLUTE),
+    EndCollection,
 ];
+pub const HID_REPORT_DESC: [u8; report_len(KEYBOARD_REPORT)] = report_descriptor!(KEYBOARD_REPORT);
 
-pub const HID_REPORT_DESC: [u8; 63] = [
-    0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
-    0x09, 0x06,        // Usage (Keyboard)
-    0xA1, 0x01,        // Collection (Application)
-    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
-    0x19, 0xE0,        //   Usage Minimum (0xE0)
-    0x29, 0xE7,        //   Usage Maximum (0xE7)
-    0x15, 0x00,        //   Logical Minimum (0)
-    0x25, 0x01,        //   Logical Maximum (1)
-    0x75, 0x01,        //   Report Size (1)
-    0x95, 0x08,        //   Report Count (8)
-    0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x95, 0x01,        //   Report Count (1)
-    0x75, 0x08,        //   Report Size (8)
-    0x81, 0x01,        //   Input (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0x95, 0x05,        //   Report Count (5)
-    0x75, 0x01,        //   Report Size (1)
-    0x05, 0x08,        //   Usage Page (LEDs)
-    0x19, 0x01,        //   Usage Minimum (Num Lock)
-    0x29, 0x05,        //   Usage Maximum (Kana)
-    0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-    0x95, 0x01,        //   Report Count (1)
-    0x75, 0x03,        //   Report Size (3)
-    0x91, 0x01,        //   Output (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-    0x95, 0x06,        //   Report Count (6)
-    0x75, 0x08,        //   Report Size (8)
-    0x15, 0x00,        //   Logical Minimum (0)
-    0x25, 0x65,        //   Logical Maximum (101)
-    0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
-    0x19, 0x00,        //   Usage Minimum (0x00)
-    0x29, 0x65,        //   Usage Maximum (0x65)
-    0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
-    0xC0,              // End Collection
+const MOUSE_HID: &[Descriptor] = &[Descriptor::Hid { report_len: MOUSE_REPORT_DESC.len() }];
+pub const MOUSE_HID_DESC: [u8; descriptors_len(MOUSE_HID)] = descriptor!(MOUSE_HID);
+
+const MOUSE_REPORT: &[ReportItem] = &[
+    UsagePage(0x01),  // Generic Desktop Ctrls
+    Usage(0x02),      // Mouse
+    Collection(APPLICATION),
+    Usage(0x01),      // Pointer
+    Collection(PHYSICAL),
+    UsagePage(0x09),  // Button
+    UsageMinimum(0x01),
+    UsageMaximum(0x05),
+    LogicalMinimum(0),
+    LogicalMaximum(1),
+    ReportCount(5),
+    ReportSize(1),
+    Input(DATA | VARIABLE | ABSOLUTE),
+    ReportCount(1),
+    ReportSize(3),
+    Input(CONSTANT),
+    UsagePage(0x01),  // Generic Desktop Ctrls
+    Usage(0x30),      // X
+    Usage(0x31),      // Y
+    Usage(0x38),      // Wheel
+    LogicalMinimum(-127),
+    LogicalMaximum(127),
+    ReportSize(8),
+    ReportCount(3),
+    Input(DATA | VARIABLE | RELATIVE),
+    EndCollection,
+    EndCollection,
+];
+pub const MOUSE_REPORT_DESC: [u8; report_len(MOUSE_REPORT)] = report_descriptor!(MOUSE_REPORT);
+
+const RAW_HID: &[Descriptor] = &[Descriptor::Hid { report_len: RAW_REPORT_DESC.len() }];
+pub const RAW_HID_DESC: [u8; descriptors_len(RAW_HID)] = descriptor!(RAW_HID);
+
+/// The usage page and usages QMK uses for its raw HID interface, so
+/// existing host tools find it
+const RAW_REPORT: &[ReportItem] = &[
+    UsagePage(0xFF60),  // Vendor Defined
+    Usage(0x61),
+    Collection(APPLICATION),
+    Usage(0x62),
+    LogicalMinimum(0),
+    LogicalMaximum(255),
+    ReportCount(32),
+    ReportSize(8),
+    Input(DATA | VARIABLE | ABSOLUTE),
+    Usage(0x63),
+    LogicalMinimum(0),
+    LogicalMaximum(255),
+    ReportCount(32),
+    ReportSize(8),
+    Output(DATA | VARIABLE | ABSOLUTE),
+    EndCollection,
 ];
+pub const RAW_REPORT_DESC: [u8; report_len(RAW_REPORT)] = report_descriptor!(RAW_REPORT);
 
 pub const DEVICE_QUALIFIER: [u8; 10] = [
     0x0A,        // bLength
@@ -115,93 +291,354 @@ pub const LANG_STR: [u8; 4] = [
     0x09, 0x04, // English - US
 ];
 
-pub const MANUFACTURER_STR: [u8; 38] = [
-    0x26, 0x03, //
-    0x52, 0x00, // R
-    0x75, 0x00, // u
-    0x73, 0x00, // s
-    0x74, 0x00, // t
-    0x79, 0x00, // y
-    0x20, 0x00, //  
-    0x4d, 0x00, // M
-    0x61, 0x00, // a
-    0x6e, 0x00, // n
-    0x75, 0x00, // u
-    0x66, 0x00, // f
-    0x61, 0x00, // a
-    0x63, 0x00, // c
-    0x74, 0x00, // t
-    0x75, 0x00, // u
-    0x72, 0x00, // r
-    0x65, 0x00, // e
-    0x72, 0x00, // r
-];
+#[cfg(test)]
+mod tests {
+    /// The descriptors as they were written by hand before the
+    /// builder
+    mod hand_written {
+        pub const CONF_DESC: [u8; 175] = [
+            0x09,        // bLength
+            0x02,        // bDescriptorType (Configuration)
+            0xAF, 0x00,  // wTotalLength 175
+            0x06,        // bNumInterfaces
+            0x01,        // bConfigurationValue
+            0x04,        // iConfiguration (String Index)
+            0xA0,        // bmAttributes Remote Wakeup
+            0xFA,        // bMaxPower 500mA
 
-pub const PRODUCT_STR: [u8; 28] = [
-    0x1c, 0x03, //
-    0x52, 0x00, // R
-    0x75, 0x00, // u
-    0x73, 0x00, // s
-    0x74, 0x00, // t
-    0x79, 0x00, // y
-    0x20, 0x00, //  
-    0x50, 0x00, // P
-    0x72, 0x00, // r
-    0x6f, 0x00, // o
-    0x64, 0x00, // d
-    0x75, 0x00, // u
-    0x63, 0x00, // c
-    0x74, 0x00, // t
-];
+            0x09,        // bLength
+            0x04,        // bDescriptorType (Interface)
+            0x00,        // bInterfaceNumber 0
+            0x00,        // bAlternateSetting
+            0x01,        // bNumEndpoints 1
+            0x03,        // bInterfaceClass
+            0x01,        // bInterfaceSubClass
+            0x01,        // bInterfaceProtocol
+            0x05,        // iInterface (String Index)
 
-pub const SERIAL_NUMBER_STR: [u8; 14] = [
-    0x0e, 0x03, //
-    0x31, 0x00, // 1
-    0x32, 0x00, // 2
-    0x33, 0x00, // 3
-    0x41, 0x00, // A
-    0x42, 0x00, // B
-    0x43, 0x00, // C
-];
+            0x09,        // bLength
+            0x21,        // bDescriptorType (HID)
+            0x11, 0x01,  // bcdHID 1.11
+            0x00,        // bCountryCode
+            0x01,        // bNumDescriptors
+            0x22,        // bDescriptorType[0] (HID)
+            0x7C, 0x00,  // wDescriptorLength[0] 124
 
-pub const CONF_STR: [u8; 40] = [
-    0x28, 0x03, //
-    0x52, 0x00, // R
-    0x75, 0x00, // u
-    0x73, 0x00, // s
-    0x74, 0x00, // t
-    0x79, 0x00, // y
-    0x20, 0x00, //
-    0x43, 0x00, // C
-    0x6f, 0x00, // o
-    0x6e, 0x00, // n
-    0x66, 0x00, // f
-    0x69, 0x00, // i
-    0x67, 0x00, // g
-    0x75, 0x00, // u
-    0x72, 0x00, // r
-    0x61, 0x00, // a
-    0x74, 0x00, // t
-    0x69, 0x00, // i
-    0x6f, 0x00, // o
-    0x6e, 0x00, // n
-];
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x81,        // bEndpointAddress (IN/D2H)
+            0x03,        // bmAttributes (Interrupt)
+            0x40, 0x00,  // wMaxPacketSize 64
+            0x01,        // bInterval 1 (unit depends on device speed)
 
-pub const INTERFACE_STR: [u8; 32] = [
-    0x20, 0x03, //
-    0x52, 0x00, // R
-    0x75, 0x00, // u
-    0x73, 0x00, // s
-    0x74, 0x00, // t
-    0x79, 0x00, // y
-    0x20, 0x00, //
-    0x49, 0x00, // I
-    0x6e, 0x00, // n
-    0x74, 0x00, // t
-    0x65, 0x00, // e
-    0x72, 0x00, // r
-    0x66, 0x00, // f
-    0x61, 0x00, // a
-    0x63, 0x00, // c
-    0x65, 0x00, // e
-];
+            0x09,        // bLength
+            0x04,        // bDescriptorType (Interface)
+            0x01,        // bInterfaceNumber 1
+            0x00,        // bAlternateSetting
+            0x01,        // bNumEndpoints 1
+            0x03,        // bInterfaceClass
+            0x00,        // bInterfaceSubClass
+            0x00,        // bInterfaceProtocol
+            0x00,        // iInterface (String Index)
+
+            0x09,        // bLength
+            0x21,        // bDescriptorType (HID)
+            0x11, 0x01,  // bcdHID 1.11
+            0x00,        // bCountryCode
+            0x01,        // bNumDescriptors
+            0x22,        // bDescriptorType[0] (HID)
+            0x34, 0x00,  // wDescriptorLength[0] 52
+
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x82,        // bEndpointAddress (IN/D2H)
+            0x03,        // bmAttributes (Interrupt)
+            0x04, 0x00,  // wMaxPacketSize 4
+            0x01,        // bInterval 1 (unit depends on device speed)
+
+            0x08,        // bLength
+            0x0B,        // bDescriptorType (Interface Association)
+            0x02,        // bFirstInterface 2
+            0x02,        // bInterfaceCount 2
+            0x02,        // bFunctionClass (Communications)
+            0x02,        // bFunctionSubClass (Abstract Control Model)
+            0x00,        // bFunctionProtocol
+            0x00,        // iFunction (String Index)
+
+            0x09,        // bLength
+            0x04,        // bDescriptorType (Interface)
+            0x02,        // bInterfaceNumber 2
+            0x00,        // bAlternateSetting
+            0x01,        // bNumEndpoints 1
+            0x02,        // bInterfaceClass (Communications)
+            0x02,        // bInterfaceSubClass (Abstract Control Model)
+            0x00,        // bInterfaceProtocol
+            0x00,        // iInterface (String Index)
+
+            0x05,        // bLength
+            0x24,        // bDescriptorType (CS_INTERFACE)
+            0x00,        // bDescriptorSubtype (Header)
+            0x10, 0x01,  // bcdCDC 1.10
+
+            0x05,        // bLength
+            0x24,        // bDescriptorType (CS_INTERFACE)
+            0x01,        // bDescriptorSubtype (Call Management)
+            0x00,        // bmCapabilities
+            0x03,        // bDataInterface 3
+
+            0x04,        // bLength
+            0x24,        // bDescriptorType (CS_INTERFACE)
+            0x02,        // bDescriptorSubtype (Abstract Control Management)
+            0x02,        // bmCapabilities (Line Coding and Serial State)
+
+            0x05,        // bLength
+            0x24,        // bDescriptorType (CS_INTERFACE)
+            0x06,        // bDescriptorSubtype (Union)
+            0x02,        // bControlInterface 2
+            0x03,        // bSubordinateInterface0 3
+
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x83,        // bEndpointAddress (IN/D2H)
+            0x03,        // bmAttributes (Interrupt)
+            0x08, 0x00,  // wMaxPacketSize 8
+            0xFF,        // bInterval 255 (unit depends on device speed)
+
+            0x09,        // bLength
+            0x04,        // bDescriptorType (Interface)
+            0x03,        // bInterfaceNumber 3
+            0x00,        // bAlternateSetting
+            0x02,        // bNumEndpoints 2
+            0x0A,        // bInterfaceClass (CDC Data)
+            0x00,        // bInterfaceSubClass
+            0x00,        // bInterfaceProtocol
+            0x00,        // iInterface (String Index)
+
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x04,        // bEndpointAddress (OUT/H2D)
+            0x02,        // bmAttributes (Bulk)
+            0x40, 0x00,  // wMaxPacketSize 64
+            0x00,        // bInterval 0
+
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x84,        // bEndpointAddress (IN/D2H)
+            0x02,        // bmAttributes (Bulk)
+            0x40, 0x00,  // wMaxPacketSize 64
+            0x00,        // bInterval 0
+
+            0x09,        // bLength
+            0x04,        // bDescriptorType (Interface)
+            0x04,        // bInterfaceNumber 4
+            0x00,        // bAlternateSetting
+            0x02,        // bNumEndpoints 2
+            0x03,        // bInterfaceClass
+            0x00,        // bInterfaceSubClass
+            0x00,        // bInterfaceProtocol
+            0x00,        // iInterface (String Index)
+
+            0x09,        // bLength
+            0x21,        // bDescriptorType (HID)
+            0x11, 0x01,  // bcdHID 1.11
+            0x00,        // bCountryCode
+            0x01,        // bNumDescriptors
+            0x22,        // bDescriptorType[0] (HID)
+            0x22, 0x00,  // wDescriptorLength[0] 34
+
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x85,        // bEndpointAddress (IN/D2H)
+            0x03,        // bmAttributes (Interrupt)
+            0x20, 0x00,  // wMaxPacketSize 32
+            0x01,        // bInterval 1 (unit depends on device speed)
+
+            0x07,        // bLength
+            0x05,        // bDescriptorType (Endpoint)
+            0x05,        // bEndpointAddress (OUT/H2D)
+            0x03,        // bmAttributes (Interrupt)
+            0x20, 0x00,  // wMaxPacketSize 32
+            0x01,        // bInterval 1 (unit depends on device speed)
+
+            0x09,        // bLength
+            0x04,        // bDescriptorType (Interface)
+            0x05,        // bInterfaceNumber 5
+            0x00,        // bAlternateSetting
+            0x00,        // bNumEndpoints 0
+            0xFE,        // bInterfaceClass (Application Specific)
+            0x01,        // bInterfaceSubClass (Device Firmware Upgrade)
+            0x01,        // bInterfaceProtocol (Run-time)
+            0x00,        // iInterface (String Index)
+
+            0x09,        // bLength
+            0x21,        // bDescriptorType (DFU Functional)
+            0x09,        // bmAttributes (Will Detach, Can Download)
+            0xFF, 0x00,  // wDetachTimeOut 255 ms
+            0x00, 0x08,  // wTransferSize 2048
+            0x1A, 0x01,  // bcdDFUVersion 1.1a (DfuSe)
+        ];
+
+        pub const HID_DESC: [u8; 9] = [
+            0x09,        // bLength
+            0x21,        // bDescriptorType (HID)
+            0x11, 0x01,  // bcdHID 1.11
+            0x00,        // bCountryCode
+            0x01,        // bNumDescriptors
+            0x22,        // bDescriptorType[0] (HID)
+            0x7C, 0x00,  // wDescriptorLength[0] 124
+        ];
+
+        pub const HID_REPORT_DESC: [u8; 124] = [
+            0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+            0x09, 0x06,        // Usage (Keyboard)
+            0xA1, 0x01,        // Collection (Application)
+            0x85, 0x01,        //   Report ID (1)
+            0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
+            0x19, 0xE0,        //   Usage Minimum (0xE0)
+            0x29, 0xE7,        //   Usage Maximum (0xE7)
+            0x15, 0x00,        //   Logical Minimum (0)
+            0x25, 0x01,        //   Logical Maximum (1)
+            0x75, 0x01,        //   Report Size (1)
+            0x95, 0x08,        //   Report Count (8)
+            0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x95, 0x01,        //   Report Count (1)
+            0x75, 0x08,        //   Report Size (8)
+            0x81, 0x01,        //   Input (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x95, 0x05,        //   Report Count (5)
+            0x75, 0x01,        //   Report Size (1)
+            0x05, 0x08,        //   Usage Page (LEDs)
+            0x19, 0x01,        //   Usage Minimum (Num Lock)
+            0x29, 0x05,        //   Usage Maximum (Kana)
+            0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x95, 0x01,        //   Report Count (1)
+            0x75, 0x03,        //   Report Size (3)
+            0x91, 0x01,        //   Output (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x95, 0x06,        //   Report Count (6)
+            0x75, 0x08,        //   Report Size (8)
+            0x25, 0x65,        //   Logical Maximum (101)
+            0x05, 0x07,        //   Usage Page (Kbrd/Keypad)
+            0x19, 0x00,        //   Usage Minimum (0x00)
+            0x29, 0x65,        //   Usage Maximum (0x65)
+            0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x95, 0x68,        //   Report Count (104)
+            0x75, 0x01,        //   Report Size (1)
+            0x25, 0x01,        //   Logical Maximum (1)
+            0x19, 0x00,        //   Usage Minimum (0x00)
+            0x29, 0x67,        //   Usage Maximum (0x67)
+            0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0xC0,              // End Collection
+            0x05, 0x0C,        // Usage Page (Consumer)
+            0x09, 0x01,        // Usage (Consumer Control)
+            0xA1, 0x01,        // Collection (Application)
+            0x85, 0x02,        //   Report ID (2)
+            0x15, 0x00,        //   Logical Minimum (0)
+            0x26, 0xFF, 0x03,  //   Logical Maximum (1023)
+            0x19, 0x00,        //   Usage Minimum (Unassigned)
+            0x2A, 0xFF, 0x03,  //   Usage Maximum (0x03FF)
+            0x95, 0x01,        //   Report Count (1)
+            0x75, 0x10,        //   Report Size (16)
+            0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0xC0,              // End Collection
+            0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+            0x09, 0x80,        // Usage (Sys Control)
+            0xA1, 0x01,        // Collection (Application)
+            0x85, 0x03,        //   Report ID (3)
+            0x15, 0x00,        //   Logical Minimum (0)
+            0x26, 0x83, 0x00,  //   Logical Maximum (131)
+            0x19, 0x00,        //   Usage Minimum (Undefined)
+            0x29, 0x83,        //   Usage Maximum (Sys Wake Up)
+            0x95, 0x01,        //   Report Count (1)
+            0x75, 0x08,        //   Report Size (8)
+            0x81, 0x00,        //   Input (Data,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0xC0,              // End Collection
+        ];
+
+        pub const MOUSE_HID_DESC: [u8; 9] = [
+            0x09,        // bLength
+            0x21,        // bDescriptorType (HID)
+            0x11, 0x01,  // bcdHID 1.11
+            0x00,        // bCountryCode
+            0x01,        // bNumDescriptors
+            0x22,        // bDescriptorType[0] (HID)
+            0x34, 0x00,  // wDescriptorLength[0] 52
+        ];
+
+        pub const MOUSE_REPORT_DESC: [u8; 52] = [
+            0x05, 0x01,        // Usage Page (Generic Desktop Ctrls)
+            0x09, 0x02,        // Usage (Mouse)
+            0xA1, 0x01,        // Collection (Application)
+            0x09, 0x01,        //   Usage (Pointer)
+            0xA1, 0x00,        //   Collection (Physical)
+            0x05, 0x09,        //     Usage Page (Button)
+            0x19, 0x01,        //     Usage Minimum (0x01)
+            0x29, 0x05,        //     Usage Maximum (0x05)
+            0x15, 0x00,        //     Logical Minimum (0)
+            0x25, 0x01,        //     Logical Maximum (1)
+            0x95, 0x05,        //     Report Count (5)
+            0x75, 0x01,        //     Report Size (1)
+            0x81, 0x02,        //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x95, 0x01,        //     Report Count (1)
+            0x75, 0x03,        //     Report Size (3)
+            0x81, 0x01,        //     Input (Const,Array,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x05, 0x01,        //     Usage Page (Generic Desktop Ctrls)
+            0x09, 0x30,        //     Usage (X)
+            0x09, 0x31,        //     Usage (Y)
+            0x09, 0x38,        //     Usage (Wheel)
+            0x15, 0x81,        //     Logical Minimum (-127)
+            0x25, 0x7F,        //     Logical Maximum (127)
+            0x75, 0x08,        //     Report Size (8)
+            0x95, 0x03,        //     Report Count (3)
+            0x81, 0x06,        //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+            0xC0,              //   End Collection
+            0xC0,              // End Collection
+        ];
+
+        pub const RAW_HID_DESC: [u8; 9] = [
+            0x09,        // bLength
+            0x21,        // bDescriptorType (HID)
+            0x11, 0x01,  // bcdHID 1.11
+            0x00,        // bCountryCode
+            0x01,        // bNumDescriptors
+            0x22,        // bDescriptorType[0] (HID)
+            0x22, 0x00,  // wDescriptorLength[0] 34
+        ];
+
+        pub const RAW_REPORT_DESC: [u8; 34] = [
+            0x06, 0x60, 0xFF,  // Usage Page (Vendor Defined 0xFF60)
+            0x09, 0x61,        // Usage (0x61)
+            0xA1, 0x01,        // Collection (Application)
+            0x09, 0x62,        //   Usage (0x62)
+            0x15, 0x00,        //   Logical Minimum (0)
+            0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+            0x95, 0x20,        //   Report Count (32)
+            0x75, 0x08,        //   Report Size (8)
+            0x81, 0x02,        //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x09, 0x63,        //   Usage (0x63)
+            0x15, 0x00,        //   Logical Minimum (0)
+            0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+            0x95, 0x20,        //   Report Count (32)
+            0x75, 0x08,        //   Report Size (8)
+            0x91, 0x02,        //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0xC0,              // End Collection
+        ];
+    }
+
+    #[test]
+    fn configuration() {
+        assert_eq!(&super::CONF_DESC[..], &hand_written::CONF_DESC[..]);
+    }
+
+    #[test]
+    fn hid() {
+        assert_eq!(super::HID_DESC, hand_written::HID_DESC);
+        assert_eq!(super::MOUSE_HID_DESC, hand_written::MOUSE_HID_DESC);
+        assert_eq!(super::RAW_HID_DESC, hand_written::RAW_HID_DESC);
+    }
+
+    #[test]
+    fn reports() {
+        assert_eq!(&super::HID_REPORT_DESC[..], &hand_written::HID_REPORT_DESC[..]);
+        assert_eq!(&super::MOUSE_REPORT_DESC[..], &hand_written::MOUSE_REPORT_DESC[..]);
+        assert_eq!(&super::RAW_REPORT_DESC[..], &hand_written::RAW_REPORT_DESC[..]);
+    }
+}
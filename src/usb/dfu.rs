@@ -0,0 +1,13 @@
+/// Interface number from `descriptors::CONF_DESC`
+pub const INTERFACE: u16 = 5;
+
+/// bState while the firmware runs, as opposed to the bootloader's
+/// dfuIDLE and friends
+const APP_IDLE: u8 = 0;
+
+/// Response to DFU_GETSTATUS: bStatus OK, bwPollTimeout 0, bState and
+/// no iString
+pub const STATUS: [u8; 6] = [0x00, 0x00, 0x00, 0x00, APP_IDLE, 0x00];
+
+/// Response to DFU_GETSTATE
+pub const STATE: [u8; 1] = [APP_IDLE];
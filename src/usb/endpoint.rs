@@ -0,0 +1,108 @@
+use crate::usb::pma::{PmaAllocator, PMA};
+use crate::usb::usb_ext::{UsbEpExt, EP_RX_VALID, EP_TX_NAK};
+use stm32l1::stm32l151::USB;
+
+/// Values of the EP_TYPE field
+#[allow(dead_code)]
+#[derive(Copy, Clone)]
+pub enum EndpointType {
+    Bulk = 0b00,
+    Control = 0b01,
+    Isochronous = 0b10,
+    Interrupt = 0b11,
+}
+
+/// Everything needed to set up an endpoint, see `Endpoint::allocate`
+#[derive(Copy, Clone)]
+pub struct EndpointConfig {
+    pub number: u8,
+    pub ep_type: EndpointType,
+    /// Size of the buffer for IN packets, 0 if there's no IN direction
+    pub tx_size: usize,
+    /// Size of the buffer for OUT packets, 0 if there's no OUT
+    /// direction. Sizes above 62 have to be multiples of 32.
+    pub rx_size: usize,
+}
+
+/// An endpoint with its buffers in the PMA
+#[derive(Copy, Clone)]
+pub struct Endpoint {
+    config: EndpointConfig,
+    tx_buffer: usize,
+    rx_buffer: usize,
+}
+
+impl Endpoint {
+    pub fn allocate(config: EndpointConfig, allocator: &mut PmaAllocator) -> Endpoint {
+        assert!(config.rx_size <= 62 || config.rx_size % 32 == 0);
+        Endpoint {
+            config,
+            tx_buffer: allocator.alloc(config.tx_size),
+            rx_buffer: allocator.alloc(config.rx_size),
+        }
+    }
+
+    pub fn register<'a>(&self, usb: &'a USB) -> &'a dyn UsbEpExt {
+        match self.config.number {
+            0 => &usb.ep0r,
+            1 => &usb.ep1r,
+            2 => &usb.ep2r,
+            3 => &usb.ep3r,
+            4 => &usb.ep4r,
+            5 => &usb.ep5r,
+            6 => &usb.ep6r,
+            7 => &usb.ep7r,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Write the buffer descriptor and enable the endpoint, which has
+    /// to be done again after every bus reset. IN directions start out
+    /// NAK until there's something to send, OUT directions are ready
+    /// to receive.
+    pub fn reset(&self, usb: &USB, pma: &PMA) {
+        let number = self.config.number as usize;
+        pma.set_tx_addr(number, self.tx_buffer);
+        pma.set_tx_count(number, 0);
+        pma.set_rx_addr(number, self.rx_buffer);
+        pma.set_rx_size(number, self.config.rx_size);
+
+        let mut status = 0;
+        if self.config.tx_size != 0 {
+            status |= EP_TX_NAK;
+        }
+        if self.config.rx_size != 0 {
+            status |= EP_RX_VALID;
+        }
+        self.register(usb).configure(
+            self.config.ep_type as u32,
+            u32::from(self.config.number),
+            status,
+        );
+    }
+
+    /// Put `data` into the transmit buffer, it goes out once the
+    /// transmit direction is made valid
+    pub fn write(&self, pma: &PMA, data: &[u8]) {
+        assert!(data.len() <= self.config.tx_size);
+        pma.write_buffer_u8(self.tx_buffer, data);
+        pma.set_tx_count(self.config.number as usize, data.len());
+    }
+
+    /// `write` and send `data` with the next IN token
+    pub fn send(&self, usb: &USB, pma: &PMA, data: &[u8]) {
+        self.write(pma, data);
+        self.register(usb).toggle_tx_out();
+    }
+
+    /// Copy the packet that was received into `buf` and make the buffer
+    /// ready for the next one. Returns how many bytes were copied, the
+    /// rest of a packet larger than `buf` is dropped.
+    pub fn read(&self, pma: &PMA, buf: &mut [u8]) -> usize {
+        let number = self.config.number as usize;
+        let count = pma.rx_count(number).min(buf.len());
+        pma.read_buffer_u8(self.rx_buffer, &mut buf[..count]);
+        pma.set_rx_size(number, self.config.rx_size);
+        count
+    }
+}
@@ -1,28 +1,388 @@
+use crate::hidreport::{
+    ConsumerReport, HidReport, MouseReport, NkroReport, SystemReport, NKRO_BITMAP_LEN,
+    REPORT_ID_CONSUMER, REPORT_ID_KEYBOARD, REPORT_ID_SYSTEM,
+};
+use crate::usb::endpoint::{Endpoint, EndpointConfig, EndpointType};
 use crate::usb::pma::PMA;
-use crate::usb::usb_ext::UsbEpExt;
+use heapless::consts::U8;
+use heapless::spsc::Queue;
 use stm32l1::stm32l151::USB;
 
+/// Values of the HID GET_PROTOCOL/SET_PROTOCOL requests
+pub const PROTOCOL_BOOT: u8 = 0;
+pub const PROTOCOL_REPORT: u8 = 1;
+
+/// Report types in the high byte of wValue of GET_REPORT/SET_REPORT
+pub const REPORT_TYPE_INPUT: u8 = 1;
+pub const REPORT_TYPE_OUTPUT: u8 = 2;
+
+/// Interface numbers from `descriptors::CONF_DESC`
+pub const KEYBOARD_INTERFACE: u16 = 0;
+pub const MOUSE_INTERFACE: u16 = 1;
+
+/// SET_IDLE durations are in units of 4 ms
+const IDLE_UNIT_MS: u32 = 4;
+/// The idle rate recommended for keyboards by the HID spec, 500 ms
+const DEFAULT_IDLE_RATE: u8 = 125;
+
+/// The endpoints as in `descriptors::CONF_DESC`
+pub const KEYBOARD_ENDPOINT: EndpointConfig = EndpointConfig {
+    number: 1,
+    ep_type: EndpointType::Interrupt,
+    tx_size: 64,
+    rx_size: 0,
+};
+pub const MOUSE_ENDPOINT: EndpointConfig = EndpointConfig {
+    number: 2,
+    ep_type: EndpointType::Interrupt,
+    tx_size: 4,
+    rx_size: 0,
+};
+
+/// A report waiting for the keyboard endpoint
+#[derive(Clone, Copy)]
+enum QueuedReport {
+    Keyboard(HidReport, NkroReport),
+    Consumer(ConsumerReport),
+    System(SystemReport),
+}
+
 pub struct UsbHid {
-    pub report: [u8; 8],
+    endpoint: Endpoint,
+    mouse_endpoint: Endpoint,
+    // The newest of each report, whether it was sent yet or not
+    /// Sent while the host has selected the boot protocol
+    pub boot_report: HidReport,
+    pub report: NkroReport,
+    pub consumer_report: ConsumerReport,
+    pub system_report: SystemReport,
+    /// Reports not sent yet, oldest first. They share the keyboard
+    /// endpoint, and every one gets its turn so that a key pressed and
+    /// released before the first of them was sent still reaches the
+    /// host. Eight are enough for a macro typing while the host skips a
+    /// few polls.
+    reports: Queue<QueuedReport, U8>,
+    // The newest report of each kind didn't fit into `reports`, it's
+    // queued as soon as there's room so that the host ends up with the
+    // keys actually held
+    keyboard_unqueued: bool,
+    consumer_unqueued: bool,
+    system_unqueued: bool,
     pub protocol: u8,
+    /// Repeat the unchanged keyboard report every `idle_rate * 4` ms,
+    /// 0 to only send it on changes
+    pub idle_rate: u8,
+    /// `clock::now_ms()` when the keyboard report was last sent
+    report_sent_at: u32,
+    /// A report is on its way to the host on the keyboard endpoint
+    busy: bool,
+    /// Last LED output report from the host
+    pub leds: u8,
+    /// A mouse report is on its way to the host
+    mouse_busy: bool,
+    /// Mouse reports waiting for the one on its way, oldest first, each
+    /// with different buttons than the one before
+    mouse_reports: Queue<MouseReport, U8>,
+    /// The newest mouse report waiting, later movement with the same
+    /// buttons is added to it
+    mouse_pending: Option<MouseReport>,
 }
 
 impl UsbHid {
-    pub fn new() -> UsbHid {
-        UsbHid {
-            report: [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-            protocol: 0,
+    pub fn new(endpoint: Endpoint, mouse_endpoint: Endpoint) -> UsbHid {
+        let mut hid = UsbHid {
+            endpoint,
+            mouse_endpoint,
+            boot_report: HidReport::default(),
+            report: NkroReport::new(HidReport::default(), [0; NKRO_BITMAP_LEN]),
+            consumer_report: ConsumerReport::new(0),
+            system_report: SystemReport::new(0),
+            reports: Queue::new(),
+            keyboard_unqueued: false,
+            consumer_unqueued: false,
+            system_unqueued: false,
+            // Devices have to start out in report protocol, hosts that
+            // want boot protocol explicitly ask for it
+            protocol: PROTOCOL_REPORT,
+            idle_rate: DEFAULT_IDLE_RATE,
+            report_sent_at: 0,
+            busy: false,
+            leds: 0,
+            mouse_busy: false,
+            mouse_reports: Queue::new(),
+            mouse_pending: None,
+        };
+        hid.queue_keyboard_report();
+        hid
+    }
+
+    /// Back to the defaults after a bus reset. Reports still queued are
+    /// dropped, only the keys held now matter to the host.
+    pub fn reset(&mut self) {
+        *self = UsbHid {
+            boot_report: self.boot_report,
+            report: self.report,
+            reports: Queue::new(),
+            ..UsbHid::new(self.endpoint, self.mouse_endpoint)
+        };
+        self.queue_keyboard_report();
+    }
+
+    fn queue_keyboard_report(&mut self) {
+        let report = QueuedReport::Keyboard(self.boot_report, self.report);
+        self.keyboard_unqueued = self.reports.enqueue(report).is_err();
+    }
+
+    fn queue_consumer_report(&mut self) {
+        let report = QueuedReport::Consumer(self.consumer_report);
+        self.consumer_unqueued = self.reports.enqueue(report).is_err();
+    }
+
+    fn queue_system_report(&mut self) {
+        let report = QueuedReport::System(self.system_report);
+        self.system_unqueued = self.reports.enqueue(report).is_err();
+    }
+
+    /// Queue the reports that didn't fit before, now that there may be
+    /// room
+    fn queue_unqueued(&mut self) {
+        if self.keyboard_unqueued {
+            self.queue_keyboard_report();
+        }
+        if self.consumer_unqueued {
+            self.queue_consumer_report();
+        }
+        if self.system_unqueued {
+            self.queue_system_report();
         }
     }
 
-    pub fn ctr(&mut self, usb: &mut USB, pma: &mut PMA) {
+    /// Answer to GET_REPORT, `None` if there's no such report
+    pub fn get_report(&self, interface: u16, report_type: u8, report_id: u8) -> Option<&[u8]> {
+        match (interface, report_type, report_id) {
+            (KEYBOARD_INTERFACE, REPORT_TYPE_INPUT, 0) if self.protocol == PROTOCOL_BOOT => {
+                Some(self.boot_report.as_bytes())
+            }
+            (KEYBOARD_INTERFACE, REPORT_TYPE_INPUT, REPORT_ID_KEYBOARD) => {
+                Some(self.report.as_bytes())
+            }
+            (KEYBOARD_INTERFACE, REPORT_TYPE_INPUT, REPORT_ID_CONSUMER) => {
+                Some(self.consumer_report.as_bytes())
+            }
+            (KEYBOARD_INTERFACE, REPORT_TYPE_INPUT, REPORT_ID_SYSTEM) => {
+                Some(self.system_report.as_bytes())
+            }
+            (KEYBOARD_INTERFACE, REPORT_TYPE_OUTPUT, _) => Some(core::slice::from_ref(&self.leds)),
+            _ => None,
+        }
+    }
+
+    /// Store the data stage of SET_REPORT
+    pub fn set_report(&mut self, report_type: u8, data: &[u8]) {
+        if report_type != REPORT_TYPE_OUTPUT {
+            return;
+        }
+        // The report ID comes first in the report protocol
+        let leds = match data {
+            [REPORT_ID_KEYBOARD, leds] => Some(*leds),
+            [leds] => Some(*leds),
+            _ => None,
+        };
+        if let Some(leds) = leds {
+            self.leds = leds;
+        }
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.protocol = protocol;
+        self.queue_keyboard_report();
+    }
+
+    /// Queue the keyboard report, unless it's the same as the last one
+    pub fn update_report(&mut self, boot_report: &HidReport, report: &NkroReport) {
+        if boot_report.as_bytes() != self.boot_report.as_bytes()
+            || report.as_bytes() != self.report.as_bytes()
+        {
+            self.boot_report = *boot_report;
+            self.report = *report;
+            self.queue_keyboard_report();
+        }
+    }
+
+    pub fn update_consumer_report(&mut self, usage: u16) {
+        self.consumer_report = ConsumerReport::new(usage);
+        self.queue_consumer_report();
+    }
+
+    pub fn update_system_report(&mut self, usage: u8) {
+        self.system_report = SystemReport::new(usage);
+        self.queue_system_report();
+    }
+
+    /// Start sending the oldest queued report on the keyboard endpoint,
+    /// unless it's still busy
+    pub fn send(&mut self, usb: &mut USB, pma: &mut PMA, now: u32) {
+        if self.busy {
+            return;
+        }
+
+        let idle_ms = u32::from(self.idle_rate) * IDLE_UNIT_MS;
+        if self.reports.is_empty()
+            && self.idle_rate != 0
+            && now.wrapping_sub(self.report_sent_at) >= idle_ms
+        {
+            self.queue_keyboard_report();
+        }
+
+        // Boot protocol hosts only know about the keyboard report
+        let report_protocol = self.protocol == PROTOCOL_REPORT;
+        while let Some(queued) = self.reports.dequeue() {
+            self.queue_unqueued();
+            let report = match &queued {
+                QueuedReport::Keyboard(_, report) if report_protocol => report.as_bytes(),
+                QueuedReport::Keyboard(boot_report, _) => boot_report.as_bytes(),
+                QueuedReport::Consumer(report) if report_protocol => report.as_bytes(),
+                QueuedReport::System(report) if report_protocol => report.as_bytes(),
+                _ => continue,
+            };
+            if let QueuedReport::Keyboard(..) = queued {
+                self.report_sent_at = now;
+            }
+            self.endpoint.send(usb, pma, report);
+            self.busy = true;
+            return;
+        }
+    }
+
+    pub fn ctr(&mut self, usb: &mut USB, pma: &mut PMA, now: u32) {
         if !usb.istr.read().dir().bit_is_set() {
-            pma.write_buffer_u8(0x100, &self.report);
-            pma.pma_area.set_u16(10, self.report.len() as u16);
-            usb.ep1r.toggle_tx_out();
-        //TODO: stall?
+            self.busy = false;
+            self.send(usb, pma, now);
+            if !self.busy {
+                // Only clear the interrupt, the endpoint stays NAK
+                self.endpoint.register(usb).toggle(0, 0, 0);
+            }
+        } else {
+            // The endpoint never accepts OUT transactions, so there's
+            // nothing to do but clear the interrupt
+            crate::heprintln!("unexpected OUT on the keyboard endpoint").ok();
+            self.endpoint.register(usb).toggle(0, 0, 0);
+        }
+    }
+
+    pub fn send_mouse_report(&mut self, usb: &mut USB, pma: &mut PMA, report: &MouseReport) {
+        if self.mouse_busy {
+            self.queue_mouse_report(report);
         } else {
-            panic!()
+            self.write_mouse_report(usb, pma, report);
+        }
+    }
+
+    /// Keep `report` until the endpoint is free again
+    fn queue_mouse_report(&mut self, report: &MouseReport) {
+        let mut report = *report;
+        if let Some(pending) = self.mouse_pending.take() {
+            // A click has to reach the host as a report of its own
+            let queued = pending.buttons != report.buttons
+                && match self.mouse_reports.enqueue(pending) {
+                    Ok(()) => true,
+                    Err(_) => {
+                        crate::heprintln!("mouse reports full, dropped buttons").ok();
+                        false
+                    }
+                };
+            if !queued {
+                // Movement is relative, so add up everything that has
+                // to wait instead of dropping it
+                report.x = report.x.saturating_add(pending.x);
+                report.y = report.y.saturating_add(pending.y);
+                report.wheel = report.wheel.saturating_add(pending.wheel);
+            }
+        }
+        self.mouse_pending = Some(report);
+    }
+
+    /// Take out the oldest mouse report waiting
+    fn next_mouse_report(&mut self) -> Option<MouseReport> {
+        self.mouse_reports
+            .dequeue()
+            .or_else(|| self.mouse_pending.take())
+    }
+
+    pub fn mouse_ctr(&mut self, usb: &mut USB, pma: &mut PMA) {
+        match self.next_mouse_report() {
+            Some(report) => self.write_mouse_report(usb, pma, &report),
+            None => {
+                self.mouse_busy = false;
+                // Only clear the interrupt, the endpoint stays NAK
+                self.mouse_endpoint.register(usb).toggle(0, 0, 0);
+            }
+        }
+    }
+
+    fn write_mouse_report(&mut self, usb: &mut USB, pma: &mut PMA, report: &MouseReport) {
+        self.mouse_endpoint.send(usb, pma, report.as_bytes());
+        self.mouse_busy = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb::pma::PmaAllocator;
+    use std::vec::Vec;
+
+    fn hid() -> UsbHid {
+        let mut allocator = PmaAllocator::new(6);
+        let keyboard = Endpoint::allocate(KEYBOARD_ENDPOINT, &mut allocator);
+        let mouse = Endpoint::allocate(MOUSE_ENDPOINT, &mut allocator);
+        UsbHid::new(keyboard, mouse)
+    }
+
+    fn mouse(buttons: u8, x: i8) -> MouseReport {
+        MouseReport {
+            buttons,
+            x,
+            ..MouseReport::default()
+        }
+    }
+
+    /// Buttons and x of the reports waiting
+    fn waiting(hid: &mut UsbHid) -> Vec<(u8, i8)> {
+        let mut reports = Vec::new();
+        while let Some(report) = hid.next_mouse_report() {
+            reports.push((report.buttons, report.x));
+        }
+        reports
+    }
+
+    #[test]
+    fn movement_adds_up() {
+        let mut hid = hid();
+        hid.queue_mouse_report(&mouse(0, 3));
+        hid.queue_mouse_report(&mouse(0, 4));
+        assert_eq!(waiting(&mut hid), vec![(0, 7)]);
+    }
+
+    #[test]
+    fn click_while_busy() {
+        let mut hid = hid();
+        hid.queue_mouse_report(&mouse(0, 3));
+        hid.queue_mouse_report(&mouse(1, 0));
+        hid.queue_mouse_report(&mouse(0, 0));
+        hid.queue_mouse_report(&mouse(0, 2));
+        assert_eq!(waiting(&mut hid), vec![(0, 3), (1, 0), (0, 2)]);
+    }
+
+    #[test]
+    fn full_queue_keeps_movement() {
+        let mut hid = hid();
+        for i in 0..10 {
+            hid.queue_mouse_report(&mouse(i % 2, 1));
         }
+        let reports = waiting(&mut hid);
+        assert_eq!(reports.len(), 9);
+        assert_eq!(reports.iter().map(|&(_, x)| i32::from(x)).sum::<i32>(), 10);
+        assert_eq!(reports.last(), Some(&(1, 2)));
     }
 }
@@ -1,27 +1,57 @@
+#[macro_use]
+pub mod builder;
+pub mod cdc;
 pub mod constants;
+pub mod control;
 pub mod descriptors;
+pub mod dfu;
+pub mod endpoint;
 pub mod hid;
 pub mod pma;
+pub mod rawhid;
 pub mod usb_ext;
 
-use core::cmp::min;
-
 use stm32l1::stm32l151;
 
-use self::constants::{UsbDescriptorType, UsbDeviceState, UsbRequest};
-use self::pma::PMA;
+use self::cdc::{ConsoleLine, UsbSerial};
+use self::constants::UsbDescriptorType;
+use self::control::{Control, ControlEndpoint, Transaction, MAX_PACKET_SIZE, SERIAL_NUMBER_LEN};
+use self::endpoint::{Endpoint, EndpointConfig, EndpointType};
+use self::pma::{PmaAllocator, PMA};
+use self::rawhid::{RawReport, UsbRawHid};
 use self::usb_ext::UsbEpExt;
-use crate::hidreport::HidReport;
+use crate::bootloader;
+use crate::clock;
+use crate::hidreport::{HidReport, MouseReport, NkroReport};
+use crate::uid;
 use crate::usb::hid::UsbHid;
 
-const MAX_PACKET_SIZE: u32 = 64;
+const CONTROL_ENDPOINT: EndpointConfig = EndpointConfig {
+    number: 0,
+    ep_type: EndpointType::Control,
+    tx_size: MAX_PACKET_SIZE,
+    rx_size: MAX_PACKET_SIZE,
+};
+
+/// Endpoints are numbered from 0 without gaps, each has an entry in
+/// the buffer descriptor table
+const ENDPOINT_COUNT: usize = 6;
+
+/// How long to signal resume for after `remote_wakeup`, the spec
+/// allows 1 to 15 ms
+pub const REMOTE_WAKEUP_MS: u32 = 10;
 
 pub struct Usb {
     usb: stm32l151::USB,
-    pending_daddr: u8,
     pma: &'static mut PMA,
+    /// All endpoints, to set them up again after a bus reset
+    endpoints: [Endpoint; ENDPOINT_COUNT],
+    control: Control,
     hid: UsbHid,
-    device_state: UsbDeviceState,
+    serial: UsbSerial,
+    raw_hid: UsbRawHid,
+    /// The host suspended the bus
+    suspended: bool,
 }
 
 impl Usb {
@@ -43,10 +73,10 @@ impl Usb {
             w.ctrm().set_bit()
              .errm().set_bit()
              .pmaovrm().set_bit()
-             //.wkupm().set_bit()
-             //.suspm().set_bit()
+             .wkupm().set_bit()
+             .suspm().set_bit()
              //.esofm().set_bit()
-             //.sofm().set_bit()
+             .sofm().set_bit()
              .resetm().set_bit()
         });
         usb.btable.reset();
@@ -56,19 +86,103 @@ impl Usb {
 
         syscfg.pmc.modify(|_, w| w.usb_pu().set_bit());
 
-        let hid = hid::UsbHid::new();
+        let mut allocator = PmaAllocator::new(ENDPOINT_COUNT);
+        let endpoints = [
+            Endpoint::allocate(CONTROL_ENDPOINT, &mut allocator),
+            Endpoint::allocate(hid::KEYBOARD_ENDPOINT, &mut allocator),
+            Endpoint::allocate(hid::MOUSE_ENDPOINT, &mut allocator),
+            Endpoint::allocate(cdc::NOTIFICATION_ENDPOINT, &mut allocator),
+            Endpoint::allocate(cdc::DATA_ENDPOINT, &mut allocator),
+            Endpoint::allocate(rawhid::ENDPOINT, &mut allocator),
+        ];
+        let hid = hid::UsbHid::new(endpoints[1], endpoints[2]);
+        let serial = UsbSerial::new(endpoints[4]);
+        let raw_hid = UsbRawHid::new(endpoints[5]);
 
         Usb {
             usb,
-            pending_daddr: 0,
             pma,
+            endpoints,
+            control: Control::new(serial_number_descriptor()),
             hid,
-            device_state: UsbDeviceState::Disconnected,
+            serial,
+            raw_hid,
+            suspended: false,
         }
     }
 
-    pub fn update_report(&mut self, report: &HidReport) {
-        self.hid.report[..].clone_from_slice(report.as_bytes());
+    pub fn update_report(&mut self, boot_report: &HidReport, report: &NkroReport) {
+        self.hid.update_report(boot_report, report);
+        self.hid.send(&mut self.usb, &mut self.pma, clock::now_ms());
+    }
+
+    pub fn update_consumer_report(&mut self, usage: u16) {
+        self.hid.update_consumer_report(usage);
+        self.hid.send(&mut self.usb, &mut self.pma, clock::now_ms());
+    }
+
+    pub fn update_system_report(&mut self, usage: u8) {
+        self.hid.update_system_report(usage);
+        self.hid.send(&mut self.usb, &mut self.pma, clock::now_ms());
+    }
+
+    pub fn send_mouse_report(&mut self, report: &MouseReport) {
+        self.hid
+            .send_mouse_report(&mut self.usb, &mut self.pma, report);
+    }
+
+    /// Num/Caps/Scroll Lock state from the host, see
+    /// `hidreport::LED_CAPS_LOCK` and friends
+    pub fn host_leds(&self) -> u8 {
+        self.hid.leds
+    }
+
+    /// A command typed into the serial console, if there's a new one
+    pub fn take_console_line(&mut self) -> Option<ConsoleLine> {
+        self.serial.take_line(&self.usb)
+    }
+
+    /// Output to the serial console, whatever doesn't fit into its
+    /// queue is dropped
+    pub fn console_write(&mut self, data: &[u8]) {
+        self.serial.write(&self.usb, &self.pma, data);
+    }
+
+    /// Send `debug::LOG` to the serial console, followed by `then`
+    pub fn console_log(&mut self, then: &'static [u8]) {
+        self.serial.write_log(&self.usb, &self.pma, then);
+    }
+
+    /// A request on the raw HID interface, if there's a new one. The
+    /// next one is only accepted after `send_raw_hid_response`.
+    pub fn take_raw_hid_request(&mut self) -> Option<RawReport> {
+        self.raw_hid.take_request()
+    }
+
+    pub fn send_raw_hid_response(&mut self, response: &RawReport) {
+        self.raw_hid.send_response(&self.usb, &self.pma, response);
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Start waking up the host if it's suspended and allowed us to.
+    /// Returns whether it did, in which case `end_remote_wakeup` has
+    /// to be called `REMOTE_WAKEUP_MS` later.
+    pub fn remote_wakeup(&mut self) -> bool {
+        if !self.suspended || !self.control.remote_wakeup_enabled {
+            return false;
+        }
+        self.usb
+            .cntr
+            .modify(|_, w| w.fsusp().clear_bit().resume().set_bit());
+        true
+    }
+
+    pub fn end_remote_wakeup(&mut self) {
+        self.usb.cntr.modify(|_, w| w.resume().clear_bit());
+        self.suspended = false;
     }
 
     pub fn interrupt(&mut self) {
@@ -77,10 +191,24 @@ impl Usb {
             self.usb.istr.modify(|_, w| w.reset().clear_bit());
             self.reset();
         }
+        if istr.wkup().bit_is_set() {
+            self.usb.istr.modify(|_, w| w.wkup().clear_bit());
+            self.usb.cntr.modify(|_, w| w.fsusp().clear_bit());
+            self.suspended = false;
+        }
+        if istr.susp().bit_is_set() {
+            self.usb.istr.modify(|_, w| w.susp().clear_bit());
+            self.usb.cntr.modify(|_, w| w.fsusp().set_bit());
+            self.suspended = true;
+        }
 
-        self.usb
-            .istr
-            .modify(|_, w| w.susp().clear_bit().sof().clear_bit().esof().clear_bit());
+        if istr.sof().bit_is_set() {
+            self.usb.istr.modify(|_, w| w.sof().clear_bit());
+            // Once per frame, for the idle rate
+            self.hid.send(&mut self.usb, &mut self.pma, clock::now_ms());
+        }
+
+        self.usb.istr.modify(|_, w| w.esof().clear_bit());
         let istr = self.usb.istr.read();
         if istr.ctr().bit_is_set() {
             self.usb.istr.modify(|_, w| w.ctr().clear_bit());
@@ -91,230 +219,114 @@ impl Usb {
                     self.ctr();
                 }
                 1 => {
-                    self.hid.ctr(&mut self.usb, &mut self.pma);
+                    self.hid.ctr(&mut self.usb, &mut self.pma, clock::now_ms());
+                }
+                2 => {
+                    self.hid.mouse_ctr(&mut self.usb, &mut self.pma);
+                }
+                4 => {
+                    self.serial.ctr(&self.usb, &self.pma);
+                }
+                5 => {
+                    self.raw_hid.ctr(&self.usb, &self.pma);
+                }
+                // The CDC notification endpoint is never armed and the others
+                // are never enabled, so this can't really happen
+                _ => {
+                    crate::heprintln!("ctr on endpoint {}", endpoint).ok();
                 }
-                _ => panic!(),
             }
         }
     }
 
     fn reset(&mut self) {
-        self.pma.pma_area.set_u16(0, 0x40);
-        self.pma.pma_area.set_u16(2, 0x0);
-        self.pma.pma_area.set_u16(4, 0x20);
-        self.pma
-            .pma_area
-            .set_u16(6, (0x8000 | ((MAX_PACKET_SIZE / 32) - 1) << 10) as u16);
-        self.pma.pma_area.set_u16(8, 0x100);
-        self.pma.pma_area.set_u16(10, 0x0);
-
-        self.pma.write_buffer_u8(0x100, &self.hid.report);
-        self.pma.pma_area.set_u16(10, 5);
-
-        self.usb.ep0r.modify(|_, w| {
-            w.ep_type()
-                .bits(0b01)
-                .stat_tx()
-                .bits(0b10)
-                .stat_rx()
-                .bits(0b11)
-        });
+        for endpoint in &self.endpoints {
+            endpoint.reset(&self.usb, &self.pma);
+        }
 
-        self.usb.ep1r.modify(|_, w| {
-            w.ep_type()
-                .bits(0b11)
-                .stat_tx()
-                .bits(0b11)
-                .stat_rx()
-                .bits(0b10)
-                .ea()
-                .bits(0b1)
-        });
+        self.hid.reset();
+        self.serial.reset();
+        self.raw_hid.reset();
 
         self.usb.daddr.write(|w| w.ef().set_bit());
 
-        self.device_state = UsbDeviceState::Default;
+        self.control.reset();
+        self.suspended = false;
     }
 
     fn ctr(&mut self) {
-        if self.usb.istr.read().dir().bit_is_set() {
-            self.rx()
+        let transaction = if !self.usb.istr.read().dir().bit_is_set() {
+            Transaction::In
+        } else if self.usb.ep0r.read().setup().bit_is_set() {
+            Transaction::Setup
         } else {
-            self.tx()
+            Transaction::Out
+        };
+        let mut endpoint = ControlRegisters {
+            usb: &self.usb,
+            pma: &self.pma,
+            endpoint: self.endpoints[0],
+        };
+        self.control
+            .ctr(transaction, &mut endpoint, &mut self.hid, &mut self.serial);
+        if self.control.detached {
+            bootloader::reboot_to_dfu();
         }
+        // SET_CONTROL_LINE_STATE may have greeted a new terminal
+        self.serial.send(&self.usb, &self.pma);
     }
+}
 
-    fn tx(&mut self) {
-        if self.pending_daddr != 0 {
-            self.usb
-                .daddr
-                .modify(|_, w| w.add().bits(self.pending_daddr));
-            self.device_state = UsbDeviceState::Addressed;
-        } else {
-            self.pma.pma_area.set_u16(6, 0);
-        }
+/// Endpoint 0 through the USB registers and the PMA
+struct ControlRegisters<'a> {
+    usb: &'a stm32l151::USB,
+    pma: &'a PMA,
+    endpoint: Endpoint,
+}
+
+impl<'a> ControlEndpoint for ControlRegisters<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.endpoint.read(self.pma, buf)
+    }
 
+    fn start_in(&mut self, packet: &[u8]) {
+        self.endpoint.write(self.pma, packet);
+        self.usb.ep0r.toggle_out();
+    }
+
+    fn continue_in(&mut self, packet: &[u8]) {
+        self.endpoint.write(self.pma, packet);
         self.usb.ep0r.toggle_tx_out();
     }
 
-    fn get_device_descriptor(&mut self, value: u16, length: u16) {
-        let descriptor_type = UsbDescriptorType::from((value >> 8) as u8);
-        let index = (value & 0xff) as u8;
-        let descriptor: Option<&[u8]> = match descriptor_type {
-            UsbDescriptorType::Configuration => Some(&descriptors::CONF_DESC),
-            UsbDescriptorType::Device => Some(&descriptors::DEV_DESC),
-            UsbDescriptorType::DeviceQualifier => Some(&descriptors::DEVICE_QUALIFIER),
-            UsbDescriptorType::StringDesc => match index {
-                0 => Some(&descriptors::LANG_STR),
-                1 => Some(&descriptors::MANUFACTURER_STR),
-                2 => Some(&descriptors::PRODUCT_STR),
-                3 => Some(&descriptors::SERIAL_NUMBER_STR),
-                4 => Some(&descriptors::CONF_STR),
-                5 => Some(&descriptors::INTERFACE_STR),
-                _ => None,
-            },
-            UsbDescriptorType::Debug => None,
-            _ => {
-                crate::heprintln!("get descriptor {:x}", value).ok();
-                None
-            }
-        };
-        match descriptor {
-            Some(bytes) => {
-                self.pma.write_buffer_u8(0x40, bytes);
-                self.pma
-                    .pma_area
-                    .set_u16(2, min(length, bytes.len() as u16));
-                self.usb.ep0r.toggle_out();
-            }
-            None => self.usb.ep0r.toggle_tx_stall(),
-        }
+    fn send_status(&mut self) {
+        self.endpoint.write(self.pma, &[]);
+        self.usb.ep0r.toggle_0();
     }
 
-    fn rx(&mut self) {
-        let request16 = self.pma.pma_area.get_u16(0x20);
-        let value = self.pma.pma_area.get_u16(0x22);
-        //let index = self.pma.pma_area.get_u16(0x24);
-        let length = self.pma.pma_area.get_u16(0x26);
-
-        self.pma
-            .pma_area
-            .set_u16(6, (0x8000 | ((MAX_PACKET_SIZE / 32) - 1) << 10) as u16);
-
-        let request = UsbRequest::from(((request16 & 0xff00) >> 8) as u8);
-        let request_type = (request16 & 0xff) as u8;
-        match (request_type, request) {
-            (0x00, UsbRequest::GetStatus) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x80, UsbRequest::GetStatus) => {
-                self.pma.pma_area.set_u16(0x40, 0);
-                self.pma.pma_area.set_u16(2, 2);
-                self.usb.ep0r.toggle_out();
-            }
-            (0x00, UsbRequest::ClearFeature) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x00, UsbRequest::SetFeature) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x00, UsbRequest::SetAddress) => {
-                self.pending_daddr = value as u8;
-                self.usb.ep0r.toggle_0();
-            }
-            (0x80, UsbRequest::GetDescriptor) => {
-                self.get_device_descriptor(value, length);
-            }
-            (0x00, UsbRequest::SetDescriptor) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x80, UsbRequest::GetConfiguration) => {
-                self.pma.pma_area.set_u16(0x40, 1);
-                self.pma.pma_area.set_u16(2, 1);
-                self.usb.ep0r.toggle_0();
-            }
-            (0x00, UsbRequest::SetConfiguration) => {
-                self.pma.pma_area.set_u16(0x40, 0);
-                self.pma.pma_area.set_u16(2, 0);
-                self.usb.ep0r.toggle_0();
-                self.device_state = UsbDeviceState::Configured;
-            }
+    fn receive(&mut self) {
+        self.usb.ep0r.toggle_rx();
+    }
 
-            (0x81, UsbRequest::GetStatus) => {
-                self.pma.pma_area.set_u16(0x40, 0);
-                self.pma.pma_area.set_u16(2, 2);
-                self.usb.ep0r.toggle_out();
-            }
-            (0x01, UsbRequest::ClearFeature) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x01, UsbRequest::SetFeature) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x01, UsbRequest::GetInterface) => {
-                self.pma.pma_area.set_u16(0x40, 0);
-                self.pma.pma_area.set_u16(2, 1);
-                self.usb.ep0r.toggle_0();
-            }
-            (0x01, UsbRequest::SetInterface) => {
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            (0x81, UsbRequest::GetDescriptor) => {
-                let descriptor_type = UsbDescriptorType::from((value >> 8) as u8);
-                match descriptor_type {
-                    UsbDescriptorType::Hid => {
-                        self.pma.write_buffer_u8(0x40, &descriptors::HID_DESC);
-                        self.pma
-                            .pma_area
-                            .set_u16(2, min(length, descriptors::HID_DESC.len() as u16));
-                        self.usb.ep0r.toggle_out();
-                    }
-                    UsbDescriptorType::HidReport => {
-                        self.pma
-                            .write_buffer_u8(0x40, &descriptors::HID_REPORT_DESC);
-                        self.pma
-                            .pma_area
-                            .set_u16(2, min(length, descriptors::HID_REPORT_DESC.len() as u16));
-                        self.usb.ep0r.toggle_out();
-                    }
-                    _ => {
-                        crate::heprintln!("{:x}", value).ok();
-                        panic!();
-                    }
-                }
-            }
-            (0x21, UsbRequest::GetInterface) => {
-                self.pma.pma_area.set_u16(2, 0);
-                self.usb.ep0r.toggle_out();
-            }
-            (0x21, UsbRequest::SetInterface) => {
-                // actually hid set protocol 0xb
-                self.hid.protocol = value as u8;
-                self.usb.ep0r.toggle_0();
-            }
-            (0x21, UsbRequest::SetConfiguration) => {
-                // TODO: is this correct?
-                self.pma.pma_area.set_u16(2, 0);
-                self.usb.ep0r.toggle_0();
-            }
-            (0xa1, UsbRequest::SetFeature) => {
-                // this is actually hid get_protocol (3)
-                // boot protocol
-                self.pma.pma_area.set_u16(0x40, self.hid.protocol.into());
-                self.pma.pma_area.set_u16(2, 1);
-                self.usb.ep0r.toggle_out();
-            }
-            (0xa1, UsbRequest::Two) => {
-                // actually set_idle
-                //self.pma.pma_area.set_u16(0x40, 0);
-                //self.pma.pma_area.set_u16(2, 2);
-                self.usb.ep0r.toggle_tx_stall();
-            }
-            _ => {
-                // TODO get descriptor f00rt 82 GetStatus 82
-                crate::heprintln!("rt {:x} {:?} {:x}", request_type, request, request16).ok();
-                panic!();
-            }
-        }
+    fn stall(&mut self) {
+        self.usb.ep0r.toggle_tx_stall();
+    }
+
+    fn wait(&mut self) {
+        self.usb.ep0r.toggle(0, 0, 0);
+    }
+
+    fn set_address(&mut self, address: u8) {
+        self.usb.daddr.modify(|_, w| w.add().bits(address));
+    }
+}
+
+fn serial_number_descriptor() -> [u8; SERIAL_NUMBER_LEN] {
+    let mut descriptor = [0; SERIAL_NUMBER_LEN];
+    descriptor[0] = SERIAL_NUMBER_LEN as u8;
+    descriptor[1] = UsbDescriptorType::StringDesc as u8;
+    for (i, &digit) in uid::unique_id_hex().iter().enumerate() {
+        descriptor[2 + i * 2] = digit;
     }
+    descriptor
 }
@@ -4,7 +4,12 @@ use vcell::VolatileCell;
 
 // TODO: make this take-able? or at least move into the main usb part
 pub const PMA: Peripheral<PMA> = unsafe { Peripheral::new(0x4000_6000) };
-//const BTABLE: usize = 0;
+
+/// Size of the PMA in bytes, as addressed by the USB peripheral
+pub const PMA_SIZE: usize = 512;
+/// The buffer descriptor table is at the start of the PMA, see
+/// `Usb::new`, with one entry per endpoint
+const BTABLE_ENTRY_SIZE: usize = 8;
 
 pub struct PMA {
     pub pma_area: PMA_Area,
@@ -44,6 +49,13 @@ impl PMA_Area {
         self.words[offset].set(val);
     }
 
+    pub fn read_buffer_u8(&self, base: usize, buf: &mut [u8]) {
+        for (ofs, v) in buf.iter_mut().enumerate() {
+            let word = self.get_u16((base + ofs) & !1);
+            *v = (word >> (8 * (ofs & 1))) as u8;
+        }
+    }
+
     pub fn write_buffer_u8(&self, base: usize, buf: &[u8]) {
         let mut last: u16 = 0;
         let mut off: usize = 0;
@@ -63,3 +75,55 @@ impl PMA_Area {
         }
     }
 }
+
+/// Accessors for the buffer descriptor table entry of each endpoint
+impl PMA_Area {
+    pub fn set_tx_addr(&self, endpoint: usize, addr: usize) {
+        self.set_u16(endpoint * BTABLE_ENTRY_SIZE, addr as u16);
+    }
+
+    pub fn set_tx_count(&self, endpoint: usize, count: usize) {
+        self.set_u16(endpoint * BTABLE_ENTRY_SIZE + 2, count as u16);
+    }
+
+    pub fn set_rx_addr(&self, endpoint: usize, addr: usize) {
+        self.set_u16(endpoint * BTABLE_ENTRY_SIZE + 4, addr as u16);
+    }
+
+    /// Set the size of the receive buffer, which also resets the count
+    /// of received bytes. Sizes above 62 have to be multiples of 32.
+    pub fn set_rx_size(&self, endpoint: usize, size: usize) {
+        let blocks = if size > 62 {
+            0x8000 | ((size / 32 - 1) << 10)
+        } else {
+            (size / 2) << 10
+        };
+        self.set_u16(endpoint * BTABLE_ENTRY_SIZE + 6, blocks as u16);
+    }
+
+    pub fn rx_count(&self, endpoint: usize) -> usize {
+        (self.get_u16(endpoint * BTABLE_ENTRY_SIZE + 6) & 0x3ff) as usize
+    }
+}
+
+/// Hands out the PMA behind the buffer descriptor table
+pub struct PmaAllocator {
+    next: usize,
+}
+
+impl PmaAllocator {
+    pub fn new(endpoints: usize) -> PmaAllocator {
+        PmaAllocator {
+            next: endpoints * BTABLE_ENTRY_SIZE,
+        }
+    }
+
+    /// PMA address of a new buffer of `size` bytes
+    pub fn alloc(&mut self, size: usize) -> usize {
+        let addr = self.next;
+        // Buffers have to start on 16 bit boundaries
+        self.next += (size + 1) & !1;
+        assert!(self.next <= PMA_SIZE, "PMA exhausted");
+        addr
+    }
+}
@@ -0,0 +1,67 @@
+use crate::usb::endpoint::{Endpoint, EndpointConfig, EndpointType};
+use crate::usb::pma::PMA;
+use stm32l1::stm32l151::USB;
+
+/// Interface number from `descriptors::CONF_DESC`
+pub const INTERFACE: u16 = 4;
+
+/// Reports in both directions, without report ID
+pub const REPORT_SIZE: usize = 32;
+
+pub const ENDPOINT: EndpointConfig = EndpointConfig {
+    number: 5,
+    ep_type: EndpointType::Interrupt,
+    tx_size: REPORT_SIZE,
+    rx_size: REPORT_SIZE,
+};
+
+pub type RawReport = [u8; REPORT_SIZE];
+
+/// The vendor defined HID interface, carrying one request from the
+/// host and one response from us at a time, see `command::execute`
+pub struct UsbRawHid {
+    endpoint: Endpoint,
+    /// Received, but not yet taken by `take_request`. No more requests
+    /// are accepted until the response is sent.
+    request: Option<RawReport>,
+}
+
+impl UsbRawHid {
+    pub fn new(endpoint: Endpoint) -> UsbRawHid {
+        UsbRawHid {
+            endpoint,
+            request: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.request = None;
+    }
+
+    pub fn take_request(&mut self) -> Option<RawReport> {
+        self.request.take()
+    }
+
+    /// Send the response and accept the next request
+    pub fn send_response(&mut self, usb: &USB, pma: &PMA, response: &RawReport) {
+        self.endpoint.write(pma, response);
+        let register = self.endpoint.register(usb);
+        register.toggle_tx_keep_ctr();
+        register.toggle_rx_keep_ctr();
+    }
+
+    pub fn ctr(&mut self, usb: &USB, pma: &PMA) {
+        let register = self.endpoint.register(usb);
+        if register.ctr_tx() {
+            // Nothing to do, the next response only comes after the next
+            // request
+            register.clear_ctr_tx();
+        }
+        if register.ctr_rx() {
+            register.clear_ctr_rx();
+            let mut request = [0; REPORT_SIZE];
+            self.endpoint.read(pma, &mut request);
+            self.request = Some(request);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use stm32l1::stm32l151::usb::{EP0R, EP1R};
+use stm32l1::stm32l151::usb::{EP0R, EP1R, EP2R, EP3R, EP4R, EP5R, EP6R, EP7R};
 
 pub trait UsbEpExt {
     fn toggle_tx_out(&self);
@@ -6,6 +6,36 @@ pub trait UsbEpExt {
     fn toggle_out(&self);
     fn toggle_0(&self);
     fn toggle(&self, mask: u32, val: u32, flags: u32);
+    /// Set type, address and the state of both directions, for use
+    /// after a bus reset
+    fn configure(&self, ep_type: u32, address: u32, status: u32);
+    /// A transaction completed in the OUT direction
+    fn ctr_rx(&self) -> bool;
+    /// A transaction completed in the IN direction
+    fn ctr_tx(&self) -> bool;
+
+    /// Accept the next OUT packet, leaving the transmit side alone
+    fn toggle_rx(&self) {
+        self.toggle(EP_RX_MASK, EP_RX_VALID, 0)
+    }
+
+    fn clear_ctr_rx(&self) {
+        self.toggle(0, 0, EP_CTR_TX)
+    }
+
+    fn clear_ctr_tx(&self) {
+        self.toggle(0, 0, EP_CTR_RX)
+    }
+
+    /// Like `toggle_tx_out` and `toggle_rx`, but without clearing a CTR
+    /// flag of the other direction, for endpoints used in both
+    fn toggle_tx_keep_ctr(&self) {
+        self.toggle(EP_TX_MASK, EP_TX_VALID, EP_CTR_RX | EP_CTR_TX)
+    }
+
+    fn toggle_rx_keep_ctr(&self) {
+        self.toggle(EP_RX_MASK, EP_RX_VALID, EP_CTR_RX | EP_CTR_TX)
+    }
 }
 
 const EP_MASK: u32 = 0x0F0F;
@@ -13,53 +43,77 @@ const EP_TX_MASK: u32 = 0x0030;
 const EP_RX_MASK: u32 = 0x3000;
 const EP_TX_RX_MASK: u32 = EP_TX_MASK | EP_RX_MASK;
 
-const EP_TX_VALID: u32 = 0x0030;
-const EP_RX_VALID: u32 = 0x3000;
+pub const EP_TX_NAK: u32 = 0x0020;
+pub const EP_TX_VALID: u32 = 0x0030;
+pub const EP_RX_VALID: u32 = 0x3000;
 const EP_TX_RX_VALID: u32 = EP_TX_VALID | EP_RX_VALID;
 
 const EP_TX_STALL: u32 = 0x0010;
+/// Writing 1 leaves these alone, 0 clears them
+const EP_CTR_RX: u32 = 0x8000;
+const EP_CTR_TX: u32 = 0x0080;
 const EP_STATUS_OUT: u32 = 0x0100;
 
-impl UsbEpExt for EP0R {
-    fn toggle_tx_stall(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
-    }
+const EP_TYPE_SHIFT: u32 = 9;
 
-    fn toggle_tx_out(&self) {
-        self.toggle(EP_TX_MASK, EP_TX_VALID, EP_STATUS_OUT)
-    }
+/// `$tx_flags` are set along with a valid transmit status, only the
+/// control endpoint wants `EP_STATUS_OUT` there. On the other types
+/// the same bit means double buffering.
+macro_rules! impl_usb_ep_ext {
+    ($($register:ident: $tx_flags:expr,)*) => {
+        $(
+            impl UsbEpExt for $register {
+                fn toggle_tx_stall(&self) {
+                    self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
+                }
 
-    fn toggle_out(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
-    }
+                fn toggle_tx_out(&self) {
+                    self.toggle(EP_TX_MASK, EP_TX_VALID, $tx_flags)
+                }
 
-    fn toggle_0(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
-    }
+                fn toggle_out(&self) {
+                    self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
+                }
 
-    fn toggle(&self, mask: u32, val: u32, flags: u32) {
-        self.modify(|r, w| unsafe { w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags) })
-    }
-}
+                fn toggle_0(&self) {
+                    self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
+                }
 
-impl UsbEpExt for EP1R {
-    fn toggle_tx_stall(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
-    }
+                fn toggle(&self, mask: u32, val: u32, flags: u32) {
+                    self.modify(|r, w| unsafe {
+                        w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags)
+                    })
+                }
 
-    fn toggle_tx_out(&self) {
-        self.toggle(EP_TX_MASK, EP_TX_VALID, 0)
-    }
+                fn ctr_rx(&self) -> bool {
+                    self.read().bits() & EP_CTR_RX != 0
+                }
 
-    fn toggle_out(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
-    }
+                fn ctr_tx(&self) -> bool {
+                    self.read().bits() & EP_CTR_TX != 0
+                }
 
-    fn toggle_0(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
-    }
+                fn configure(&self, ep_type: u32, address: u32, status: u32) {
+                    self.modify(|r, w| unsafe {
+                        w.bits(
+                            ((r.bits() & EP_TX_RX_MASK) ^ status)
+                                | (ep_type << EP_TYPE_SHIFT)
+                                | address,
+                        )
+                    })
+                }
+            }
+        )*
+    };
+}
 
-    fn toggle(&self, mask: u32, val: u32, flags: u32) {
-        self.modify(|r, w| unsafe { w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags) })
-    }
+impl_usb_ep_ext! {
+    EP0R: EP_STATUS_OUT,
+    EP1R: 0,
+    EP2R: 0,
+    EP3R: 0,
+    EP4R: 0,
+    EP5R: 0,
+    EP6R: 0,
+    EP7R: 0,
 }
@@ -0,0 +1,406 @@
+use crate::action::Action;
+use crate::bootloader;
+use crate::clock;
+use crate::debug::UnwrapLog;
+use crate::eeprom::{self, Eeprom, LAYOUT_OPTIONS, MACROS_SIZE};
+use crate::keyboard::Keyboard;
+use crate::keycodes::{consumer, KeyCode, SystemControl};
+use crate::keymatrix::{COLUMNS, ROWS};
+use crate::layout::LAYERS;
+use crate::macros::MACRO_COUNT;
+use crate::mousekeys::MouseAction;
+use crate::usb::rawhid::{RawReport, REPORT_SIZE};
+
+/// The version of VIA's protocol and of QMK's keycodes this speaks
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+
+// Commands of the VIA protocol, as in QMK's `via.h`. The response is
+// the request with the values filled in, or with the command replaced
+// by UNHANDLED.
+const GET_PROTOCOL_VERSION: u8 = 0x01;
+const GET_KEYBOARD_VALUE: u8 = 0x02;
+const SET_KEYBOARD_VALUE: u8 = 0x03;
+const DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+const DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+const EEPROM_RESET: u8 = 0x0A;
+const BOOTLOADER_JUMP: u8 = 0x0B;
+const DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+const DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+const DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+const DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+const DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+const DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+const UNHANDLED: u8 = 0xFF;
+
+// Values of GET_KEYBOARD_VALUE and SET_KEYBOARD_VALUE
+const UPTIME: u8 = 0x01;
+const LAYOUT_OPTIONS_VALUE: u8 = 0x02;
+const SWITCH_MATRIX_STATE: u8 = 0x03;
+const FIRMWARE_VERSION: u8 = 0x04;
+
+/// Most data bytes of the buffer commands, after the command, offset
+/// and size
+const BUFFER_CHUNK: usize = REPORT_SIZE - 4;
+
+// QMK keycodes, besides the basic ones that are HID usages
+const KC_NO: u16 = 0x0000;
+const KC_TRANSPARENT: u16 = 0x0001;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_TOGGLE_LAYER: u16 = 0x5260;
+const QK_MACRO: u16 = 0x7700;
+const QK_REBOOT: u16 = 0x7C01;
+/// The first of the keyboard specific keycodes, see `CUSTOM_KEYCODES`
+const QK_KB: u16 = 0x7E00;
+
+/// QMK keycodes of the `Action::Consumer` usages
+const CONSUMER_KEYCODES: [(u16, u16); 9] = [
+    (0x00A8, consumer::MUTE),
+    (0x00A9, consumer::VOLUME_UP),
+    (0x00AA, consumer::VOLUME_DOWN),
+    (0x00AB, consumer::NEXT_TRACK),
+    (0x00AC, consumer::PREVIOUS_TRACK),
+    (0x00AD, consumer::STOP),
+    (0x00AE, consumer::PLAY_PAUSE),
+    (0x00BD, consumer::BRIGHTNESS_UP),
+    (0x00BE, consumer::BRIGHTNESS_DOWN),
+];
+
+const SYSTEM_KEYCODES: [(u16, SystemControl); 3] = [
+    (0x00A5, SystemControl::PowerDown),
+    (0x00A6, SystemControl::Sleep),
+    (0x00A7, SystemControl::WakeUp),
+];
+
+const MOUSE_KEYCODES: [(u16, MouseAction); 11] = [
+    (0x00CD, MouseAction::Up),
+    (0x00CE, MouseAction::Down),
+    (0x00CF, MouseAction::Left),
+    (0x00D0, MouseAction::Right),
+    (0x00D1, MouseAction::Button1),
+    (0x00D2, MouseAction::Button2),
+    (0x00D3, MouseAction::Button3),
+    (0x00D4, MouseAction::Button4),
+    (0x00D5, MouseAction::Button5),
+    (0x00D9, MouseAction::WheelUp),
+    (0x00DA, MouseAction::WheelDown),
+];
+
+/// Actions without a QMK keycode, in the order of `customKeycodes` in
+/// `tools/via/anne-pro.json`, from `QK_KB` on
+const CUSTOM_KEYCODES: [Action; 36] = [
+    Action::UsbToggle,
+    Action::NkroToggle,
+    Action::MatrixTest,
+    Action::LayerOn(0),
+    Action::LayerOn(1),
+    Action::LayerOn(2),
+    Action::LayerOn(3),
+    Action::LayerOff(0),
+    Action::LayerOff(1),
+    Action::LayerOff(2),
+    Action::LayerOff(3),
+    Action::LedOn,
+    Action::LedOff,
+    Action::LedToggle,
+    Action::LedNextTheme,
+    Action::LedNextBrightness,
+    Action::LedNextAnimationSpeed,
+    Action::BtOn,
+    Action::BtOff,
+    Action::BtBroadcast,
+    Action::BtToggleLegacyMode,
+    Action::BtLegacyMode(true),
+    Action::BtLegacyMode(false),
+    Action::BtHostListQuery,
+    Action::BtSaveHost(1),
+    Action::BtSaveHost(2),
+    Action::BtSaveHost(3),
+    Action::BtSaveHost(4),
+    Action::BtConnectHost(1),
+    Action::BtConnectHost(2),
+    Action::BtConnectHost(3),
+    Action::BtConnectHost(4),
+    Action::BtDeleteHost(1),
+    Action::BtDeleteHost(2),
+    Action::BtDeleteHost(3),
+    Action::BtDeleteHost(4),
+];
+
+/// The QMK keycode of `action`. Actions that have none, like
+/// `Action::LedTheme` or uncommon consumer usages, show up as KC_NO.
+pub fn keycode(action: Action) -> u16 {
+    let custom = CUSTOM_KEYCODES.iter().position(|&custom| custom == action);
+    if let Some(index) = custom {
+        return QK_KB + index as u16;
+    }
+    match action {
+        Action::Nop => KC_NO,
+        Action::Transparent => KC_TRANSPARENT,
+        Action::Reset => QK_REBOOT,
+        Action::Macro(index) => QK_MACRO + u16::from(index),
+        Action::Key(code) => code as u16,
+        Action::Consumer(usage) => CONSUMER_KEYCODES
+            .iter()
+            .find(|(_, consumer)| *consumer == usage)
+            .map_or(KC_NO, |(keycode, _)| *keycode),
+        Action::System(control) => SYSTEM_KEYCODES
+            .iter()
+            .find(|(_, system)| *system == control)
+            .map_or(KC_NO, |(keycode, _)| *keycode),
+        Action::Mouse(mouse) => MOUSE_KEYCODES
+            .iter()
+            .find(|(_, action)| *action == mouse)
+            .map_or(KC_NO, |(keycode, _)| *keycode),
+        Action::LayerMomentary(layer) => QK_MOMENTARY | u16::from(layer),
+        Action::LayerToggle(layer) => QK_TOGGLE_LAYER | u16::from(layer),
+        _ => KC_NO,
+    }
+}
+
+/// The reverse of `keycode`, `None` for keycodes without an action
+pub fn action(keycode: u16) -> Option<Action> {
+    let action = match keycode {
+        KC_NO => Action::Nop,
+        KC_TRANSPARENT => Action::Transparent,
+        QK_REBOOT => Action::Reset,
+        0x0004..=0x00FF => {
+            if let Some(code) = KeyCode::from_u8(keycode as u8) {
+                Action::Key(code)
+            } else if let Some((_, usage)) = CONSUMER_KEYCODES.iter().find(|(k, _)| *k == keycode) {
+                Action::Consumer(*usage)
+            } else if let Some((_, control)) = SYSTEM_KEYCODES.iter().find(|(k, _)| *k == keycode) {
+                Action::System(*control)
+            } else {
+                let (_, mouse) = MOUSE_KEYCODES.iter().find(|(k, _)| *k == keycode)?;
+                Action::Mouse(*mouse)
+            }
+        }
+        _ => {
+            let (base, arg) = (keycode & 0xFFE0, keycode & 0x1F);
+            match base {
+                QK_MOMENTARY if usize::from(arg) < LAYERS.len() => {
+                    Action::LayerMomentary(arg as u8)
+                }
+                QK_TOGGLE_LAYER if usize::from(arg) < LAYERS.len() => {
+                    Action::LayerToggle(arg as u8)
+                }
+                _ if (QK_MACRO..QK_MACRO + u16::from(MACRO_COUNT)).contains(&keycode) => {
+                    Action::Macro((keycode - QK_MACRO) as u8)
+                }
+                _ => *CUSTOM_KEYCODES.get(keycode.checked_sub(QK_KB)? as usize)?,
+            }
+        }
+    };
+    Some(action)
+}
+
+/// Run the VIA command in `request` and return the response. Keymap,
+/// macro and layout option changes are saved in `eeprom`.
+pub fn execute(request: &RawReport, keyboard: &mut Keyboard, eeprom: &mut Eeprom) -> RawReport {
+    let mut response = *request;
+    let data = &mut response[1..];
+
+    match request[0] {
+        GET_PROTOCOL_VERSION => data[..2].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+        GET_KEYBOARD_VALUE => match data[0] {
+            UPTIME => data[1..5].copy_from_slice(&clock::now_ms().to_be_bytes()),
+            LAYOUT_OPTIONS_VALUE => {
+                let options = eeprom::read_word(LAYOUT_OPTIONS);
+                data[1..5].copy_from_slice(&options.to_be_bytes());
+            }
+            SWITCH_MATRIX_STATE => {
+                for row in 0..ROWS {
+                    let mut columns: u16 = 0;
+                    for column in 0..COLUMNS {
+                        if keyboard.is_pressed(row * COLUMNS + column) {
+                            columns |= 1 << column;
+                        }
+                    }
+                    data[1 + row * 2..3 + row * 2].copy_from_slice(&columns.to_be_bytes());
+                }
+            }
+            FIRMWARE_VERSION => data[1..5].copy_from_slice(&firmware_version().to_be_bytes()),
+            _ => response[0] = UNHANDLED,
+        },
+        SET_KEYBOARD_VALUE => match data[0] {
+            LAYOUT_OPTIONS_VALUE => {
+                let options = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+                eeprom.write_word(LAYOUT_OPTIONS, options).log_error();
+            }
+            _ => response[0] = UNHANDLED,
+        },
+        DYNAMIC_KEYMAP_GET_KEYCODE => {
+            let (layer, key) = (usize::from(data[0]), key_index(data[1], data[2]));
+            let action = key.and_then(|key| keyboard.keymap_action(layer, key));
+            let keycode = action.map_or(KC_NO, keycode);
+            data[3..5].copy_from_slice(&keycode.to_be_bytes());
+        }
+        DYNAMIC_KEYMAP_SET_KEYCODE => {
+            if let Some(key) = key_index(data[1], data[2]) {
+                let keycode = u16::from_be_bytes([data[3], data[4]]);
+                set_keycode(keyboard, eeprom, usize::from(data[0]), key, keycode);
+            }
+        }
+        DYNAMIC_KEYMAP_RESET => {
+            keyboard.reset_keymap();
+            eeprom.clear_keymap().log_error();
+        }
+        EEPROM_RESET => {
+            keyboard.reset_keymap();
+            eeprom.clear_keymap().log_error();
+            eeprom.clear_macros().log_error();
+            eeprom.write_word(LAYOUT_OPTIONS, 0).log_error();
+        }
+        BOOTLOADER_JUMP => bootloader::reboot_to_dfu(),
+        DYNAMIC_KEYMAP_MACRO_GET_COUNT => data[0] = MACRO_COUNT,
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+            data[..2].copy_from_slice(&(MACROS_SIZE as u16).to_be_bytes())
+        }
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+            let (offset, size) = buffer_range(data);
+            if offset + size <= MACROS_SIZE {
+                eeprom::read_macros(offset, &mut data[3..3 + size]);
+            }
+        }
+        DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+            let (offset, size) = buffer_range(data);
+            if offset + size <= MACROS_SIZE {
+                eeprom.write_macros(offset, &data[3..3 + size]).log_error();
+            }
+        }
+        DYNAMIC_KEYMAP_MACRO_RESET => eeprom.clear_macros().log_error(),
+        DYNAMIC_KEYMAP_GET_LAYER_COUNT => data[0] = LAYERS.len() as u8,
+        DYNAMIC_KEYMAP_GET_BUFFER => {
+            let (offset, size) = buffer_range(data);
+            for (i, byte) in data[3..3 + size].iter_mut().enumerate() {
+                let position = offset + i;
+                *byte = keymap_keycode(keyboard, position / 2).to_be_bytes()[position % 2];
+            }
+        }
+        DYNAMIC_KEYMAP_SET_BUFFER => {
+            let (offset, size) = buffer_range(data);
+            if size > 0 {
+                // Whole keycodes only, keeping the half of one that's
+                // not part of the range
+                for index in offset / 2..=(offset + size - 1) / 2 {
+                    let mut keycode = keymap_keycode(keyboard, index).to_be_bytes();
+                    for (i, byte) in keycode.iter_mut().enumerate() {
+                        let position = index * 2 + i;
+                        if position >= offset && position < offset + size {
+                            *byte = data[3 + position - offset];
+                        }
+                    }
+                    let (layer, key) = (index / (ROWS * COLUMNS), index % (ROWS * COLUMNS));
+                    set_keycode(keyboard, eeprom, layer, key, u16::from_be_bytes(keycode));
+                }
+            }
+        }
+        _ => response[0] = UNHANDLED,
+    }
+    response
+}
+
+/// `offset` and `size` of the buffer commands, limited to what fits in
+/// one report
+fn buffer_range(data: &[u8]) -> (usize, usize) {
+    let offset = usize::from(u16::from_be_bytes([data[0], data[1]]));
+    (offset, usize::from(data[2]).min(BUFFER_CHUNK))
+}
+
+fn key_index(row: u8, column: u8) -> Option<usize> {
+    let (row, column) = (usize::from(row), usize::from(column));
+    if row < ROWS && column < COLUMNS {
+        Some(row * COLUMNS + column)
+    } else {
+        None
+    }
+}
+
+/// Keycode number `index` of the keymap buffer, which has all layers
+/// one after the other
+fn keymap_keycode(keyboard: &Keyboard, index: usize) -> u16 {
+    let (layer, key) = (index / (ROWS * COLUMNS), index % (ROWS * COLUMNS));
+    keyboard.keymap_action(layer, key).map_or(KC_NO, keycode)
+}
+
+/// Change and save the keymap, ignoring keycodes without an action
+fn set_keycode(
+    keyboard: &mut Keyboard,
+    eeprom: &mut Eeprom,
+    layer: usize,
+    key: usize,
+    keycode: u16,
+) {
+    if let Some(action) = action(keycode) {
+        if keyboard.set_keymap_action(layer, key, action) {
+            eeprom.store_action(layer, key, action).log_error();
+        }
+    }
+}
+
+/// The crate version as 0x00MMmmpp
+fn firmware_version() -> u32 {
+    let part = |version: &str| version.parse::<u8>().map_or(0, u32::from);
+    part(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+        | part(env!("CARGO_PKG_VERSION_MINOR")) << 8
+        | part(env!("CARGO_PKG_VERSION_PATCH"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keycodes_round_trip() {
+        for keycode in 0..=0xFFFF {
+            if let Some(action) = action(keycode) {
+                assert_eq!(super::keycode(action), keycode);
+            }
+        }
+    }
+
+    #[test]
+    fn layout_actions_round_trip() {
+        for (layer, actions) in LAYERS.iter().enumerate() {
+            for (key, &action) in actions.iter().enumerate() {
+                let keycode = keycode(action);
+                if keycode != KC_NO {
+                    assert!(
+                        super::action(keycode) == Some(action),
+                        "layer {} key {}: {:#06x}",
+                        layer,
+                        key,
+                        keycode
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn qmk_keycodes() {
+        assert!(action(0x0004) == Some(Action::Key(KeyCode::A)));
+        assert!(action(0x00E1) == Some(Action::Key(KeyCode::LShift)));
+        assert!(action(0x00A9) == Some(Action::Consumer(consumer::VOLUME_UP)));
+        assert!(action(0x00A6) == Some(Action::System(SystemControl::Sleep)));
+        assert!(action(0x00D1) == Some(Action::Mouse(MouseAction::Button1)));
+        assert!(action(0x5221) == Some(Action::LayerMomentary(1)));
+        assert!(action(0x5262) == Some(Action::LayerToggle(2)));
+        assert!(action(0x7701) == Some(Action::Macro(1)));
+        assert!(action(QK_KB) == Some(Action::UsbToggle));
+    }
+
+    #[test]
+    fn unknown_keycodes() {
+        // Past the layers, macros and custom keycodes
+        assert!(action(QK_MOMENTARY + LAYERS.len() as u16).is_none());
+        assert!(action(QK_MACRO + u16::from(MACRO_COUNT)).is_none());
+        assert!(action(QK_KB + CUSTOM_KEYCODES.len() as u16).is_none());
+        // Mod-tap, which has no action
+        assert!(action(0x2004).is_none());
+        assert_eq!(keycode(Action::LedTheme(3)), KC_NO);
+    }
+}
@@ -0,0 +1,40 @@
+//! The actions that `set` and `trigger` take.
+//!
+//! The firmware's tests include this file to check the kinds against
+//! `Action::to_bytes`, so keep it free of anything but the table.
+
+/// Kinds of `Action::to_bytes` in the firmware, and whether they take
+/// an argument
+pub const ACTIONS: &[(&str, u8, bool)] = &[
+    ("nop", 0x00, false),
+    ("reset", 0x01, false),
+    ("transparent", 0x02, false),
+    ("usb-toggle", 0x03, false),
+    ("nkro-toggle", 0x04, false),
+    ("matrix-test", 0x05, false),
+    ("macro", 0x06, true),
+    ("key", 0x10, true),
+    ("consumer", 0x11, true),
+    ("system", 0x12, true),
+    ("mouse", 0x13, true),
+    ("layer-momentary", 0x20, true),
+    ("layer-toggle", 0x21, true),
+    ("layer-on", 0x22, true),
+    ("layer-off", 0x23, true),
+    ("led-on", 0x30, false),
+    ("led-off", 0x31, false),
+    ("led-toggle", 0x32, false),
+    ("led-next-theme", 0x33, false),
+    ("led-next-brightness", 0x34, false),
+    ("led-next-animation-speed", 0x35, false),
+    ("led-theme", 0x36, true),
+    ("bt-on", 0x40, false),
+    ("bt-off", 0x41, false),
+    ("bt-save-host", 0x42, true),
+    ("bt-connect-host", 0x43, true),
+    ("bt-delete-host", 0x44, true),
+    ("bt-broadcast", 0x45, false),
+    ("bt-legacy-mode", 0x46, true),
+    ("bt-toggle-legacy-mode", 0x47, false),
+    ("bt-host-list-query", 0x48, false),
+];
@@ -0,0 +1,193 @@
+//! Command line client for the raw HID interface of the anne-key
+//! firmware, see `src/command.rs` there for the protocol.
+//!
+//! Needs read and write access to the keyboard's `/dev/hidraw*` node.
+//! The firmware is built for another target, so build this one with
+//! e.g. `cargo run --target x86_64-unknown-linux-gnu` in `tools/anne-cli`.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+
+mod actions;
+use actions::ACTIONS;
+
+const REPORT_SIZE: usize = 32;
+/// The report descriptor of the raw HID interface starts with Usage
+/// Page (Vendor Defined 0xFF60)
+const USAGE_PAGE: [u8; 3] = [0x06, 0x60, 0xFF];
+
+const GET_VERSION: u8 = 0x80;
+const GET_LAYER_STATE: u8 = 0x81;
+const GET_KEYMAP_ENTRY: u8 = 0x82;
+const SET_KEYMAP_ENTRY: u8 = 0x83;
+const TRIGGER_ACTION: u8 = 0x84;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_COMMAND: u8 = 1;
+
+const USAGE: &str = "\
+usage: anne-cli <command>
+
+commands:
+    version                          firmware and protocol version
+    layers                           active layers
+    get <layer> <key>                action of a key
+    set <layer> <key> <action> [arg] change the action of a key
+    trigger <action> [arg]           run an LED or Bluetooth action
+    actions                          list action names
+
+<key> is the index in the key matrix, row * 14 + column. Changed keys
+are saved in the keyboard's EEPROM.";
+
+struct Keyboard {
+    file: File,
+}
+
+impl Keyboard {
+    /// The first hidraw device with the raw HID interface
+    fn open() -> io::Result<Keyboard> {
+        for entry in fs::read_dir("/sys/class/hidraw")? {
+            let entry = entry?;
+            let descriptor = match fs::read(entry.path().join("device/report_descriptor")) {
+                Ok(descriptor) => descriptor,
+                Err(_) => continue,
+            };
+            if descriptor.starts_with(&USAGE_PAGE) {
+                let path = Path::new("/dev").join(entry.file_name());
+                let file = OpenOptions::new().read(true).write(true).open(path)?;
+                return Ok(Keyboard { file });
+            }
+        }
+        Err(error("no keyboard with a raw HID interface found"))
+    }
+
+    /// Send `command` and return the data of its response
+    fn request(&mut self, command: u8, args: &[u8]) -> io::Result<[u8; REPORT_SIZE - 2]> {
+        // hidraw wants the report ID first, 0 as there are none
+        let mut report = [0; REPORT_SIZE + 1];
+        report[1] = command;
+        report[2..2 + args.len()].copy_from_slice(args);
+        self.file.write_all(&report)?;
+
+        let mut response = [0; REPORT_SIZE];
+        loop {
+            let size = self.file.read(&mut response)?;
+            if size == REPORT_SIZE && response[0] == command {
+                break;
+            }
+        }
+        match response[1] {
+            STATUS_OK => {
+                let mut data = [0; REPORT_SIZE - 2];
+                data.copy_from_slice(&response[2..]);
+                Ok(data)
+            }
+            STATUS_UNKNOWN_COMMAND => Err(error("the firmware doesn't know this command")),
+            _ => Err(error("invalid argument")),
+        }
+    }
+}
+
+fn error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_number(arg: &str) -> io::Result<u16> {
+    let parsed = match arg.get(..2) {
+        Some("0x") => u16::from_str_radix(&arg[2..], 16),
+        _ => arg.parse(),
+    };
+    parsed.map_err(|_| error(&format!("not a number: {}", arg)))
+}
+
+/// `name` and the optional argument in `Action::to_bytes` format
+fn parse_action(args: &[String]) -> io::Result<[u8; 3]> {
+    let name = args.first().ok_or_else(|| error("missing action"))?;
+    let (_, kind, has_arg) = ACTIONS
+        .iter()
+        .find(|(action, _, _)| action == name)
+        .ok_or_else(|| error(&format!("unknown action {}, see actions", name)))?;
+    let arg = match (has_arg, args.get(1)) {
+        (true, Some(arg)) => parse_number(arg)?,
+        (true, None) => return Err(error(&format!("{} needs an argument", name))),
+        (false, _) => 0,
+    };
+    let arg = arg.to_le_bytes();
+    Ok([*kind, arg[0], arg[1]])
+}
+
+fn format_action(bytes: &[u8]) -> String {
+    let arg = u16::from_le_bytes([bytes[1], bytes[2]]);
+    match ACTIONS.iter().find(|(_, kind, _)| *kind == bytes[0]) {
+        Some((name, _, true)) => format!("{} {:#04x}", name, arg),
+        Some((name, _, false)) => (*name).to_string(),
+        None => format!("unknown {:#04x} {:#06x}", bytes[0], arg),
+    }
+}
+
+fn parse_key(args: &[String]) -> io::Result<[u8; 2]> {
+    match args {
+        [layer, key, ..] => Ok([parse_number(layer)? as u8, parse_number(key)? as u8]),
+        _ => Err(error("missing layer or key")),
+    }
+}
+
+fn run(args: &[String]) -> io::Result<()> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return Err(error(USAGE)),
+    };
+    if command == "actions" {
+        for (name, _, has_arg) in ACTIONS {
+            println!("{}{}", name, if *has_arg { " <arg>" } else { "" });
+        }
+        return Ok(());
+    }
+
+    let mut keyboard = Keyboard::open()?;
+    match command {
+        "version" => {
+            let data = keyboard.request(GET_VERSION, &[])?;
+            let length = usize::from(data[1]).min(data.len() - 2);
+            let version = String::from_utf8_lossy(&data[2..2 + length]);
+            println!("firmware {}, protocol {}", version, data[0]);
+        }
+        "layers" => {
+            let data = keyboard.request(GET_LAYER_STATE, &[])?;
+            for layer in 0..data[1] {
+                let active = data[0] & (1 << layer) != 0;
+                println!("{} {}", layer, if active { "active" } else { "" });
+            }
+        }
+        "get" => {
+            let key = parse_key(&args[1..])?;
+            let data = keyboard.request(GET_KEYMAP_ENTRY, &key)?;
+            println!("{}", format_action(&data));
+        }
+        "set" => {
+            let key = parse_key(&args[1..])?;
+            let action = parse_action(args.get(3..).unwrap_or(&[]))?;
+            keyboard.request(
+                SET_KEYMAP_ENTRY,
+                &[key[0], key[1], action[0], action[1], action[2]],
+            )?;
+        }
+        "trigger" => {
+            let action = parse_action(&args[1..])?;
+            keyboard.request(TRIGGER_ACTION, &action)?;
+        }
+        _ => return Err(error(USAGE)),
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}